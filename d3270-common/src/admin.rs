@@ -0,0 +1,113 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! The protocol spoken on d3270d's admin socket.
+//!
+//! Each line sent to the socket is an [`AdminRequest`], and each is answered
+//! with a line holding an [`AdminResponse`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::b3270::indication::RosterEntry;
+
+// {"command":"kick","id":3}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum AdminRequest {
+    /// List every session
+    Sessions,
+    /// List the clients attached to a session, or to every session if none is given
+    Clients {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Disconnect a client. Client ids are unique across sessions, so the
+    /// session only narrows down where to look.
+    Kick {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Show a popup to the clients of a session, or of every session if none is given
+    Broadcast {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Disconnect b3270 from its host and connect it again. Goes to the
+    /// default session if none is given.
+    Reconnect {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Disconnect b3270 from its host. Goes to the default session if none is given.
+    Disconnect {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Start new recording files for a session, or for every session if none is given
+    Rotate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Read the configuration again, and apply what can be applied without a restart
+    Reload,
+}
+
+// "ok", {"error":"No such session cics"}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminResponse {
+    Ok,
+    Sessions(Vec<SessionInfo>),
+    Clients(Vec<ClientInfo>),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionInfo {
+    pub name: String,
+    /// The host b3270 was told to connect to
+    pub connect: String,
+    pub default: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientInfo {
+    pub session: String,
+    #[serde(flatten)]
+    pub client: RosterEntry,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_format() {
+        let request: AdminRequest = serde_json::from_str(r#"{"command":"kick","id":3}"#).unwrap();
+        assert_eq!(request, AdminRequest::Kick { id: 3, session: None });
+        assert_eq!(
+            serde_json::to_string(&AdminRequest::Reload).unwrap(),
+            r#"{"command":"reload"}"#
+        );
+        assert_eq!(serde_json::to_string(&AdminResponse::Ok).unwrap(), r#""ok""#);
+    }
+}
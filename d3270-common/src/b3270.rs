@@ -17,11 +17,11 @@
  *************************************************************************/
 
 use indication::{
-    CodePage, ConnectAttempt, Connection, Erase, FileTransfer, Hello, Model, Passthru, Popup,
-    Proxy, RunResult, Screen, ScreenMode, Scroll, Setting, Stats, TerminalName, Thumb, Tls,
-    TlsHello, TraceFile, UiError,
+    CodePage, ConnectAttempt, Connection, Erase, FileTransfer, Floor, Hello, Model, Passthru, Popup,
+    Proxy, Roster, RunResult, Screen, ScreenMode, Scroll, Setting, Stats, TerminalName, Thumb, Tls,
+    TlsHello, TraceFile, UiError, WaitResult,
 };
-use operation::{Fail, Register, Run, Succeed};
+use operation::{Attach, Fail, FloorControl, Register, Run, Succeed, WaitFor};
 use serde::{Deserialize, Serialize};
 use crate::b3270::indication::OiaField;
 
@@ -56,6 +56,10 @@ pub enum Indication {
     /// File transfer state change
     #[serde(rename = "ft")]
     FileTransfer(FileTransfer),
+    /// Keyboard ownership changed (d3270 extension)
+    Floor(Floor),
+    /// Clients attached to the session changed (d3270 extension)
+    Roster(Roster),
     /// An XTerm escape sequence requested a new icon name
     Icon {
         text: String,
@@ -89,12 +93,49 @@ pub enum Indication {
     Tls(Tls),
     /// Error in b3270's input
     UiError(UiError),
+    /// The answer to a wait-for operation (d3270 extension)
+    WaitResult(WaitResult),
     /// Xterm escape sequence requested a change to the window title
     WindowTitle {
         text: String,
     },
 }
 
+impl Indication {
+    /// The indication's name on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Indication::Bell { .. } => "bell",
+            Indication::Connection(_) => "connection",
+            Indication::ConnectAttempt(_) => "connect-attempt",
+            Indication::Erase(_) => "erase",
+            Indication::Flipped { .. } => "flipped",
+            Indication::Font { .. } => "font",
+            Indication::Formatted { .. } => "formatted",
+            Indication::FileTransfer(_) => "ft",
+            Indication::Floor(_) => "floor",
+            Indication::Icon { .. } => "icon",
+            Indication::Initialize(_) => "initialize",
+            Indication::Oia(_) => "oia",
+            Indication::Passthru(_) => "passthru",
+            Indication::Popup(_) => "popup",
+            Indication::Roster(_) => "roster",
+            Indication::RunResult(_) => "run-result",
+            Indication::Screen(_) => "screen",
+            Indication::ScreenMode(_) => "screen-mode",
+            Indication::Scroll(_) => "scroll",
+            Indication::Setting(_) => "setting",
+            Indication::Stats(_) => "stats",
+            Indication::Thumb(_) => "thumb",
+            Indication::TraceFile(_) => "trace-file",
+            Indication::Tls(_) => "tls",
+            Indication::UiError(_) => "ui-error",
+            Indication::WaitResult(_) => "wait-result",
+            Indication::WindowTitle { .. } => "window-title",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub enum InitializeIndication {
@@ -142,4 +183,25 @@ pub enum Operation {
     Fail(Fail),
     /// Tell b3270 that a passthru action succeeded
     Succeed(Succeed),
+    /// Identify this client to d3270d (d3270 extension)
+    Attach(Attach),
+    /// Request or release the keyboard (d3270 extension)
+    Floor(FloorControl),
+    /// Be told when the screen meets a condition (d3270 extension)
+    WaitFor(WaitFor),
+}
+
+impl Operation {
+    /// The operation's name on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Run(_) => "run",
+            Operation::Register(_) => "register",
+            Operation::Fail(_) => "fail",
+            Operation::Succeed(_) => "succeed",
+            Operation::Attach(_) => "attach",
+            Operation::Floor(_) => "floor",
+            Operation::WaitFor(_) => "wait-for",
+        }
+    }
 }
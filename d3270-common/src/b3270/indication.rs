@@ -353,6 +353,10 @@ impl CountOrText {
             CountOrText::Text(text) => text.chars().count(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -450,6 +454,76 @@ pub struct UiError {
     pub column: Option<usize>,
 }
 
+// d3270 extensions. These are generated by d3270d; b3270 never sends them.
+
+// {"floor":{"holder":"alice","holder-id":3,"mine":false}}
+/// Who holds the keyboard. While somebody does, only their actions are run.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Floor {
+    /// Name of the client that holds the keyboard, if anybody does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holder: Option<String>,
+    /// Connection number of the client that holds the keyboard
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holder_id: Option<u64>,
+    /// Whether the client receiving this indication holds the keyboard
+    #[serde(default)]
+    pub mine: bool,
+}
+
+// {"roster":{"clients":[{"id":3,"user":"alice","listener":"tcp","peer":"10.0.0.5:51234","connected":"2026-01-02T03:04:05.678Z","read-only":false}]}}
+/// Everybody attached to the session, in the order they attached
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Roster {
+    pub clients: Vec<RosterEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct RosterEntry {
+    /// Connection number, as in `holder-id` of a floor indication
+    pub id: u64,
+    /// The name the client authenticated as
+    pub user: String,
+    /// `tcp`, `unix`, or `ws`
+    pub listener: String,
+    /// The client's address, or for unix sockets, its pid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// When the client attached (RFC 3339)
+    pub connected: String,
+    #[serde(default)]
+    pub read_only: bool,
+    /// When the client last sent an operation (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_input: Option<String>,
+}
+
+// {"wait-result":{"w-tag":"1","success":true,"row":1,"column":2,"screen":["...",...],"cursor":{"enabled":true,"row":3,"column":10}}}
+/// The answer to a wait-for operation
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w_tag: Option<String>,
+    /// Whether the condition was met, rather than timing out
+    pub success: bool,
+    /// Why the wait failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Where the text or regular expression matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<u8>,
+    /// The screen when the wait ended, a string per row
+    #[serde(default)]
+    pub screen: Vec<String>,
+    pub cursor: Cursor,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -465,10 +539,48 @@ mod test {
         );
     }
 
+    #[test]
+    fn floor_round_trips() {
+        let floor = crate::b3270::Indication::Floor(Floor {
+            holder: Some("alice".to_owned()),
+            holder_id: Some(3),
+            mine: false,
+        });
+        let json = serde_json::to_string(&floor).unwrap();
+        assert_eq!(json, r#"{"floor":{"holder":"alice","holder-id":3,"mine":false}}"#);
+        assert_eq!(serde_json::from_str::<crate::b3270::Indication>(&json).unwrap(), floor);
+        assert_eq!(
+            serde_json::from_str::<crate::b3270::Indication>(r#"{"floor":{}}"#).unwrap(),
+            crate::b3270::Indication::Floor(Floor::default())
+        );
+    }
+
+    #[test]
+    fn indication_names_match_serialization() {
+        use crate::b3270::Indication;
+        for ind in [
+            Indication::Bell {},
+            Indication::Formatted { state: true },
+            Indication::Floor(Floor::default()),
+            Indication::Roster(Roster::default()),
+            Indication::Stats(Stats {
+                bytes_received: 0,
+                bytes_sent: 0,
+                records_received: 0,
+                records_sent: 0,
+            }),
+            Indication::WindowTitle { text: String::new() },
+        ] {
+            let json = serde_json::to_value(&ind).unwrap();
+            let (name, _) = json.as_object().unwrap().iter().next().unwrap();
+            assert_eq!(name, ind.name());
+        }
+    }
+
+    #[test]
     fn parse_row() {
         let instr = r#"[{"row":1,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","text":"z/OS V1R13 PUT Level 1401"},{"column":26,"fg":"red","gr":"highlight,selectable","count":26},{"column":52,"fg":"red","gr":"highlight,selectable","text":"IP Address = 10.24.74.32     "}]},{"row":2,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":51},{"column":52,"fg":"red","gr":"highlight,selectable","text":"VTAM Terminal = SC0TCP05     "}]},{"row":3,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":4,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":23},{"column":24,"fg":"red","gr":"highlight,selectable","text":"Application Developer System"},{"column":52,"fg":"red","gr":"highlight,selectable","count":29}]},{"row":5,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":6,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":32},{"column":33,"fg":"red","gr":"highlight,selectable","text":"//  OOOOOOO   SSSSS"},{"column":52,"fg":"red","gr":"highlight,selectable","count":29}]},{"row":7,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":31},{"column":32,"fg":"red","gr":"highlight,selectable","text":"//  OO    OO SS"},{"column":47,"fg":"red","gr":"highlight,selectable","count":34}]},{"row":8,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":23},{"column":24,"fg":"red","gr":"highlight,selectable","text":"zzzzzz //  OO    OO SS"},{"column":46,"fg":"red","gr":"highlight,selectable","count":35}]},{"row":9,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":25},{"column":26,"fg":"red","gr":"highlight,selectable","text":"zz  //  OO    OO SSSS"},{"column":47,"fg":"red","gr":"highlight,selectable","count":34}]},{"row":10,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":23},{"column":24,"fg":"red","gr":"highlight,selectable","text":"zz   //  OO    OO      SS"},{"column":49,"fg":"red","gr":"highlight,selectable","count":32}]},{"row":11,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":21},{"column":22,"fg":"red","gr":"highlight,selectable","text":"zz    //  OO    OO      SS"},{"column":48,"fg":"red","gr":"highlight,selectable","count":33}]},{"row":12,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":19},{"column":20,"fg":"red","gr":"highlight,selectable","text":"zzzzzz //   OOOOOOO  SSSS"},{"column":45,"fg":"red","gr":"highlight,selectable","count":36}]},{"row":13,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":14,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":15,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":19},{"column":20,"fg":"red","gr":"highlight,selectable","text":"System Customization - ADCD.Z113H.*"},{"column":55,"fg":"red","gr":"highlight,selectable","count":26}]},{"row":16,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":17,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":18,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":19,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":80}]},{"row":20,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","text":" ===> Enter \"LOGON\" followed by the TSO userid. Example \"LOGON IBMUSER\" or      "}]},{"row":21,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","text":" ===> Enter L followed by the APPLID"},{"column":37,"fg":"red","gr":"highlight,selectable","count":44}]},{"row":22,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","text":" ===> Examples: \"L TSO\", \"L CICSTS41\", \"L CICSTS42\", \"L IMS11\", \"L IMS12\"       "}]},{"row":23,"changes":[{"column":1,"fg":"red","gr":"highlight,selectable","count":79},{"column":80,"fg":"green","count":1}]},{"row":24,"changes":[{"column":1,"fg":"green","count":79},{"column":80,"fg":"red","gr":"highlight,selectable","count":1}]}]"#;
         if let Err(err) = serde_json::from_slice::<Vec<Row>>(instr.as_bytes()) {
-            let pos = err.column();
             println!("Parse error: {err}");
             let (pre, post) = instr.split_at(err.column());
             println!("Context: {pre}\x1b[1;31m{post}\x1b[0m");
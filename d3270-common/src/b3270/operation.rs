@@ -18,6 +18,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::b3270::indication::ConnectionState;
+
 // {"run":{"actions":[{"action":"Connect","args":["10.24.74.37:3270"]}]}}
 // {"run":{"actions":[{"action":"Key","args":["a"]}]}}
 // Operations
@@ -64,3 +66,73 @@ pub struct Succeed {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub text: Vec<String>,
 }
+
+// d3270 extensions. These are understood by d3270d and are never sent to b3270.
+
+// {"attach":{"token":"hunter2","session":"cics","read-only":true}}
+/// Handshake sent as the first line of a connection to d3270d
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Attach {
+    /// Authentication token, if the server requires one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Name of the session to attach to. If not given, the server's default session is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Only watch the session. The server refuses any operation that would affect it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+}
+
+// {"floor":{"action":"request"}}
+/// Ask d3270d for (or give up) the keyboard
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct FloorControl {
+    pub action: FloorAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum FloorAction {
+    /// Take the keyboard if nobody else holds it
+    Request,
+    /// Give up the keyboard
+    Release,
+    /// Take the keyboard even if somebody else holds it
+    Take,
+}
+
+// {"wait-for":{"w-tag":"1","condition":{"text":{"row":1,"column":2,"text":"READY"}},"timeout":30}}
+// {"wait-for":{"condition":"unlocked"}}
+/// Ask d3270d to say when the screen meets a condition. It answers with a
+/// wait-result carrying the same `w-tag`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct WaitFor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w_tag: Option<String>,
+    pub condition: WaitCondition,
+    /// Seconds to wait before giving up. Without one, the wait lasts as long
+    /// as the connection does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum WaitCondition {
+    /// `text` is on the screen, starting at `row` and `column` (from 1)
+    Text { row: u8, column: u8, text: String },
+    /// A regular expression matches somewhere on the screen. Rows are
+    /// separated by newlines.
+    Regex(String),
+    /// The keyboard is unlocked
+    Unlocked,
+    /// The keyboard is unlocked and the cursor is in a field of a formatted
+    /// screen, rather than on a field attribute
+    InputField,
+    /// The host connection is in this state
+    Connection(ConnectionState),
+}
@@ -39,7 +39,7 @@ bitflags! {
     }
 }
 
-static FLAG_NAMES: &'static [(GraphicRendition, &'static str)] = &[
+static FLAG_NAMES: &[(GraphicRendition, &str)] = &[
     (GraphicRendition::UNDERLINE, "underline"),
     (GraphicRendition::BLINK, "blink"),
     (GraphicRendition::HIGHLIGHT, "highlight"),
@@ -0,0 +1,176 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! The protocol spoken on d3270d's HLLAPI socket.
+//!
+//! It follows EHLLAPI's functions and return codes, but as lines of JSON:
+//! each line sent is an [`HllapiRequest`], and is answered with a line
+//! holding an [`HllapiResponse`]. Presentation space positions are counted
+//! from 1 at the top left, row by row, as in EHLLAPI.
+
+use serde::{Deserialize, Serialize};
+
+// {"function":"send-key","keys":"logon@E"}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "function", rename_all = "kebab-case")]
+pub enum HllapiRequest {
+    /// Connect to a session's presentation space (1), the default session's
+    /// if none is given. Everything but `query-sessions` needs this first.
+    ConnectPs {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Let go of the presentation space (2)
+    DisconnectPs,
+    /// Type keys, with `@` mnemonics for the special ones, e.g. `@E` for
+    /// Enter (3)
+    SendKey { keys: String },
+    /// Wait until the keyboard is unlocked, for up to `timeout` seconds (4)
+    Wait {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<f64>,
+    },
+    /// The whole presentation space, as one string (5)
+    CopyPs,
+    /// Find text in the presentation space (6). Searches from `position`, or
+    /// from the start (end, if `backward`) if none is given.
+    SearchPs {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position: Option<usize>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        backward: bool,
+    },
+    /// Where the cursor is (7)
+    QueryCursor,
+    /// Part of the presentation space (8)
+    CopyPsToString { position: usize, length: usize },
+    /// The attribute of the field that holds a position (14)
+    QueryFieldAttribute { position: usize },
+    /// The sessions that may be connected to (10)
+    QuerySessions,
+    /// The connected presentation space's size and state (22)
+    QuerySessionStatus,
+    /// Move the cursor (40)
+    SetCursor { position: usize },
+}
+
+/// EHLLAPI's return codes
+pub mod rc {
+    pub const OK: u8 = 0;
+    pub const NOT_CONNECTED: u8 = 1;
+    pub const PARAMETER_ERROR: u8 = 2;
+    pub const BUSY: u8 = 4;
+    pub const INHIBITED: u8 = 5;
+    pub const INVALID_POSITION: u8 = 7;
+    pub const SYSTEM_ERROR: u8 = 9;
+    /// Also what `query-field-attribute` says of an unformatted screen
+    pub const NOT_FOUND: u8 = 24;
+}
+
+// {"rc":0,"position":81}, {"rc":24,"error":"Not found"}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct HllapiResponse {
+    /// One of [`rc`]
+    pub rc: u8,
+    /// What went wrong, when `rc` isn't 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Of the cursor, the text that was found, or a field's attribute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribute: Option<FieldAttribute>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<SessionStatus>,
+}
+
+impl HllapiResponse {
+    pub fn ok() -> Self {
+        Self::default()
+    }
+
+    pub fn error(rc: u8, error: impl Into<String>) -> Self {
+        Self {
+            rc,
+            error: Some(error.into()),
+            ..Self::default()
+        }
+    }
+}
+
+/// A 3270 field attribute byte, and what it means
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct FieldAttribute {
+    pub value: u8,
+    pub protected: bool,
+    pub numeric: bool,
+    pub intensified: bool,
+    /// Non-display, such as a password field
+    pub hidden: bool,
+    pub modified: bool,
+}
+
+impl From<u8> for FieldAttribute {
+    fn from(value: u8) -> Self {
+        FieldAttribute {
+            value,
+            protected: value & 0x20 != 0,
+            numeric: value & 0x10 != 0,
+            intensified: value & 0x0c == 0x08,
+            hidden: value & 0x0c == 0x0c,
+            modified: value & 0x01 != 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionStatus {
+    pub session: String,
+    /// The host, if connected to one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub model: u8,
+    pub rows: u8,
+    pub columns: u8,
+    pub keyboard_locked: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_format() {
+        let request: HllapiRequest = serde_json::from_str(r#"{"function":"send-key","keys":"@E"}"#).unwrap();
+        assert_eq!(request, HllapiRequest::SendKey { keys: "@E".to_owned() });
+        assert_eq!(
+            serde_json::to_string(&HllapiResponse::error(rc::NOT_FOUND, "Not found")).unwrap(),
+            r#"{"rc":24,"error":"Not found"}"#
+        );
+        assert!(FieldAttribute::from(0xe8).protected);
+        assert!(FieldAttribute::from(0x4c).hidden);
+    }
+}
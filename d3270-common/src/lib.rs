@@ -16,5 +16,7 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
  *************************************************************************/
 
+pub mod admin;
 pub mod b3270;
+pub mod hllapi;
 pub mod tracker;
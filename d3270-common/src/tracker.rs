@@ -19,7 +19,7 @@
 use std::collections::HashMap;
 use tracing::warn;
 
-use crate::b3270::indication::{Change, ComposeType, Connection, ConnectionState, CountOrText, Cursor, Erase, OiaField, OiaFieldName, Row, RunResult, Screen, ScreenMode, Scroll, Setting, Thumb, Tls, TraceFile};
+use crate::b3270::indication::{Change, ComposeType, Connection, ConnectionState, CountOrText, Cursor, Erase, Floor, OiaField, OiaFieldName, Roster, Row, RunResult, Screen, ScreenMode, Scroll, Setting, Thumb, Tls, TraceFile};
 use crate::b3270::types::{Color, GraphicRendition, PackedAttr};
 use crate::b3270::{Indication, InitializeIndication};
 use crate::b3270::types::Color::{NeutralBlack, NeutralWhite};
@@ -43,6 +43,8 @@ pub struct Tracker {
     formatted: bool,
     trace_file: Option<String>,
     tls: Option<Tls>,
+    floor: Option<Floor>,
+    roster: Option<Roster>,
 
     oia_tracker: OiaTracker,
     // These never change, but need to be represented in an initialize message
@@ -57,6 +59,8 @@ pub enum Disposition {
     Drop,
     // Send this message to one particular client
     Direct(String),
+    // Send this message to the client whose operation caused it
+    Originator,
 }
 
 impl Tracker {
@@ -106,6 +110,9 @@ impl Tracker {
             }
 
             Indication::Initialize(init) => {
+                // An initialize carries the whole state, so nothing from
+                // before it (another session's floor, say) may survive
+                *self = Tracker::default();
                 let mut static_init = Vec::with_capacity(init.len());
                 for indicator in init.clone() {
                     match indicator {
@@ -214,7 +221,7 @@ impl Tracker {
                 self.settings.insert(setting.name.clone(), setting.clone());
             }
             Indication::Thumb(thumb) => {
-                self.thumb = thumb.clone();
+                self.thumb = *thumb;
             }
             Indication::TraceFile(TraceFile { name }) => {
                 self.trace_file = name.clone();
@@ -222,11 +229,19 @@ impl Tracker {
             Indication::Tls(tls) => {
                 self.tls = Some(tls.clone());
             }
+            Indication::Floor(floor) => {
+                self.floor = Some(floor.clone());
+            }
+            Indication::Roster(roster) => {
+                self.roster = Some(roster.clone());
+            }
 
             // These need direction
-            Indication::UiError(_) => {} // we can assume that this came from the last sent command
-            Indication::Passthru(_) => {} // dunno how to handle this one
-            Indication::FileTransfer(_) => {}
+            Indication::UiError(_) | Indication::FileTransfer(_) => return Disposition::Originator,
+            // d3270d sends these straight to the client that asked
+            Indication::WaitResult(_) => return Disposition::Drop,
+            // Goes to whoever registered the action
+            Indication::Passthru(passthru) => return Disposition::Direct(passthru.p_tag.clone()),
             Indication::RunResult(RunResult { r_tag, .. }) => {
                 if let Some(dest) = r_tag {
                     return Disposition::Direct(dest.clone());
@@ -235,7 +250,7 @@ impl Tracker {
                 }
             }
         }
-        return Disposition::Broadcast;
+        Disposition::Broadcast
     }
 
     pub fn get_init_indication(&self) -> Vec<Indication> {
@@ -267,6 +282,12 @@ impl Tracker {
                 name: Some(trace_file),
             }))
         }
+        if let Some(floor) = self.floor.clone() {
+            result.push(Indication::Floor(floor));
+        }
+        if let Some(roster) = self.roster.clone() {
+            result.push(Indication::Roster(roster));
+        }
         result
     }
 
@@ -322,11 +343,29 @@ impl Tracker {
         &self.cursor
     }
 
+    /// The cell under the cursor, if the cursor is on the screen
+    pub fn get_cursor_cell(&self) -> Option<&CharCell> {
+        let Cursor { enabled: true, row: Some(row), column: Some(column) } = self.cursor else {
+            return None;
+        };
+        self.screen
+            .get((row as usize).checked_sub(1)?)?
+            .get((column as usize).checked_sub(1)?)
+    }
+
     pub fn get_oia_state(&self) -> &OiaTracker {
         &self.oia_tracker
     }
 
     pub fn get_connection(&self) -> &Connection { &self.connection }
+
+    pub fn get_screen_mode(&self) -> &ScreenMode { &self.screen_mode }
+
+    pub fn get_formatted(&self) -> bool { self.formatted }
+
+    pub fn get_floor(&self) -> Option<&Floor> { self.floor.as_ref() }
+
+    pub fn get_roster(&self) -> Option<&Roster> { self.roster.as_ref() }
 }
 
 #[derive(Default)]
@@ -372,7 +411,7 @@ impl OiaTracker {
 
 impl Default for Tracker {
     fn default() -> Self {
-        let ret = Self {
+        Self {
             screen: vec![vec![CharCell{
                 attr: u32::c_pack(Color::NeutralWhite, Color::NeutralBlack, GraphicRendition::empty()),
                 ch: ' ',
@@ -413,9 +452,10 @@ impl Default for Tracker {
             formatted: false,
             trace_file: None,
             tls: None,
+            floor: None,
+            roster: None,
             static_init: vec![],
             oia_tracker: OiaTracker::default(),
-        };
-        ret
+        }
     }
 }
@@ -16,27 +16,32 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
  *************************************************************************/
 
+use std::convert::Infallible;
 use std::fmt::Debug;
 use std::io;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::ops::Range;
-use std::os::linux::raw::stat;
-use crossterm::{Command, cursor, queue, style, terminal};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use crossterm::{cursor, queue, style, terminal};
 use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::style::{Attribute};
 use crossterm::terminal::ClearType;
+use anyhow::{bail, Context};
 use futures::StreamExt;
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 use structopt::StructOpt;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::select;
+use tokio_rustls::TlsConnector;
+use rustls::ServerName;
 use d3270_common::b3270;
 use d3270_common::b3270::{Indication, Operation};
-use d3270_common::b3270::indication::{Connection, ConnectionState, Cursor, Erase, Screen};
-use d3270_common::b3270::operation::{Action, Run};
+use d3270_common::b3270::indication::{Connection, ConnectionState, Cursor, Screen};
+use d3270_common::b3270::operation::{Action, Attach, FloorAction, FloorControl, Run};
 use d3270_common::b3270::types::{Color, GraphicRendition, PackedAttr};
-use d3270_common::b3270::types::Color::{NeutralBlack, NeutralWhite};
 use d3270_common::tracker::Tracker;
 
 macro_rules! actions {
@@ -55,7 +60,83 @@ macro_rules! actions {
 
 #[derive(StructOpt)]
 struct Opts {
-    host: SocketAddr,
+    /// Authentication token for d3270d
+    #[structopt(long, env = "D3270_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Name of the session to attach to (defaults to the server's default session)
+    #[structopt(long)]
+    session: Option<String>,
+    /// Watch the session without being able to type into it
+    #[structopt(long)]
+    read_only: bool,
+    /// Connect using TLS, verifying the server against the system trust store
+    #[structopt(long)]
+    tls: bool,
+    /// Name to verify the server certificate against (defaults to the address)
+    #[structopt(long)]
+    tls_name: Option<String>,
+    /// Accept only the certificate in this PEM file (e.g., a self-signed cert). Implies --tls
+    #[structopt(long, parse(from_os_str))]
+    tls_pin: Option<PathBuf>,
+    /// ip:port of d3270d's TCP listener, or the path of its unix socket
+    host: Remote,
+}
+
+enum Remote {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Remote {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(addr) => Remote::Tcp(addr),
+            Err(_) => Remote::Unix(PathBuf::from(s)),
+        })
+    }
+}
+
+mod tls;
+
+trait RemoteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RemoteStream for T {}
+
+async fn send_operation<W: AsyncWrite + Unpin>(wr: &mut W, op: &Operation) -> anyhow::Result<()> {
+    let mut enc = serde_json::to_string(op)?;
+    enc.push('\n');
+    wr.write_all(enc.as_bytes()).await?;
+    Ok(())
+}
+
+async fn connect(opts: &Opts) -> anyhow::Result<Box<dyn RemoteStream>> {
+    let use_tls = opts.tls || opts.tls_pin.is_some();
+    let addr = match opts.host {
+        Remote::Unix(ref path) if use_tls => {
+            bail!("TLS can't be used over a unix socket ({})", path.display())
+        }
+        Remote::Unix(ref path) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Failed to connect to {}", path.display()))?;
+            return Ok(Box::new(stream));
+        }
+        Remote::Tcp(addr) => addr,
+    };
+    let stream = TcpStream::connect(addr).await?;
+    if !use_tls {
+        return Ok(Box::new(stream));
+    }
+    let config = tls::client_config(opts.tls_pin.as_deref())?;
+    let server_name = match opts.tls_name {
+        Some(ref name) => ServerName::try_from(name.as_str())?,
+        None => ServerName::IpAddress(addr.ip()),
+    };
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(stream))
 }
 
 #[derive(Default)]
@@ -63,6 +144,8 @@ pub struct State {
     tracker: Tracker,
     // cols, rows
     screen_size: (u16, u16),
+    // Whether the list of attached clients is shown over the screen
+    show_roster: bool,
 }
 
 trait IfElse {
@@ -108,6 +191,7 @@ impl State {
             Indication::Erase(_) | Indication::ScreenMode(_) => {
                 self.redraw_all()?; // this does its own writing
             }
+            Indication::Roster(_) | Indication::Floor(_) if self.show_roster => self.redraw_all()?,
             _ => {},
         }
 
@@ -132,6 +216,9 @@ impl State {
             self.redraw_region(&mut buf, i, 0..screen_width)?;
         }
         self.redraw_oia(&mut buf)?;
+        if self.show_roster {
+            self.redraw_roster(&mut buf)?;
+        }
 
         queue!(buf, crossterm::terminal::EndSynchronizedUpdate)?;
         io::stdout().write_all(buf.as_slice())?;
@@ -198,8 +285,8 @@ impl State {
             // security?
             st = oia.screen_trace.is_some().if_else('t', ' '),
             sc = oia.script.if_else('s', ' '),
-            lu = oia.lu.as_ref().map(String::as_str).unwrap_or(""),
-            timing = oia.timing.as_ref().map(String::as_str).unwrap_or(""),
+            lu = oia.lu.as_deref().unwrap_or(""),
+            timing = oia.timing.as_deref().unwrap_or(""),
         )?;
         queue!(buf,
             crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
@@ -208,6 +295,37 @@ impl State {
         self.restore_cursor(buf)
     }
 
+    /// Draw the attached clients in the top right corner. The keyboard holder
+    /// is marked with `*` and read-only clients with `r`.
+    fn redraw_roster(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        let Some(roster) = self.tracker.get_roster() else { return Ok(()) };
+        let holder = self.tracker.get_floor().and_then(|floor| floor.holder_id);
+        let mut lines = vec![format!(" {} attached", roster.clients.len())];
+        lines.extend(roster.clients.iter().map(|client| {
+            let flag = if Some(client.id) == holder { '*' } else { client.read_only.if_else('r', ' ') };
+            format!(
+                " {flag} {:12} {:4} {:21} {} ",
+                client.user,
+                client.listener,
+                client.peer.as_deref().unwrap_or(""),
+                client.last_input.as_deref().map_or("--:--:--", time_of_day),
+            )
+        }));
+        let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        let left = self.screen_size.0.saturating_sub(width as u16 + 1);
+        queue!(buf,
+            style::SetAttribute(Attribute::Reset),
+            style::SetForegroundColor(style::Color::Black),
+            style::SetBackgroundColor(style::Color::Grey),
+        )?;
+        for (i, line) in lines.iter().enumerate() {
+            queue!(buf, cursor::MoveTo(left, i as u16 + 1))?;
+            write!(buf, "{line:width$}")?;
+        }
+        queue!(buf, style::SetAttribute(Attribute::Reset), style::ResetColor)?;
+        self.restore_cursor(buf)
+    }
+
     fn restore_cursor(&self, buf: &mut Vec<u8>) -> io::Result<()> {
         match self.tracker.get_cursor() {
             Cursor { enabled: true, row: Some(row), column: Some(col)} => {
@@ -246,6 +364,11 @@ impl State {
     }
 }
 
+/// The hh:mm:ss part of an RFC 3339 time
+fn time_of_day(time: &str) -> &str {
+    time.get(11..19).unwrap_or(time)
+}
+
 static GR_TO_ATTR: &[(GraphicRendition, Attribute)] = &[
     (GraphicRendition::BLINK, Attribute::SlowBlink),
     (GraphicRendition::HIGHLIGHT, Attribute::Italic),
@@ -324,14 +447,22 @@ async fn main() -> anyhow::Result<()> {
     let mut log_f = std::fs::File::create("b3270.trace.jsonl")?;
     let opts = Opts::from_args();
     let size = crossterm::terminal::size()?;
+    // Connect before touching the terminal so that errors are readable
+    let remote = connect(&opts).await?;
     let mut term_setup = TermSetup::setup()?;
 
-    let mut remote = TcpStream::connect(opts.host).await?;
-    let (rem_rd, mut rem_wr) = remote.split();
+    let (rem_rd, mut rem_wr) = tokio::io::split(remote);
     let mut rem_rd = BufReader::new(rem_rd).lines();
+    let attach = Operation::Attach(Attach {
+        token: opts.token.clone(),
+        session: opts.session.clone(),
+        read_only: opts.read_only,
+    });
+    send_operation(&mut rem_wr, &attach).await?;
     let mut state = State{
         tracker: Default::default(),
         screen_size: size,
+        show_roster: false,
     };
 
     state.apply_indicator(Indication::Connection(Connection{state: ConnectionState::NotConnected, host: None, cause: None}))?;
@@ -381,6 +512,20 @@ async fn main() -> anyhow::Result<()> {
                             (KeyCode::Char('c'), KeyModifiers::ALT) => actions!(Reconnect()),
                             (KeyCode::Char('r'), KeyModifiers::ALT) => actions!(Reset()),
 
+                            (KeyCode::Char('k'), KeyModifiers::ALT) => {
+                                let action = if state.tracker.get_floor().is_some_and(|floor| floor.mine) {
+                                    FloorAction::Release
+                                } else {
+                                    FloorAction::Request
+                                };
+                                send_operation(&mut rem_wr, &Operation::Floor(FloorControl { action })).await?;
+                                continue 'main;
+                            }
+                            (KeyCode::Char('w'), KeyModifiers::ALT) => {
+                                state.show_roster = !state.show_roster;
+                                state.redraw_all()?;
+                                continue 'main;
+                            }
                             (KeyCode::Char('l'), KeyModifiers::CONTROL) => {
                                 state.redraw_all()?;
                                 continue 'main;
@@ -391,9 +536,7 @@ async fn main() -> anyhow::Result<()> {
                             },
                         };
                         let op = Operation::Run(Run{actions, type_: None, r_tag: None});
-                        let mut enc = serde_json::to_string(&op)?;
-                        enc.push('\n');
-                        rem_wr.write_all(enc.as_bytes()).await?;
+                        send_operation(&mut rem_wr, &op).await?;
                     }
 
                     Event::Mouse(_) => {}
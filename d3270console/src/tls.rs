@@ -0,0 +1,106 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+
+/// Accepts exactly one certificate, regardless of who signed it or what name it's for.
+/// This is how you talk to a d3270d with a self-signed certificate.
+struct PinnedCert(Certificate);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if *end_entity == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Server certificate doesn't match the pinned certificate".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Build a client config that verifies the server against the system trust store,
+/// or, if `pin` is given, accepts only the certificate in that PEM file.
+pub fn client_config(pin: Option<&Path>) -> anyhow::Result<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match pin {
+        Some(path) => {
+            let file =
+                File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+            let cert = rustls_pemfile::certs(&mut BufReader::new(file))?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No certificate found in {}", path.display()))?;
+            builder
+                .with_custom_certificate_verifier(Arc::new(PinnedCert(Certificate(cert))))
+                .with_no_client_auth()
+        }
+        None => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_native_certs::load_native_certs()? {
+                // Some system stores contain certs that rustls can't parse; skip those.
+                roots.add(&Certificate(cert.0)).ok();
+            }
+            if roots.is_empty() {
+                bail!("No usable CA certificates found in the system trust store");
+            }
+            builder
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        }
+    };
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pinned_cert() {
+        let verifier = PinnedCert(Certificate(b"pinned".to_vec()));
+        let verify = |cert: &[u8]| {
+            verifier.verify_server_cert(
+                &Certificate(cert.to_vec()),
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify(b"pinned").is_ok());
+        assert!(verify(b"other").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use structopt::StructOpt;
+
+use d3270_common::admin::{AdminRequest, AdminResponse, ClientInfo, SessionInfo};
+
+/// Manage a running d3270d through its admin socket
+#[derive(StructOpt)]
+struct Opts {
+    /// Path of d3270d's admin socket (-admin-socket)
+    #[structopt(long, env = "D3270_ADMIN_SOCKET", parse(from_os_str))]
+    socket: PathBuf,
+    /// Print the server's response as JSON
+    #[structopt(long)]
+    json: bool,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// List sessions
+    Sessions,
+    /// List attached clients
+    Clients {
+        /// Only list the clients of this session
+        #[structopt(long)]
+        session: Option<String>,
+    },
+    /// Disconnect a client, by the id that `clients` shows
+    Kick {
+        id: u64,
+        #[structopt(long)]
+        session: Option<String>,
+    },
+    /// Show a message to every client
+    Broadcast {
+        /// Only show it to the clients of this session
+        #[structopt(long)]
+        session: Option<String>,
+        #[structopt(required = true)]
+        text: Vec<String>,
+    },
+    /// Disconnect b3270 from its host and connect it again
+    Reconnect {
+        /// The session to reconnect, rather than the default one
+        #[structopt(long)]
+        session: Option<String>,
+    },
+    /// Disconnect b3270 from its host
+    Disconnect {
+        /// The session to disconnect, rather than the default one
+        #[structopt(long)]
+        session: Option<String>,
+    },
+    /// Start new recording files
+    Rotate {
+        /// Only rotate this session's recording
+        #[structopt(long)]
+        session: Option<String>,
+    },
+    /// Reload the token file and action policy from the configuration
+    Reload,
+}
+
+impl From<Command> for AdminRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Sessions => AdminRequest::Sessions,
+            Command::Clients { session } => AdminRequest::Clients { session },
+            Command::Kick { id, session } => AdminRequest::Kick { id, session },
+            Command::Broadcast { session, text } => AdminRequest::Broadcast {
+                text: text.join(" "),
+                session,
+            },
+            Command::Reconnect { session } => AdminRequest::Reconnect { session },
+            Command::Disconnect { session } => AdminRequest::Disconnect { session },
+            Command::Rotate { session } => AdminRequest::Rotate { session },
+            Command::Reload => AdminRequest::Reload,
+        }
+    }
+}
+
+fn request(socket: &Path, request: &AdminRequest) -> anyhow::Result<String> {
+    let mut conn = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    conn.write_all(&line)?;
+    let mut response = String::new();
+    BufReader::new(conn).read_line(&mut response)?;
+    if response.is_empty() {
+        bail!("d3270d closed the connection without answering");
+    }
+    Ok(response)
+}
+
+fn print_sessions(sessions: &[SessionInfo]) {
+    for session in sessions {
+        let default = if session.default { " (default)" } else { "" };
+        println!("{}\t{}{default}", session.name, session.connect);
+    }
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    println!(
+        "{:>4} {:12} {:12} {:4} {:21} {:2} {:24} LAST INPUT",
+        "ID", "SESSION", "USER", "VIA", "PEER", "RO", "CONNECTED"
+    );
+    for ClientInfo { session, client } in clients {
+        println!(
+            "{:>4} {:12} {:12} {:4} {:21} {:2} {:24} {}",
+            client.id,
+            session,
+            client.user,
+            client.listener,
+            client.peer.as_deref().unwrap_or("-"),
+            if client.read_only { "y" } else { "n" },
+            client.connected,
+            client.last_input.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let response = request(&opts.socket, &opts.command.into())?;
+    if opts.json {
+        print!("{response}");
+        return Ok(());
+    }
+    match serde_json::from_str(&response).context("Invalid response from d3270d")? {
+        AdminResponse::Ok => {}
+        AdminResponse::Sessions(sessions) => print_sessions(&sessions),
+        AdminResponse::Clients(clients) => print_clients(&clients),
+        AdminResponse::Error(error) => return Err(anyhow!(error)),
+    }
+    Ok(())
+}
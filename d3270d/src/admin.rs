@@ -0,0 +1,312 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! The admin socket, which `d3270ctl` talks to.
+//!
+//! It is a unix socket, so that who may use it is up to the file system
+//! (and to the same peer checks as the client socket). Each line is an
+//! [`AdminRequest`], answered by a line with an [`AdminResponse`].
+
+use std::ffi::OsString;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use futures::never::Never;
+use nix::unistd::{Uid, User};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+use d3270_common::admin::{AdminRequest, AdminResponse, ClientInfo};
+use d3270_common::b3270::indication::RunResult;
+use d3270_common::b3270::operation::Action;
+
+use crate::arbiter::{failed_run_result, ArbiterHandleRequester};
+use crate::audit::{AuditLog, AuditTrail, Origin, PendingAudit};
+use crate::auth::{Authenticator, Identity};
+use crate::config;
+use crate::policy::ActionPolicy;
+use crate::session::SessionRegistry;
+use crate::unix_server::{self, PeerPolicy, UnixListenOptions};
+
+/// Mode of the admin socket unless another is configured
+pub const DEFAULT_MODE: u32 = 0o600;
+
+/// Everything the admin socket can change
+#[derive(Clone)]
+pub struct AdminState {
+    pub sessions: SessionRegistry,
+    pub auth: Arc<Authenticator>,
+    pub policy: Arc<ActionPolicy>,
+    /// Where kicks, reconnects, and disconnects are recorded
+    pub audit: Option<Arc<AuditLog>>,
+    /// The command line d3270d was started with, to read the configuration again
+    pub args: Vec<OsString>,
+}
+
+/// Who is using the admin socket, as the audit log sees them
+struct Administrator {
+    identity: Identity,
+    origin: Origin,
+}
+
+#[instrument(skip_all, fields(path = %options.path.display()))]
+pub async fn listener_proc(
+    mut options: UnixListenOptions,
+    state: AdminState,
+) -> anyhow::Result<JoinHandle<anyhow::Error>> {
+    options.mode.get_or_insert(DEFAULT_MODE);
+    let listener = unix_server::bind(&options).map_err(|error| {
+        error!(%error, "Failed to bind");
+        error
+    })?;
+    info!("Admin listener starting");
+    Ok(tokio::spawn(
+        async move {
+            let error = listener_task(listener, options.peers, state).await.unwrap_err();
+            error!(%error, "Admin listener failed to accept");
+            error
+        }
+        .in_current_span(),
+    ))
+}
+
+async fn listener_task(
+    listener: UnixListener,
+    peers: PeerPolicy,
+    state: AdminState,
+) -> anyhow::Result<Never> {
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let cred = match conn.peer_cred() {
+            Ok(cred) => cred,
+            Err(error) => {
+                warn!(%error, "Failed to get peer credentials");
+                continue;
+            }
+        };
+        let (uid, gid) = (cred.uid(), cred.gid());
+        let name = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map_or_else(|| format!("uid:{uid}"), |user| user.name);
+        let conn_span = info_span!(target: "connection-handling", "admin_accept", user = name, pid = cred.pid());
+        let allowed = peers.check(uid, gid, &name);
+        let state = state.clone();
+        let admin = Administrator {
+            identity: Identity { name },
+            origin: Origin {
+                listener: "admin",
+                address: Some(format!("uid:{uid}")),
+            },
+        };
+        tokio::spawn(
+            async move {
+                let result = if allowed {
+                    handle_connection(conn, &state, &admin).await
+                } else {
+                    warn!("Rejecting admin connection");
+                    respond(&mut BufReader::new(conn), &AdminResponse::Error("Permission denied".to_owned())).await
+                };
+                if let Err(error) = result {
+                    error!(%error, "Admin connection failed");
+                }
+            }
+            .instrument(conn_span),
+        );
+    }
+}
+
+async fn respond(conn: &mut BufReader<UnixStream>, response: &AdminResponse) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    conn.get_mut().write_all(&line).await?;
+    Ok(())
+}
+
+async fn handle_connection(conn: UnixStream, state: &AdminState, admin: &Administrator) -> anyhow::Result<()> {
+    let mut conn = BufReader::new(conn);
+    let mut line = String::new();
+    while conn.read_line(&mut line).await? != 0 {
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => {
+                info!(?request, "Admin request");
+                state.handle(request, admin).await.unwrap_or_else(|error| {
+                    warn!(%error, "Admin request failed");
+                    AdminResponse::Error(format!("{error:#}"))
+                })
+            }
+            Err(error) => AdminResponse::Error(format!("Invalid request: {error}")),
+        };
+        respond(&mut conn, &response).await?;
+        line.clear();
+    }
+    Ok(())
+}
+
+impl AdminState {
+    async fn handle(&self, request: AdminRequest, admin: &Administrator) -> anyhow::Result<AdminResponse> {
+        match request {
+            AdminRequest::Sessions => Ok(AdminResponse::Sessions(self.sessions.list())),
+            AdminRequest::Clients { session } => {
+                let mut clients = vec![];
+                for requester in self.sessions_or_all(session.as_deref())? {
+                    let roster = requester.snapshot().await?.get_roster().cloned();
+                    clients.extend(roster.into_iter().flat_map(|roster| roster.clients).map(
+                        |client| ClientInfo {
+                            session: requester.session().to_owned(),
+                            client,
+                        },
+                    ));
+                }
+                Ok(AdminResponse::Clients(clients))
+            }
+            AdminRequest::Kick { id, session } => {
+                let mut outcome = Err(format!("No client {id} is attached"));
+                let mut looked_in = None;
+                for requester in self.sessions_or_all(session.as_deref())? {
+                    outcome = requester.kick(id).await?;
+                    looked_in = Some(requester);
+                    if outcome.is_ok() {
+                        break;
+                    }
+                }
+                // Recorded as if it were an action, in the session the client
+                // was found in, or else the last one looked in
+                if let Some(requester) = looked_in {
+                    let result = match &outcome {
+                        Ok(()) => succeeded(),
+                        Err(reason) => failed_run_result(reason.clone()),
+                    };
+                    if let Some(audit) = self.audit(admin, &requester, &[action("Kick", vec![id.to_string()])]) {
+                        audit.finish(&result);
+                    }
+                }
+                outcome.map(|()| AdminResponse::Ok).map_err(|reason| anyhow!(reason))
+            }
+            AdminRequest::Broadcast { text, session } => {
+                for requester in self.sessions_or_all(session.as_deref())? {
+                    requester.popup(text.clone()).await?;
+                }
+                Ok(AdminResponse::Ok)
+            }
+            AdminRequest::Reconnect { session } => {
+                let requester = self.sessions.get(session.as_deref())?;
+                let connect = self
+                    .sessions
+                    .list()
+                    .into_iter()
+                    .find(|info| info.name == requester.session())
+                    .ok_or_else(|| anyhow!("No such session {}", requester.session()))?
+                    .connect;
+                let actions = vec![action("Disconnect", vec![]), action("Connect", vec![connect])];
+                let audit = self.audit(admin, &requester, &actions);
+                run(&requester, actions, audit).await
+            }
+            AdminRequest::Disconnect { session } => {
+                let requester = self.sessions.get(session.as_deref())?;
+                let actions = vec![action("Disconnect", vec![])];
+                let audit = self.audit(admin, &requester, &actions);
+                run(&requester, actions, audit).await
+            }
+            AdminRequest::Rotate { session } => {
+                let named = session.is_some();
+                let mut rotated = false;
+                for requester in self.sessions_or_all(session.as_deref())? {
+                    match requester.rotate().await? {
+                        Ok(()) => rotated = true,
+                        // Asking for every session only concerns those being recorded
+                        Err(_) if !named => {}
+                        Err(reason) => bail!(reason),
+                    }
+                }
+                if !rotated {
+                    bail!("No sessions are being recorded");
+                }
+                Ok(AdminResponse::Ok)
+            }
+            AdminRequest::Reload => {
+                self.reload().await?;
+                Ok(AdminResponse::Ok)
+            }
+        }
+    }
+
+    /// Start recording what an administrator does to a session
+    fn audit(&self, admin: &Administrator, requester: &ArbiterHandleRequester, actions: &[Action]) -> Option<PendingAudit> {
+        AuditTrail::new(self.audit.clone(), &admin.identity, admin.origin.clone(), requester.session())
+            .begin(actions, false)
+    }
+
+    /// The named session, or every session if there's no name
+    fn sessions_or_all(&self, session: Option<&str>) -> anyhow::Result<Vec<ArbiterHandleRequester>> {
+        match session {
+            Some(name) => Ok(vec![self.sessions.get(Some(name))?]),
+            None => self
+                .sessions
+                .list()
+                .into_iter()
+                .map(|info| self.sessions.get(Some(&info.name)))
+                .collect(),
+        }
+    }
+
+    /// Read the configuration again. The token file and action policy take
+    /// effect right away; everything else needs a restart.
+    async fn reload(&self) -> anyhow::Result<()> {
+        let options = config::parse_args(self.args.iter().cloned())?;
+        let settings = options.config.resolve().await?;
+        self.auth.reload(settings.auth);
+        self.policy.reload(settings.policy);
+        info!("Reloaded token file and action policy");
+        Ok(())
+    }
+}
+
+async fn run(
+    requester: &ArbiterHandleRequester,
+    actions: Vec<Action>,
+    audit: Option<PendingAudit>,
+) -> anyhow::Result<AdminResponse> {
+    let result = requester.admin_actions(actions).await?;
+    if let Some(audit) = audit {
+        audit.finish(&result);
+    }
+    if !result.success {
+        bail!(result.text.join("\n"));
+    }
+    Ok(AdminResponse::Ok)
+}
+
+fn succeeded() -> RunResult {
+    RunResult {
+        r_tag: None,
+        success: true,
+        text: vec![],
+        abort: None,
+        time: 0.0,
+    }
+}
+
+fn action(name: &str, args: Vec<String>) -> Action {
+    Action {
+        action: name.to_owned(),
+        args,
+    }
+}
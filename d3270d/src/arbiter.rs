@@ -19,9 +19,13 @@
 use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use anyhow::anyhow;
+use chrono::{SecondsFormat, Utc};
 use base64::engine::general_purpose::STANDARD as B64_STANDARD;
 use base64::Engine;
 use bytes::Buf;
@@ -29,18 +33,181 @@ use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
 use rand::RngCore;
 use tokio::io::{AsyncBufReadExt, AsyncWrite, BufReader, Lines};
 use tokio::process::{Child, ChildStdout};
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{Instant, Sleep};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tracing::{error, info, info_span, instrument, trace, warn, Instrument};
 
-use d3270_common::b3270::indication::RunResult;
-use d3270_common::b3270::operation::{Action, Run};
+use d3270_common::b3270::indication::{
+    ActionCause, FileTransfer, FileTransferState, Floor, Passthru, Popup, PopupType, Roster,
+    RosterEntry, RunResult, UiError,
+};
+use d3270_common::b3270::operation::{Action, Fail, FloorAction, Register, Run, Succeed, WaitFor};
+use d3270_common::b3270::types::{GraphicRendition, PackedAttr};
 use d3270_common::b3270::{operation, Indication, Operation};
 use d3270_common::tracker::{Disposition, Tracker};
 
-enum B3270Request {
-    Action(Vec<Action>, oneshot::Sender<RunResult>),
+use crate::audit::{redact_typing, Origin};
+use crate::metrics::{HandleMetrics, SessionMetrics};
+use crate::recording::{RecordOptions, Recorder};
+use crate::replay::ReplayCommand;
+use crate::screen_wait::ScreenWaits;
+
+/// A result for actions that were never run
+pub fn failed_run_result(text: String) -> RunResult {
+    RunResult {
+        r_tag: None,
+        success: false,
+        text: vec![text],
+        abort: None,
+        time: 0.0,
+    }
+}
+
+/// Whether the cursor is in a non-display field, such as a password prompt.
+/// b3270 marks the contents of non-display fields as no-copy.
+pub(crate) fn cursor_in_hidden_field(tracker: &Tracker) -> bool {
+    tracker
+        .get_cursor_cell()
+        .is_some_and(|cell| cell.attr.c_gr().contains(GraphicRendition::NO_COPY))
+}
+
+/// Identifies an [`ArbiterHandle`] for the purposes of floor control
+pub type ClientId = u64;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Who actions run from the admin socket are recorded as
+const ADMIN_NAME: &str = "admin";
+
+/// The tag of the run of the session's initial actions, such as connecting to
+/// its host. Other runs get random tags in base64, which this can't be.
+const INITIAL_RUN_TAG: &str = "d3270d-initial";
+
+pub(crate) enum B3270Request {
+    // The client, its name, and what it wants run
+    Action(Originator, String, Vec<Action>, oneshot::Sender<RunResult>),
     Resync(oneshot::Sender<(Vec<Indication>, broadcast::Receiver<Indication>)>),
+    Floor(FloorRequest, oneshot::Sender<Result<(), String>>),
+    Register(RegisterRequest, oneshot::Sender<Result<(), String>>),
+    // The answer to a passthru action, from the client it was sent to
+    Complete(Originator, PassthruCompletion, oneshot::Sender<Result<(), String>>),
+    // A client to add to the roster until its handle is dropped. Sending a
+    // reason on the oneshot disconnects it.
+    Join(RosterEntry, watch::Receiver<()>, oneshot::Sender<String>),
+    // Only a replay does anything with this
+    Replay(ReplayCommand, oneshot::Sender<Result<(), String>>),
+    // The rest come from the admin socket
+    Kick(ClientId, oneshot::Sender<Result<(), String>>),
+    Popup(String),
+    Rotate(oneshot::Sender<Result<(), String>>),
+    // Actions to run whoever holds the keyboard
+    AdminAction(Vec<Action>, oneshot::Sender<RunResult>),
+    // A client's wait-for operation, and its direct channel for the answer
+    WaitFor(WaitFor, mpsc::UnboundedSender<Indication>),
+    // Disconnect every client with this reason, then let b3270 exit. The
+    // oneshot is sent on once it has.
+    Stop(String, oneshot::Sender<()>),
+}
+
+pub(crate) struct FloorRequest {
+    client: ClientId,
+    name: String,
+    action: FloorAction,
+    // Closes when the requesting handle is dropped
+    presence: watch::Receiver<()>,
+}
+
+/// The client that sent an operation to b3270, so that whatever the
+/// operation causes can be sent back to it alone
+#[derive(Clone)]
+pub(crate) struct Originator {
+    client: ClientId,
+    direct: mpsc::UnboundedSender<Indication>,
+}
+
+/// A run that b3270 has been given but hasn't answered yet
+struct UnansweredRun {
+    r_tag: String,
+    // Nobody, for runs that d3270d makes itself
+    origin: Option<Originator>,
+}
+
+/// An error about a run that can't yet be told apart from the others that
+/// were unanswered when it arrived
+struct UnmatchedRunError {
+    error: UiError,
+    // The runs it may be about, oldest first, less those answered since
+    candidates: Vec<String>,
+    // Whether it has been sent to everybody in the meantime
+    broadcast: bool,
+}
+
+pub(crate) struct RegisterRequest {
+    // Passthru indications for the action go to its direct channel
+    originator: Originator,
+    register: Register,
+    // Closes when the requesting handle is dropped
+    presence: watch::Receiver<()>,
+}
+
+/// How a client finished a passthru action
+pub enum PassthruCompletion {
+    Succeed(Succeed),
+    Fail(Fail),
+}
+
+impl PassthruCompletion {
+    fn p_tag(&self) -> &str {
+        match self {
+            PassthruCompletion::Succeed(Succeed { p_tag, .. })
+            | PassthruCompletion::Fail(Fail { p_tag, .. }) => p_tag,
+        }
+    }
+}
+
+impl From<PassthruCompletion> for Operation {
+    fn from(completion: PassthruCompletion) -> Self {
+        match completion {
+            PassthruCompletion::Succeed(succeed) => Operation::Succeed(succeed),
+            PassthruCompletion::Fail(fail) => Operation::Fail(fail),
+        }
+    }
+}
+
+/// A passthru action that a client has registered
+struct PassthruOwner {
+    client: ClientId,
+    register: Register,
+    direct: mpsc::UnboundedSender<Indication>,
+    // Resolves once the client has gone away
+    gone: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+/// Resolves once the handle that `presence` came from has been dropped
+pub(crate) fn departure(mut presence: watch::Receiver<()>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move { while presence.changed().await.is_ok() {} })
+}
+
+struct RosterMember {
+    entry: RosterEntry,
+    gone: Pin<Box<dyn Future<Output = ()> + Send>>,
+    kick: Option<oneshot::Sender<String>>,
+}
+
+/// How far the arbiter has got with shutting down
+enum Shutdown {
+    Running,
+    // Waiting for clients to leave, then for b3270 to exit
+    Stopping(oneshot::Sender<()>),
+    Stopped,
+}
+
+struct FloorHolder {
+    client: ClientId,
+    name: String,
+    // Resolves once the holder has gone away
+    gone: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 enum HandleReceiveState {
@@ -58,9 +225,111 @@ enum HandleReceiveState {
 pub struct ArbiterHandle {
     sender: mpsc::Sender<B3270Request>,
     receiver: Option<HandleReceiveState>,
+    // Indications meant for this client alone, such as passthru actions
+    direct: mpsc::UnboundedSender<Indication>,
+    direct_rcv: mpsc::UnboundedReceiver<Indication>,
+    id: ClientId,
+    name: String,
+    // Never sent on; the arbiter notices when it is dropped
+    presence: watch::Sender<()>,
+    // Fires, with the reason, if this client is disconnected by the server
+    kicked: Option<oneshot::Receiver<String>>,
+    metrics: HandleMetrics,
+    input_hidden: watch::Receiver<bool>,
 }
 
 impl ArbiterHandle {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    fn originator(&self) -> Originator {
+        Originator {
+            client: self.id,
+            direct: self.direct.clone(),
+        }
+    }
+
+    /// Whether the cursor is in a non-display field, such as a password prompt
+    pub fn input_hidden(&self) -> bool {
+        *self.input_hidden.borrow()
+    }
+
+    /// Show this client in the session's roster for as long as the handle
+    /// lives. From then on, the server can disconnect it.
+    pub async fn join(&mut self, origin: &Origin, read_only: bool) -> anyhow::Result<()> {
+        let entry = RosterEntry {
+            id: self.id,
+            user: self.name.clone(),
+            listener: origin.listener.to_owned(),
+            peer: origin.address.clone(),
+            connected: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            read_only,
+            last_input: None,
+        };
+        let (kick, kicked) = oneshot::channel();
+        self.kicked = Some(kicked);
+        self.sender
+            .send(B3270Request::Join(entry, self.presence.subscribe(), kick))
+            .await
+            .map_err(|_| anyhow!("Failed to send join request to arbiter"))
+    }
+
+    /// Ask for or give up the keyboard. Returns the reason the request was
+    /// refused, if it was.
+    pub async fn floor(&self, action: FloorAction) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        let request = FloorRequest {
+            client: self.id,
+            name: self.name.clone(),
+            action,
+            presence: self.presence.subscribe(),
+        };
+        self.sender
+            .send(B3270Request::Floor(request, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send floor request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Register a passthru action. Whenever it is run, this handle receives
+    /// the passthru indication and must answer it with [`Self::complete`].
+    pub async fn register(&self, register: Register) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        let request = RegisterRequest {
+            originator: self.originator(),
+            register,
+            presence: self.presence.subscribe(),
+        };
+        self.sender
+            .send(B3270Request::Register(request, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send register request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Answer a passthru action that was sent to this handle
+    pub async fn complete(
+        &self,
+        completion: PassthruCompletion,
+    ) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Complete(self.originator(), completion, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send passthru result to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Ask to be sent a wait-result indication once the screen meets the
+    /// condition
+    pub async fn wait_for(&self, wait: WaitFor) -> anyhow::Result<()> {
+        self.sender
+            .send(B3270Request::WaitFor(wait, self.direct.clone()))
+            .await
+            .map_err(|_| anyhow!("Failed to send wait request to arbiter"))
+    }
+
     pub async fn send_action(
         &self,
         action: Action,
@@ -75,17 +344,58 @@ impl ArbiterHandle {
     ) -> anyhow::Result<oneshot::Receiver<RunResult>> {
         let (os_snd, os_rcv) = oneshot::channel();
         self.sender
-            .send(B3270Request::Action(actions, os_snd))
+            .send(B3270Request::Action(self.originator(), self.name.clone(), actions, os_snd))
             .await
             .map_err(|_| anyhow!("Failed to send action to arbiter"))?;
         Ok(os_rcv)
     }
 }
 
+impl ArbiterHandle {
+    fn start_resync(&mut self) {
+        let (os_snd, os_rcv) = oneshot::channel();
+        let fut = self
+            .sender
+            .clone()
+            .reserve_owned()
+            .map_ok(move |permit| {
+                permit.send(B3270Request::Resync(os_snd));
+            })
+            .map_err(|_| ());
+
+        self.receiver = Some(HandleReceiveState::TryRestart(Box::pin(fut), os_rcv));
+    }
+}
+
 impl Stream for ArbiterHandle {
     type Item = Indication;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(kicked) = self.kicked.as_mut() {
+            match kicked.poll_unpin(cx) {
+                Poll::Ready(Ok(reason)) => {
+                    info!(reason, "Disconnecting client");
+                    self.kicked = None;
+                    // Ends the stream once the client has been told
+                    self.receiver = None;
+                    return Poll::Ready(Some(Indication::UiError(UiError {
+                        fatal: true,
+                        text: reason,
+                        operation: None,
+                        member: None,
+                        line: None,
+                        column: None,
+                    })));
+                }
+                // The arbiter doesn't keep track of us (a replay doesn't)
+                Poll::Ready(Err(_)) => self.kicked = None,
+                Poll::Pending => {}
+            }
+        }
+        // We hold a sender ourselves, so this never ends
+        if let Poll::Ready(Some(ind)) = self.direct_rcv.poll_recv(cx) {
+            return Poll::Ready(Some(ind));
+        }
         loop {
             match self.receiver.take() {
                 Some(HandleReceiveState::TryRestart(mut fut, receiver)) => {
@@ -129,25 +439,20 @@ impl Stream for ArbiterHandle {
                         return Poll::Ready(Some(msg));
                     }
                     Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(n)))) => {
+                        self.metrics.lagged.inc_by(n);
+                        self.metrics.lag_resyncs.inc();
                         info!(
                             dropped = n,
                             "Dropped messages from b3270 server; starting resync"
                         );
-                        let (os_snd, os_rcv) = oneshot::channel();
-                        let fut = self
-                            .sender
-                            .clone()
-                            .reserve_owned()
-                            .map_ok(move |permit| {
-                                permit.send(B3270Request::Resync(os_snd));
-                            })
-                            .map_err(|_| ());
-
-                        self.receiver = Some(HandleReceiveState::TryRestart(Box::pin(fut), os_rcv));
+                        self.start_resync();
                     }
                     Poll::Ready(None) => {
-                        warn!("Failed to receive from b3270 server");
-                        return Poll::Ready(None);
+                        // The arbiter closes the channel when b3270 is restarted. If the
+                        // arbiter itself is gone, the resync fails and the stream ends.
+                        self.metrics.restart_resyncs.inc();
+                        info!("Indication stream closed; starting resync");
+                        self.start_resync();
                     }
                     Poll::Pending => {
                         self.receiver = Some(HandleReceiveState::Steady(rcvr));
@@ -164,25 +469,162 @@ impl Stream for ArbiterHandle {
 }
 
 #[derive(Clone)]
-pub struct ArbiterHandleRequester(mpsc::Sender<B3270Request>);
+pub struct ArbiterHandleRequester {
+    sender: mpsc::Sender<B3270Request>,
+    session: Arc<str>,
+    metrics: HandleMetrics,
+    input_hidden: watch::Receiver<bool>,
+}
 
 impl ArbiterHandleRequester {
+    pub(crate) fn new(
+        sender: mpsc::Sender<B3270Request>,
+        session: &str,
+        metrics: HandleMetrics,
+        input_hidden: watch::Receiver<bool>,
+    ) -> Self {
+        ArbiterHandleRequester {
+            sender,
+            session: session.into(),
+            metrics,
+            input_hidden,
+        }
+    }
+
+    /// `name` is shown to other clients when this one holds the floor
     #[instrument(skip(self))]
-    pub async fn connect(&self) -> anyhow::Result<ArbiterHandle> {
+    pub async fn connect(&self, name: &str) -> anyhow::Result<ArbiterHandle> {
         let (conn_send, conn_rcv) = oneshot::channel();
-        self.0
+        self.sender
             .send(B3270Request::Resync(conn_send))
             .await
             .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
 
         let (indications, rcvr) = conn_rcv.await?;
+        let (direct, direct_rcv) = mpsc::unbounded_channel();
         Ok(ArbiterHandle {
-            sender: self.0.clone(),
+            sender: self.sender.clone(),
             receiver: Some(HandleReceiveState::Resume(indications.into_iter(), rcvr)),
+            direct,
+            direct_rcv,
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.to_owned(),
+            presence: watch::channel(()).0,
+            kicked: None,
+            metrics: self.metrics.clone(),
+            input_hidden: self.input_hidden.clone(),
         })
     }
+
+    /// The name of the session this requester connects to
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Fetch the current terminal state without attaching a client
+    pub async fn snapshot(&self) -> anyhow::Result<Tracker> {
+        let (conn_send, conn_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Resync(conn_send))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+
+        let (indications, _) = conn_rcv.await?;
+        let mut tracker = Tracker::default();
+        for mut indication in indications {
+            tracker.handle_indication(&mut indication);
+        }
+        Ok(tracker)
+    }
+
+    /// Control playback, if this session is a replay. Returns the reason the
+    /// command was refused, if it was.
+    pub async fn replay(&self, command: ReplayCommand) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Replay(command, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Disconnect a client. Returns an error message if it isn't attached to this session.
+    pub async fn kick(&self, client: ClientId) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Kick(client, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Show a popup to every client
+    pub async fn popup(&self, text: String) -> anyhow::Result<()> {
+        self.sender
+            .send(B3270Request::Popup(text))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))
+    }
+
+    /// Start a new recording file. Returns the reason it couldn't, if the
+    /// session isn't being recorded.
+    pub async fn rotate(&self) -> anyhow::Result<Result<(), String>> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Rotate(reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Run actions on behalf of an administrator, even if somebody holds the keyboard
+    pub async fn admin_actions(&self, actions: Vec<Action>) -> anyhow::Result<RunResult> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::AdminAction(actions, reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+
+    /// Disconnect every client, telling them `reason`, and stop b3270.
+    /// Returns once b3270 has exited.
+    pub async fn stop(&self, reason: &str) -> anyhow::Result<()> {
+        let (reply, reply_rcv) = oneshot::channel();
+        self.sender
+            .send(B3270Request::Stop(reason.to_owned(), reply))
+            .await
+            .map_err(|_| anyhow!("Failed to send request to arbiter"))?;
+        Ok(reply_rcv.await?)
+    }
+}
+
+/// How the arbiter deals with b3270 exiting
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// How many times in a row b3270 may be restarted before the session is given up on
+    pub max_restarts: u32,
+    /// Delay before the first restart; it doubles with every consecutive restart
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
 }
 
+/// A b3270 that stays up this long is considered healthy again, and the
+/// count of consecutive restarts is reset
+const STABLE_RUN_TIME: Duration = Duration::from_secs(60);
+
+type ChildSpawner = Box<dyn FnMut() -> std::io::Result<Child> + Send>;
+
 pub struct B3270 {
     tracker: Tracker,
     child: Child,
@@ -191,51 +633,652 @@ pub struct B3270 {
     child_reader: Lines<BufReader<ChildStdout>>,
 
     write_buf: VecDeque<u8>,
+    // Bytes ever queued for and written to b3270. Together with
+    // `queued_origins`, these tell which operation b3270 has read last.
+    queued_bytes: u64,
+    written_bytes: u64,
+    // Where each queued operation ends, its name, its tag if it is a run,
+    // and who sent it
+    queued_origins: VecDeque<(u64, &'static str, Option<String>, Option<Originator>)>,
+    // Who sent the last operation that b3270 was given, and the last of each kind
+    last_written: Option<Originator>,
+    last_written_by_name: HashMap<&'static str, Option<Originator>>,
+    // Runs that b3270 has been given but hasn't answered, oldest first.
+    // d3270d's own are here too, so that a bad run is blamed on the right one.
+    unanswered_runs: VecDeque<UnansweredRun>,
+    // Errors about runs, in the order they arrived, until it is known which
+    // run each is about
+    unmatched_run_errors: VecDeque<UnmatchedRunError>,
+    // Who started the file transfer in progress, if a client did
+    transfer_owner: Option<Originator>,
     action_response_map: HashMap<String, oneshot::Sender<RunResult>>,
+    floor: Option<FloorHolder>,
+    // Attached clients, in the order they joined
+    roster: Vec<RosterMember>,
+    // Registered passthru actions, by lowercased name
+    passthru_owners: HashMap<String, PassthruOwner>,
+    // Passthru actions in progress, by p-tag, and the client handling each
+    pending_passthru: HashMap<String, ClientId>,
+    waits: ScreenWaits,
+
+    spawn_child: ChildSpawner,
+    initial_actions: Vec<Action>,
+    restart_policy: RestartPolicy,
+    // Consecutive restarts so far
+    restarts: u32,
+    started_at: Instant,
+    // Set while b3270 is down and waiting to be restarted
+    restart_timer: Option<Pin<Box<Sleep>>>,
+    metrics: SessionMetrics,
+    input_hidden: watch::Sender<bool>,
+    recorder: Option<Recorder>,
+    shutdown: Shutdown,
 }
 
 impl B3270 {
+    /// Start b3270 using `spawn_child`, which must capture its stdin and
+    /// stdout. It is called again to replace b3270 whenever it exits.
+    /// `session` labels the session's metrics and recordings.
     pub fn spawn(
-        mut child: Child,
+        session: &str,
+        mut spawn_child: ChildSpawner,
         initial_actions: &[Action],
-    ) -> (
+        restart_policy: RestartPolicy,
+        recording: Option<RecordOptions>,
+    ) -> anyhow::Result<(
         tokio::task::JoinHandle<anyhow::Error>,
         ArbiterHandleRequester,
-    ) {
+    )> {
         let (subproc_snd, subproc_rcv) = mpsc::channel(10);
-        let child_reader = child
-            .stdout
-            .take()
-            .expect("Should always be given a child that has stdout captured");
-        let child_reader = BufReader::new(child_reader).lines();
+        let mut child = spawn_child()?;
+        let child_reader = Self::child_reader(&mut child);
         // A single connect can result in a flurry of messages, so we need a big buffer
         let (ind_chan, _) = broadcast::channel(100);
 
-        let mut write_buf = VecDeque::new();
-
-        // Queue any initial actions.
-        let act_str = serde_json::to_string(&Operation::Run(Run{
-            actions: initial_actions.to_vec(),
-            type_: Some("keybind".to_owned()),
-            r_tag: None,
-        })).unwrap();
-        trace!(json=%act_str, "Writing initialization action");
-        write_buf.extend(act_str.as_bytes());
-        write_buf.push_back(b'\n');
-
-        let proc = B3270 {
+        let mut proc = B3270 {
             child,
             child_reader,
             tracker: Tracker::default(),
             comm: subproc_rcv,
             ind_chan,
-            write_buf,
+            write_buf: VecDeque::new(),
+            queued_bytes: 0,
+            written_bytes: 0,
+            queued_origins: VecDeque::new(),
+            last_written: None,
+            last_written_by_name: HashMap::new(),
+            unanswered_runs: VecDeque::new(),
+            unmatched_run_errors: VecDeque::new(),
+            transfer_owner: None,
             action_response_map: Default::default(),
+            floor: None,
+            roster: vec![],
+            passthru_owners: Default::default(),
+            pending_passthru: Default::default(),
+            waits: ScreenWaits::default(),
+            spawn_child,
+            initial_actions: initial_actions.to_vec(),
+            restart_policy,
+            restarts: 0,
+            started_at: Instant::now(),
+            restart_timer: None,
+            metrics: SessionMetrics::new(session),
+            input_hidden: watch::channel(false).0,
+            recorder: None,
+            shutdown: Shutdown::Running,
+        };
+        if let Some(options) = recording {
+            let snapshot = proc.tracker.get_init_indication();
+            proc.recorder = Some(Recorder::start(options, session, snapshot)?);
+        }
+        let input_hidden = proc.input_hidden.subscribe();
+        let handle_metrics = proc.metrics.handle_metrics();
+        proc.queue_initial_actions();
+        Ok((
+            tokio::task::spawn(proc.instrument(info_span!("arbiter", session))),
+            ArbiterHandleRequester::new(subproc_snd, session, handle_metrics, input_hidden),
+        ))
+    }
+
+    fn child_reader(child: &mut Child) -> Lines<BufReader<ChildStdout>> {
+        let child_reader = child
+            .stdout
+            .take()
+            .expect("Should always be given a child that has stdout captured");
+        BufReader::new(child_reader).lines()
+    }
+
+    fn queue_initial_actions(&mut self) {
+        let op = Operation::Run(Run {
+            actions: self.initial_actions.clone(),
+            type_: Some("keybind".to_owned()),
+            // Nobody waits for the result, but tagging it lets it be matched
+            // up with the run
+            r_tag: Some(INITIAL_RUN_TAG.to_owned()),
+        });
+        self.queue_operation(&op, None);
+    }
+
+    /// Queue an operation for b3270. Problems it reports are sent to `origin`.
+    fn queue_operation(&mut self, op: &Operation, origin: Option<Originator>) {
+        match serde_json::to_string(op) {
+            Ok(op_str) => {
+                trace!(json = op_str, "Sending operation");
+                self.write_buf.extend(op_str.bytes());
+                self.write_buf.push_back(b'\n');
+                self.queued_bytes += op_str.len() as u64 + 1;
+                let r_tag = match op {
+                    Operation::Run(run) => run.r_tag.clone(),
+                    _ => None,
+                };
+                self.queued_origins
+                    .push_back((self.queued_bytes, op.name(), r_tag, origin));
+            }
+            Err(error) => error!(?op, %error, "Failed to serialize op"),
+        }
+    }
+
+    fn wrote(&mut self, n: usize) {
+        self.write_buf.advance(n);
+        self.written_bytes += n as u64;
+        while let Some((end, _, _, _)) = self.queued_origins.front() {
+            if *end > self.written_bytes {
+                break;
+            }
+            let (_, name, r_tag, origin) = self.queued_origins.pop_front().unwrap();
+            if let Some(r_tag) = r_tag {
+                self.unanswered_runs.push_back(UnansweredRun {
+                    r_tag,
+                    origin: origin.clone(),
+                });
+            }
+            self.last_written_by_name.insert(name, origin.clone());
+            self.last_written = origin;
+        }
+    }
+
+    /// Deal with b3270 going away. Returns an error if it shouldn't be restarted.
+    fn child_exited(&mut self, reason: anyhow::Error) -> Option<anyhow::Error> {
+        // Nothing that was in flight is going to be answered now
+        for (_, dest) in self.action_response_map.drain() {
+            dest.send(failed_run_result(format!("b3270 exited: {reason}")))
+            .ok();
+        }
+        self.pending_passthru.clear();
+        self.write_buf.clear();
+        self.written_bytes = self.queued_bytes;
+        self.queued_origins.clear();
+        self.last_written = None;
+        self.last_written_by_name.clear();
+        self.unanswered_runs.clear();
+        self.unmatched_run_errors.clear();
+        self.transfer_owner = None;
+
+        if let Shutdown::Stopping(_) = self.shutdown {
+            info!(%reason, "b3270 has stopped");
+            self.finish_shutdown();
+            return None;
+        }
+        if self.started_at.elapsed() >= STABLE_RUN_TIME {
+            self.restarts = 0;
+        }
+        if self.restarts >= self.restart_policy.max_restarts {
+            error!(%reason, restarts = self.restarts, "Not restarting b3270");
+            return Some(reason);
+        }
+        let backoff = self
+            .restart_policy
+            .initial_backoff
+            .saturating_mul(1 << self.restarts.min(16))
+            .min(self.restart_policy.max_backoff);
+        self.restarts += 1;
+        warn!(%reason, ?backoff, attempt = self.restarts, "b3270 exited; restarting");
+        self.restart_timer = Some(Box::pin(tokio::time::sleep(backoff)));
+        None
+    }
+
+    /// Replace b3270 with a fresh one and start everything over
+    fn restart_child(&mut self) -> std::io::Result<()> {
+        let mut child = (self.spawn_child)()?;
+        self.child_reader = Self::child_reader(&mut child);
+        self.child = child;
+        self.started_at = Instant::now();
+        info!("Restarted b3270");
+
+        // The new b3270 knows nothing of what the old one displayed. The floor
+        // and roster are ours, though, so they survive.
+        self.tracker = Tracker::default();
+        let mut floor = self.floor_indication();
+        self.tracker.handle_indication(&mut floor);
+        let mut roster = self.roster_indication();
+        self.tracker.handle_indication(&mut roster);
+        self.queue_initial_actions();
+        // Passthru actions stay registered for as long as their clients are around
+        let registrations = self
+            .passthru_owners
+            .values()
+            .map(|owner| Operation::Register(owner.register.clone()))
+            .collect::<Vec<_>>();
+        for op in &registrations {
+            self.queue_operation(op, None);
+        }
+
+        // Closing the indication channel makes every handle resync
+        self.ind_chan = broadcast::channel(100).0;
+        Ok(())
+    }
+}
+
+impl B3270 {
+    fn floor_indication(&self) -> Indication {
+        Indication::Floor(Floor {
+            holder: self.floor.as_ref().map(|holder| holder.name.clone()),
+            holder_id: self.floor.as_ref().map(|holder| holder.client),
+            mine: false,
+        })
+    }
+
+    fn set_floor(&mut self, holder: Option<FloorHolder>) {
+        info!(holder = ?holder.as_ref().map(|holder| &holder.name), "Floor changed");
+        self.floor = holder;
+        let mut ind = self.floor_indication();
+        self.tracker.handle_indication(&mut ind);
+        self.broadcast(ind);
+    }
+
+    /// Send an indication to every client, and to the recording
+    fn broadcast(&mut self, ind: Indication) {
+        self.record(|recorder| recorder.indication(&ind));
+        // It's OK to drop these, as anybody who cares will resync
+        self.ind_chan.send(ind).ok();
+    }
+
+    /// Write to the recording, if there is one. If that fails, recording stops
+    /// rather than filling the log with errors.
+    fn record(&mut self, write: impl FnOnce(&mut Recorder) -> anyhow::Result<()>) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(error) = write(recorder) {
+                error!(%error, "Failed to write recording; no longer recording");
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn roster_indication(&self) -> Indication {
+        Indication::Roster(Roster {
+            clients: self.roster.iter().map(|member| member.entry.clone()).collect(),
+        })
+    }
+
+    fn roster_changed(&mut self) {
+        let mut ind = self.roster_indication();
+        self.tracker.handle_indication(&mut ind);
+        self.broadcast(ind);
+    }
+
+    fn join(&mut self, entry: RosterEntry, presence: watch::Receiver<()>, kick: oneshot::Sender<String>) {
+        info!(client = entry.id, user = entry.user, listener = entry.listener, "Client joined");
+        self.roster.push(RosterMember {
+            entry,
+            gone: departure(presence),
+            kick: Some(kick),
+        });
+        self.roster_changed();
+    }
+
+    /// Disconnect a client. It leaves the roster once its connection has closed.
+    fn kick(&mut self, client: ClientId) -> Result<(), String> {
+        let member = self
+            .roster
+            .iter_mut()
+            .find(|member| member.entry.id == client)
+            .ok_or_else(|| format!("No client {client} is attached"))?;
+        let kick = member
+            .kick
+            .take()
+            .ok_or_else(|| format!("Client {client} is already being disconnected"))?;
+        info!(client, user = member.entry.user, "Disconnecting client");
+        kick.send("Disconnected by an administrator".to_owned()).ok();
+        Ok(())
+    }
+
+    fn stop(&mut self, reason: String, stopped: oneshot::Sender<()>) {
+        if let Shutdown::Stopped = self.shutdown {
+            stopped.send(()).ok();
+            return;
+        }
+        info!(reason, "Shutting down");
+        for member in &mut self.roster {
+            if let Some(kick) = member.kick.take() {
+                kick.send(reason.clone()).ok();
+            }
+        }
+        self.shutdown = Shutdown::Stopping(stopped);
+    }
+
+    /// Once every client has gone, let b3270 go too
+    fn continue_shutdown(&mut self) {
+        if !matches!(self.shutdown, Shutdown::Stopping(_)) || !self.roster.is_empty() {
+            return;
+        }
+        if self.restart_timer.take().is_some() {
+            // There's no b3270 to stop
+            self.finish_shutdown();
+        } else if self.child.stdin.take().is_some() {
+            // b3270 exits once its input is closed
+            info!("Closing b3270's input");
+            self.write_buf.clear();
+        }
+    }
+
+    fn finish_shutdown(&mut self) {
+        if let Shutdown::Stopping(stopped) = std::mem::replace(&mut self.shutdown, Shutdown::Stopped) {
+            stopped.send(()).ok();
+        }
+    }
+
+    fn rotate_recording(&mut self) -> Result<(), String> {
+        if self.recorder.is_none() {
+            return Err("This session isn't being recorded".to_owned());
+        }
+        let snapshot = self.tracker.get_init_indication();
+        self.record(|recorder| recorder.rotate(snapshot));
+        // Writing the new file may have failed, which stops the recording
+        match self.recorder {
+            Some(_) => Ok(()),
+            None => Err("Failed to start a new recording file; see the log".to_owned()),
+        }
+    }
+
+    /// Note that a client sent something. Nobody is told right away, as that
+    /// would mean telling everybody about every keystroke, but clients that
+    /// attach later see it.
+    fn touch(&mut self, client: ClientId) {
+        let Some(member) = self.roster.iter_mut().find(|member| member.entry.id == client) else {
+            return;
+        };
+        member.entry.last_input = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+        let mut ind = self.roster_indication();
+        self.tracker.handle_indication(&mut ind);
+    }
+
+    fn drop_departed_clients(&mut self, cx: &mut Context<'_>) {
+        let before = self.roster.len();
+        self.roster.retain_mut(|member| {
+            let gone = member.gone.poll_unpin(cx).is_ready();
+            if gone {
+                info!(client = member.entry.id, user = member.entry.user, "Client left");
+            }
+            !gone
+        });
+        if self.roster.len() != before {
+            self.roster_changed();
+        }
+    }
+
+    fn handle_floor_request(&mut self, request: FloorRequest) -> Result<(), String> {
+        let held_by_requester = self
+            .floor
+            .as_ref()
+            .map(|holder| holder.client == request.client);
+        match (request.action, held_by_requester) {
+            (FloorAction::Release, Some(true)) => self.set_floor(None),
+            (FloorAction::Release, _) => return Err("You don't hold the keyboard".to_owned()),
+            (FloorAction::Request, Some(false)) => {
+                return Err(format!(
+                    "The keyboard is held by {}",
+                    self.floor.as_ref().unwrap().name
+                ))
+            }
+            (FloorAction::Request | FloorAction::Take, Some(true)) => {}
+            (FloorAction::Request | FloorAction::Take, _) => {
+                self.set_floor(Some(FloorHolder {
+                    client: request.client,
+                    name: request.name,
+                    gone: departure(request.presence),
+                }))
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_register_request(&mut self, request: RegisterRequest) -> Result<(), String> {
+        let key = request.register.name.to_lowercase();
+        let originator = request.originator;
+        if let Some(owner) = self.passthru_owners.get(&key) {
+            if owner.client != originator.client {
+                return Err(format!(
+                    "{} is already registered by another client",
+                    request.register.name
+                ));
+            }
+        }
+        info!(action = request.register.name, client = originator.client, "Registered passthru action");
+        self.queue_operation(
+            &Operation::Register(request.register.clone()),
+            Some(originator.clone()),
+        );
+        self.passthru_owners.insert(
+            key,
+            PassthruOwner {
+                client: originator.client,
+                register: request.register,
+                direct: originator.direct,
+                gone: departure(request.presence),
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_completion(
+        &mut self,
+        originator: Originator,
+        completion: PassthruCompletion,
+    ) -> Result<(), String> {
+        let p_tag = completion.p_tag();
+        if self.pending_passthru.get(p_tag) != Some(&originator.client) {
+            return Err(format!("No passthru action {p_tag} is waiting on you"));
+        }
+        self.pending_passthru.remove(p_tag);
+        self.queue_operation(&completion.into(), Some(originator));
+        Ok(())
+    }
+
+    /// Send an error from b3270 to the client whose operation caused it. If
+    /// that was a run, the run fails with the error, as b3270 won't answer
+    /// it otherwise.
+    fn route_ui_error(&mut self, error: UiError) {
+        // b3270 deals with operations in order, but we may have written several
+        // since the one at fault. Anything but a run is dealt with as soon as
+        // it is read, so it's the last of its kind.
+        let origin = match error.operation.as_deref() {
+            _ if error.fatal => None,
+            Some("run") => {
+                self.unmatched_run_errors.push_back(UnmatchedRunError {
+                    error: error.clone(),
+                    candidates: self.unanswered_runs.iter().map(|run| run.r_tag.clone()).collect(),
+                    broadcast: false,
+                });
+                self.match_run_errors();
+                // Which run it was becomes clear as the others are answered;
+                // until then everybody is told, and the runs are left alone
+                match self.unmatched_run_errors.back_mut() {
+                    Some(unmatched) if !unmatched.broadcast => unmatched.broadcast = true,
+                    _ => return,
+                }
+                None
+            }
+            Some(name) => self.last_written_by_name.get(name).cloned().flatten(),
+            None => self.last_written.clone(),
+        };
+        let Some(origin) = origin else {
+            // Fatal errors concern everybody, and otherwise there's nobody else to tell
+            self.broadcast(Indication::UiError(error));
+            return;
         };
-        (
-            tokio::task::spawn(proc.instrument(info_span!("arbiter"))),
-            ArbiterHandleRequester(subproc_snd),
-        )
+        origin.direct.send(Indication::UiError(error)).ok();
+    }
+
+    /// Fail the runs that errors were about, once it is known which they
+    /// are, and tell their clients about any errors not already sent to
+    /// everybody.
+    ///
+    /// A run that went wrong is never answered, and was unanswered when its
+    /// error arrived; runs are read in order, so their errors arrive in the
+    /// same order. Once the first `n` errors have only `n` runs left between
+    /// them, those are the runs that they are about, oldest first.
+    fn match_run_errors(&mut self) {
+        while let Some(n) = (1..=self.unmatched_run_errors.len())
+            .find(|&n| self.unmatched_run_errors[n - 1].candidates.len() <= n)
+        {
+            if self.unmatched_run_errors[n - 1].candidates.len() < n {
+                // More errors than runs, so the oldest isn't about any of them
+                let unmatched = self.unmatched_run_errors.pop_front().unwrap();
+                warn!(error = unmatched.error.text, "Can't tell which run an error is about");
+                if !unmatched.broadcast {
+                    self.broadcast(Indication::UiError(unmatched.error));
+                }
+                continue;
+            }
+            let runs = self.unmatched_run_errors[n - 1].candidates.clone();
+            let matched: Vec<_> = self.unmatched_run_errors.drain(..n).collect();
+            for later in &mut self.unmatched_run_errors {
+                later.candidates.retain(|r_tag| !runs.contains(r_tag));
+            }
+            for (unmatched, r_tag) in matched.into_iter().zip(runs) {
+                let Some(index) = self.unanswered_runs.iter().position(|run| run.r_tag == r_tag) else {
+                    continue;
+                };
+                let run = self.unanswered_runs.remove(index).unwrap();
+                if let Some(dest) = self.action_response_map.remove(&run.r_tag) {
+                    dest.send(failed_run_result(unmatched.error.text.clone())).ok();
+                }
+                match run.origin {
+                    _ if unmatched.broadcast => {}
+                    Some(origin) => {
+                        origin.direct.send(Indication::UiError(unmatched.error)).ok();
+                    }
+                    None => self.broadcast(Indication::UiError(unmatched.error)),
+                }
+            }
+        }
+    }
+
+    /// Send file transfer progress to the client that started the transfer
+    fn route_file_transfer(&mut self, transfer: FileTransfer) {
+        let complete = matches!(transfer.state, FileTransferState::Complete { .. });
+        // Clients' actions are all run from the keymap; anything else was
+        // started by b3270 itself
+        let owner = match transfer.cause {
+            ActionCause::Keymap => self.transfer_owner.clone(),
+            _ => None,
+        };
+        if complete {
+            self.transfer_owner = None;
+        }
+        match owner {
+            Some(owner) => {
+                owner.direct.send(Indication::FileTransfer(transfer)).ok();
+            }
+            None => self.broadcast(Indication::FileTransfer(transfer)),
+        }
+    }
+
+    /// Hand a passthru action to the client that registered it, or fail it
+    /// if there's nobody to handle it
+    fn route_passthru(&mut self, passthru: Passthru) {
+        let owner = self.passthru_owners.get(&passthru.action.to_lowercase());
+        let p_tag = passthru.p_tag.clone();
+        let action = passthru.action.clone();
+        match owner {
+            Some(owner) if owner.direct.send(Indication::Passthru(passthru)).is_ok() => {
+                self.pending_passthru.insert(p_tag, owner.client);
+            }
+            _ => {
+                warn!(action, "Nobody is handling passthru action");
+                self.queue_operation(
+                    &Operation::Fail(Fail {
+                        p_tag,
+                        text: vec![format!("No client is handling {action}")],
+                    }),
+                    None,
+                );
+            }
+        }
+    }
+
+    /// Pass actions on to b3270. `originator` is told about any problems with
+    /// them, and `response_chan` gets the result.
+    fn run(
+        &mut self,
+        originator: Option<Originator>,
+        name: &str,
+        actions: Vec<Action>,
+        response_chan: oneshot::Sender<RunResult>,
+    ) {
+        // What's typed into a password field stays out of the recording
+        let recorded = if *self.input_hidden.borrow() {
+            redact_typing(&actions)
+        } else {
+            actions.clone()
+        };
+        self.record(|recorder| recorder.actions(name, &recorded));
+        let tag = 'find_tag: loop {
+            let tag = rand::thread_rng().next_u64().to_le_bytes();
+            let tag = B64_STANDARD.encode(tag);
+            if !self.action_response_map.contains_key(&tag) {
+                break 'find_tag tag;
+            }
+        };
+        if let Some(originator) = originator.as_ref() {
+            if actions
+                .iter()
+                .any(|action| action.action.eq_ignore_ascii_case("Transfer"))
+            {
+                self.transfer_owner = Some(originator.clone());
+            }
+        }
+        let op = Operation::Run(operation::Run {
+            r_tag: Some(tag.clone()),
+            type_: Some("keymap".to_owned()),
+            actions,
+        });
+        self.queue_operation(&op, originator);
+        self.action_response_map.insert(tag, response_chan);
+    }
+
+    /// Forget about passthru actions registered by clients that have gone
+    /// away, and fail whatever they were in the middle of
+    fn drop_departed_passthru_owners(&mut self, cx: &mut Context<'_>) {
+        let mut departed = vec![];
+        self.passthru_owners.retain(|_, owner| {
+            let gone = owner.gone.poll_unpin(cx).is_ready();
+            if gone {
+                info!(action = owner.register.name, "Passthru action owner disconnected");
+                departed.push(owner.client);
+            }
+            !gone
+        });
+        if departed.is_empty() {
+            return;
+        }
+        let mut orphaned = vec![];
+        self.pending_passthru.retain(|p_tag, client| {
+            let orphan = departed.contains(client);
+            if orphan {
+                orphaned.push(p_tag.clone());
+            }
+            !orphan
+        });
+        for p_tag in orphaned {
+            self.queue_operation(
+                &Operation::Fail(Fail {
+                    p_tag,
+                    text: vec!["The client handling this action disconnected".to_owned()],
+                }),
+                None,
+            );
+        }
     }
 }
 
@@ -243,10 +1286,30 @@ impl Future for B3270 {
     type Output = anyhow::Error;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // If b3270 is down, bring it back once it's been down long enough
+        if let Some(timer) = self.restart_timer.as_mut() {
+            if timer.poll_unpin(cx).is_ready() {
+                self.restart_timer = None;
+                if let Err(error) = self.restart_child() {
+                    let error = anyhow!(error).context("Failed to restart b3270");
+                    if let Some(error) = self.child_exited(error) {
+                        return Poll::Ready(error);
+                    }
+                    // Make sure that the new timer gets polled
+                    cx.waker().wake_by_ref();
+                }
+            }
+        }
+
         // try to read data from the child
         let mut indications = vec![];
+        let mut exited = None;
         // handle new indications first, so that new subscribers get the results in the sync state.
-        while let Poll::Ready(buf) = Pin::new(&mut self.child_reader).poll_next_line(cx) {
+        let stopped = matches!(self.shutdown, Shutdown::Stopped);
+        while self.restart_timer.is_none() && !stopped {
+            let Poll::Ready(buf) = Pin::new(&mut self.child_reader).poll_next_line(cx) else {
+                break;
+            };
             match buf {
                 Ok(Some(line)) => match serde_json::from_str(&line) {
                     Ok(ind) => {
@@ -258,50 +1321,91 @@ impl Future for B3270 {
                     }
                 },
                 // EOF on stdin; this is a big problem
-                Ok(None) => return Poll::Ready(anyhow!("Child exited unexpectedly")),
-                Err(err) => return Poll::Ready(anyhow!(err).context("Failed to read from child")),
+                Ok(None) => {
+                    exited = Some(anyhow!("Child exited unexpectedly"));
+                    break;
+                }
+                Err(err) => {
+                    exited = Some(anyhow!(err).context("Failed to read from child"));
+                    break;
+                }
             }
         }
 
         for mut ind in indications {
+            self.metrics.indication(&ind);
             match self.tracker.handle_indication(&mut ind) {
-                Disposition::Broadcast => {
-                    // It's OK to drop these, as anybody who cares will resync
-                    self.ind_chan.send(ind).ok();
-                }
+                Disposition::Broadcast => self.broadcast(ind),
                 Disposition::Drop => {
                     // do nothing
                 }
-                Disposition::Direct(dst) => {
-                    // TODO: handle this once we have a map of destinations.
-                    if let Indication::RunResult(run_res) = ind {
+                Disposition::Direct(dst) => match ind {
+                    Indication::RunResult(run_res) => {
+                        self.unanswered_runs
+                            .retain(|run| run.r_tag != dst);
                         if let Some(dest) = self.action_response_map.remove(&dst) {
                             // If this fails, whoever sent the request must not have cared.
                             dest.send(run_res).ok();
                         }
+                        // A run that's been answered wasn't one that went wrong
+                        for unmatched in &mut self.unmatched_run_errors {
+                            unmatched.candidates.retain(|r_tag| *r_tag != dst);
+                        }
+                        self.match_run_errors();
                     }
-                }
+                    Indication::Passthru(passthru) => self.route_passthru(passthru),
+                    _ => {}
+                },
+                Disposition::Originator => match ind {
+                    Indication::UiError(error) => self.route_ui_error(error),
+                    Indication::FileTransfer(transfer) => self.route_file_transfer(transfer),
+                    ind => self.broadcast(ind),
+                },
             }
         }
-        // check if the server has exited; if so, no sense looking at new connections
-        match self.child.try_wait() {
-            Ok(Some(status)) => {
-                info!(%status, "b3270 process exited");
-                return Poll::Ready(anyhow!("b3270 process exited"));
+        let hidden = cursor_in_hidden_field(&self.tracker);
+        self.input_hidden.send_if_modified(|old| std::mem::replace(old, hidden) != hidden);
+
+        // check if the server has exited; if so, it needs to be restarted
+        if exited.is_none() && self.restart_timer.is_none() && !stopped {
+            match self.child.try_wait() {
+                Ok(Some(status)) => {
+                    info!(%status, "b3270 process exited");
+                    exited = Some(anyhow!("b3270 process exited ({status})"));
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    warn!(%error, "Failed to check status of b3270");
+                    // TODO: should we end now?
+                }
             }
-            Ok(None) => {}
-            Err(error) => {
-                warn!(%error, "Failed to check status of b3270");
-                // TODO: should we end now?
+        }
+        if let Some(reason) = exited {
+            if let Some(error) = self.child_exited(reason) {
+                return Poll::Ready(error);
             }
+            // Make sure that the restart timer gets polled
+            cx.waker().wake_by_ref();
         }
 
         // Only now do we handle connection requests. This way new connections
         // cache the sync state in case we have multiple requests for it at once
         let mut sync_state = None;
         while let Poll::Ready(cmd) = self.comm.poll_recv(cx) {
+            match &cmd {
+                Some(B3270Request::Action(Originator { client, .. }, ..))
+                | Some(B3270Request::Complete(Originator { client, .. }, ..))
+                | Some(B3270Request::Floor(FloorRequest { client, .. }, _)) => self.touch(*client),
+                Some(B3270Request::Register(request, _)) => self.touch(request.originator.client),
+                _ => {}
+            }
             match cmd {
                 None => {}
+                Some(B3270Request::Join(entry, presence, kick)) => {
+                    self.join(entry, presence, kick);
+                    // The roster is part of the sync state
+                    sync_state = None;
+                }
                 Some(B3270Request::Resync(sender)) => {
                     if sync_state.is_none() {
                         sync_state = Some(self.tracker.get_init_indication());
@@ -311,52 +1415,117 @@ impl Future for B3270 {
                         .send((sync_state.clone().unwrap(), self.ind_chan.subscribe()))
                         .ok();
                 }
-                Some(B3270Request::Action(actions, response_chan)) => {
-                    let tag = 'find_tag: loop {
-                        let tag = rand::thread_rng().next_u64().to_le_bytes();
-                        let tag = B64_STANDARD.encode(tag);
-                        if !self.action_response_map.contains_key(&tag) {
-                            break 'find_tag tag;
-                        }
-                    };
-                    let op = Operation::Run(operation::Run {
-                        r_tag: Some(tag.clone()),
-                        type_: Some("keymap".to_owned()),
-                        actions,
-                    });
-                    match serde_json::to_string(&op) {
-                        Ok(op_str) => {
-                            trace!(json = op_str, "Sending operation");
-                            self.write_buf.extend(op_str.bytes());
-                            self.write_buf.push_back(b'\n');
-                            self.action_response_map.insert(tag, response_chan);
-                        }
-                        Err(error) => error!(?op, %error, "Failed to serialize op"),
-                    }
+                Some(B3270Request::Floor(request, reply)) => {
+                    reply.send(self.handle_floor_request(request)).ok();
+                }
+                Some(B3270Request::Register(request, reply)) => {
+                    reply.send(self.handle_register_request(request)).ok();
+                }
+                Some(B3270Request::Complete(originator, completion, reply)) => {
+                    reply.send(self.handle_completion(originator, completion)).ok();
+                }
+                Some(B3270Request::Replay(_, reply)) => {
+                    reply.send(Err("This session isn't a replay".to_owned())).ok();
+                }
+                Some(B3270Request::Kick(client, reply)) => {
+                    reply.send(self.kick(client)).ok();
                 }
+                Some(B3270Request::Popup(text)) => {
+                    info!(text, "Showing popup");
+                    self.broadcast(Indication::Popup(Popup {
+                        type_: PopupType::Info,
+                        text,
+                        error: None,
+                    }));
+                }
+                Some(B3270Request::Rotate(reply)) => {
+                    reply.send(self.rotate_recording()).ok();
+                }
+                Some(B3270Request::Stop(reason, stopped)) => self.stop(reason, stopped),
+                Some(B3270Request::WaitFor(wait, client)) => {
+                    let this = &mut *self;
+                    this.waits.add(wait, client, &this.tracker);
+                }
+                Some(B3270Request::Action(originator, _, _, response_chan))
+                    if self
+                        .floor
+                        .as_ref()
+                        .is_some_and(|holder| holder.client != originator.client) =>
+                {
+                    let holder = &self.floor.as_ref().unwrap().name;
+                    response_chan
+                        .send(failed_run_result(format!("The keyboard is held by {holder}")))
+                        .ok();
+                }
+                Some(
+                    B3270Request::Action(_, _, _, response_chan)
+                    | B3270Request::AdminAction(_, response_chan),
+                ) if self.restart_timer.is_some() => {
+                    response_chan
+                        .send(failed_run_result("b3270 is restarting".to_owned()))
+                        .ok();
+                }
+                Some(B3270Request::Action(originator, name, actions, response_chan)) => {
+                    self.run(Some(originator), &name, actions, response_chan);
+                }
+                Some(B3270Request::AdminAction(actions, response_chan)) => {
+                    info!(?actions, "Running actions for an administrator");
+                    self.run(None, ADMIN_NAME, actions, response_chan);
+                }
+            }
+        }
+
+        // Both the screen and the waits may have changed
+        let this = &mut *self;
+        this.waits.poll(&this.tracker, cx);
+
+        if self.recorder.as_ref().is_some_and(Recorder::needs_rotation) {
+            let snapshot = self.tracker.get_init_indication();
+            self.record(|recorder| recorder.rotate(snapshot));
+        }
+        self.record(Recorder::flush);
+
+        // Give up the floor if its holder has disconnected. This comes after
+        // handling requests so that a new holder is watched from the start.
+        if let Some(holder) = self.floor.as_mut() {
+            if holder.gone.poll_unpin(cx).is_ready() {
+                info!(holder = holder.name, "Floor holder disconnected");
+                self.set_floor(None);
             }
         }
 
+        self.drop_departed_passthru_owners(cx);
+        self.drop_departed_clients(cx);
+        self.continue_shutdown();
+
+        self.metrics.set_pending_actions(self.action_response_map.len());
+
         // Now, check if there's anything to be written
         'write: while !self.write_buf.is_empty() {
             let myself = &mut *self;
             let chunk = myself.write_buf.chunk();
-            let stdin = Pin::new(
-                myself
-                    .child
-                    .stdin
-                    .as_mut()
-                    .expect("Should always have child stdin"),
-            );
+            // Only missing once b3270 is being shut down
+            let Some(stdin) = myself.child.stdin.as_mut() else {
+                break 'write;
+            };
+            let stdin = Pin::new(stdin);
             match stdin.poll_write(cx, chunk) {
                 Poll::Pending | Poll::Ready(Ok(0)) => {
                     break 'write;
                 }
                 Poll::Ready(Ok(n)) => {
-                    myself.write_buf.advance(n);
+                    myself.wrote(n);
                 }
                 Poll::Ready(Err(error)) => {
-                    warn!(%error, "Failed to write to b3270");
+                    // Nothing more can be said to this b3270, so treat it the
+                    // same as failing to read from it
+                    myself.child.stdin = None;
+                    if let Some(error) = myself.child_exited(anyhow!(error).context("Failed to write to b3270")) {
+                        return Poll::Ready(error);
+                    }
+                    // Make sure that the restart timer gets polled
+                    cx.waker().wake_by_ref();
+                    break 'write;
                 }
             }
         }
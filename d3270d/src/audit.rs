@@ -0,0 +1,249 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! An append-only record of who ran which actions
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use tracing::error;
+
+use d3270_common::b3270::indication::RunResult;
+use d3270_common::b3270::operation::Action;
+
+use crate::auth::Identity;
+
+/// Actions whose arguments are what the user typed
+const TYPING_ACTIONS: &[&str] = &["String", "Key"];
+
+const REDACTED: &str = "<redacted>";
+
+/// Logged for actions whose result never arrived, e.g. because the client left
+const ABANDONED: &str = "No result arrived; the actions may or may not have run";
+
+/// The audit log file. Each line is a JSON object describing one run of actions.
+pub struct AuditLog {
+    file: Mutex<File>,
+    redact_hidden_input: bool,
+}
+
+/// Where a client connected from
+#[derive(Clone, Debug)]
+pub struct Origin {
+    /// `tcp`, `unix`, `ws`, `http`, `admin`, or `shutdown`
+    pub listener: &'static str,
+    /// The peer's address, or for unix sockets, its pid (its uid on the admin socket)
+    pub address: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    user: &'a str,
+    listener: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<&'a str>,
+    session: &'a str,
+    actions: &'a [Action],
+    success: bool,
+    /// Only given for failures, as successful actions may return screen contents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a [String]>,
+    /// Set if nobody was waiting for the result any more
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    abandoned: bool,
+}
+
+impl AuditLog {
+    /// If `redact_hidden_input` is set, what is typed while the cursor is in a
+    /// non-display field (such as a password) is left out.
+    pub fn open(path: &Path, redact_hidden_input: bool) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            redact_hidden_input,
+        })
+    }
+
+    fn write(&self, record: &Record) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(error) => {
+                error!(%error, "Failed to serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+        // A single write keeps lines whole even if something else appends to the file
+        if let Err(error) = self.file.lock().unwrap().write_all(&line) {
+            error!(%error, "Failed to write audit log");
+        }
+    }
+}
+
+/// Hide the arguments of actions that type text, for when it's going into a
+/// non-display field
+pub fn redact_typing(actions: &[Action]) -> Vec<Action> {
+    actions
+        .iter()
+        .map(|action| match action {
+            Action { action: name, args }
+                if TYPING_ACTIONS.iter().any(|typing| typing.eq_ignore_ascii_case(name)) =>
+            {
+                Action {
+                    action: name.clone(),
+                    args: args.iter().map(|_| REDACTED.to_owned()).collect(),
+                }
+            }
+            action => action.clone(),
+        })
+        .collect()
+}
+
+/// Everything the audit log needs to know about a client
+#[derive(Clone)]
+pub struct AuditTrail {
+    log: Option<Arc<AuditLog>>,
+    user: String,
+    origin: Origin,
+    session: String,
+}
+
+/// Actions that have been submitted, waiting for their result to be logged.
+/// If it is dropped without [`PendingAudit::finish`], the actions are logged
+/// as abandoned.
+pub struct PendingAudit {
+    trail: AuditTrail,
+    time: String,
+    actions: Vec<Action>,
+    logged: bool,
+}
+
+impl AuditTrail {
+    pub fn new(log: Option<Arc<AuditLog>>, identity: &Identity, origin: Origin, session: &str) -> Self {
+        AuditTrail {
+            log,
+            user: identity.name.clone(),
+            origin,
+            session: session.to_owned(),
+        }
+    }
+
+    /// Note that `actions` are about to be run. `input_hidden` says whether the
+    /// cursor is in a non-display field. Returns `None` if there's no audit log.
+    pub fn begin(&self, actions: &[Action], input_hidden: bool) -> Option<PendingAudit> {
+        let log = self.log.as_ref()?;
+        let actions = if input_hidden && log.redact_hidden_input {
+            redact_typing(actions)
+        } else {
+            actions.to_vec()
+        };
+        Some(PendingAudit {
+            trail: self.clone(),
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            actions,
+            logged: false,
+        })
+    }
+}
+
+impl PendingAudit {
+    pub fn finish(mut self, result: &RunResult) {
+        self.log(result.success, (!result.success).then_some(result.text.as_slice()), false);
+    }
+
+    fn log(&mut self, success: bool, text: Option<&[String]>, abandoned: bool) {
+        self.logged = true;
+        let trail = &self.trail;
+        let Some(log) = trail.log.as_ref() else {
+            return;
+        };
+        log.write(&Record {
+            time: self.time.clone(),
+            user: &trail.user,
+            listener: trail.origin.listener,
+            peer: trail.origin.address.as_deref(),
+            session: &trail.session,
+            actions: &self.actions,
+            success,
+            text,
+            abandoned,
+        });
+    }
+}
+
+impl Drop for PendingAudit {
+    fn drop(&mut self) {
+        if !self.logged {
+            self.log(false, Some(&[ABANDONED.to_owned()]), true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacts_typing_in_hidden_fields() {
+        let path = std::env::temp_dir().join(format!("d3270d-audit-test-{}", std::process::id()));
+        let log = Arc::new(AuditLog::open(&path, true).unwrap());
+        let trail = AuditTrail::new(
+            Some(log),
+            &Identity { name: "alice".to_owned() },
+            Origin { listener: "tcp", address: None },
+            "default",
+        );
+        let actions = [
+            Action { action: "string".to_owned(), args: vec!["hunter2".to_owned()] },
+            Action { action: "Enter".to_owned(), args: vec![] },
+        ];
+        let visible = trail.begin(&actions, false).unwrap();
+        assert_eq!(visible.actions, actions);
+        let hidden = trail.begin(&actions, true).unwrap();
+        assert_eq!(hidden.actions[0].args, vec![REDACTED.to_owned()]);
+        assert_eq!(hidden.actions[1], actions[1]);
+
+        hidden.finish(&RunResult {
+            r_tag: None,
+            success: true,
+            text: vec![],
+            abort: None,
+            time: 0.0,
+        });
+        drop(visible);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(!lines[0].contains("hunter2"));
+        assert!(lines[0].contains(r#""user":"alice""#));
+        assert!(!lines[0].contains("abandoned"));
+        assert!(lines[1].contains("hunter2"));
+        assert!(lines[1].contains(r#""abandoned":true"#));
+    }
+}
@@ -0,0 +1,154 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, bail, Context};
+
+/// Identity used for connections when authentication is disabled
+const ANONYMOUS: &str = "anonymous";
+/// Identity used for tokens in the token file that don't name a user
+const SHARED: &str = "shared";
+
+/// Who a client authenticated as
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// Checks client tokens against the contents of a token file.
+///
+/// The token file has one token per line, either as `user:token` or as a
+/// bare shared secret. Blank lines and lines starting with `#` are ignored.
+/// The tokens can be replaced while clients are using them, with [`Self::reload`].
+#[derive(Default)]
+pub struct Authenticator {
+    // (token, identity). None if authentication is disabled.
+    tokens: RwLock<Option<Vec<(String, Identity)>>>,
+}
+
+impl Authenticator {
+    /// An authenticator that lets everybody in
+    pub fn open() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read token file {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid token file {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut tokens = vec![];
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, token) = line.split_once(':').unwrap_or((SHARED, line));
+            if name.is_empty() || token.is_empty() {
+                bail!("Line {}: expected user:token", lineno + 1);
+            }
+            tokens.push((
+                token.to_owned(),
+                Identity {
+                    name: name.to_owned(),
+                },
+            ));
+        }
+        if tokens.is_empty() {
+            bail!("No tokens given");
+        }
+        Ok(Self {
+            tokens: RwLock::new(Some(tokens)),
+        })
+    }
+
+    /// Switch to the tokens that `other` accepts. Clients that are already
+    /// attached stay attached.
+    pub fn reload(&self, other: Authenticator) {
+        *self.tokens.write().unwrap() = other.tokens.into_inner().unwrap();
+    }
+
+    /// Whether clients need to present a token at all
+    pub fn required(&self) -> bool {
+        self.tokens.read().unwrap().is_some()
+    }
+
+    pub fn authenticate(&self, token: Option<&str>) -> anyhow::Result<Identity> {
+        let tokens = self.tokens.read().unwrap();
+        let Some(tokens) = tokens.as_ref() else {
+            return Ok(Identity {
+                name: ANONYMOUS.to_owned(),
+            });
+        };
+        let token = token.ok_or_else(|| anyhow!("Authentication required"))?;
+        // Check every token so that the time taken doesn't reveal which one matched
+        let mut found = None;
+        for (candidate, identity) in tokens {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) && found.is_none() {
+                found = Some(identity);
+            }
+        }
+        found
+            .cloned()
+            .ok_or_else(|| anyhow!("Authentication failed"))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_token_file() {
+        let auth = Authenticator::parse("# comment\n\nalice:s3cret\nhunter2\n").unwrap();
+        assert!(auth.required());
+        assert_eq!(auth.authenticate(Some("s3cret")).unwrap().name, "alice");
+        assert_eq!(auth.authenticate(Some("hunter2")).unwrap().name, SHARED);
+        assert!(auth.authenticate(Some("s3cre")).is_err());
+        assert!(auth.authenticate(None).is_err());
+
+        auth.reload(Authenticator::parse("bob:t0ken\n").unwrap());
+        assert!(auth.authenticate(Some("s3cret")).is_err());
+        assert_eq!(auth.authenticate(Some("t0ken")).unwrap().name, "bob");
+    }
+
+    #[test]
+    fn open_allows_anybody() {
+        let auth = Authenticator::open();
+        assert!(!auth.required());
+        assert_eq!(auth.authenticate(None).unwrap().name, ANONYMOUS);
+    }
+}
@@ -0,0 +1,285 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! A stand-in for b3270, for testing d3270d without a host.
+//!
+//! It speaks b3270's JSON protocol on stdin and stdout. At startup it sends
+//! the indications in its fixture (`-fixture file`, one per line; a plain
+//! 24x80 terminal otherwise), and it answers every `run` with a
+//! `run-result` carrying the same `r-tag`. A few actions are scripted:
+//!
+//! - `Connect(host)` and `Disconnect()` change the connection state
+//! - `String(text)` writes the text at the cursor, and moves it along
+//! - `Fail(text...)` fails, with the given text
+//! - `Flood(n)` sends `n` screen updates before answering
+//! - `Crash([status])` exits at once, without answering (status 1 by default)
+//! - `Deafen()` closes its input, answers, and then hangs without exiting
+//! - `Hang()` answers, and then stops reading its input, so that nothing
+//!   else is answered and closing the input doesn't make it exit
+//! - `Pause()` holds back the run's result until a later run does `Resume()`
+//! - `Reject()` makes the run one that b3270 can't make sense of: it sends a
+//!   `ui-error` about the run, and never answers it
+//!
+//! Actions registered with `register` are passed through: a `passthru`
+//! indication is sent in their place, and the run is answered once it is
+//! completed with `succeed` or `fail`. Every other action succeeds without
+//! doing anything. It exits when its input is closed, as b3270 does.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufWriter, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::process::exit;
+
+use anyhow::{anyhow, Context};
+
+use d3270_common::b3270::indication::{
+    Change, Connection, ConnectionState, CountOrText, Cursor, Passthru, Row, RunResult, Screen, UiError,
+};
+use d3270_common::b3270::operation::{Action, Fail, Run, Succeed};
+use d3270_common::b3270::{Indication, Operation};
+
+const DEFAULT_FIXTURE: &str = r#"
+{"initialize":[{"hello":{"version":"4.3ga0","build":"fake-b3270","copyright":"none"}},{"models":[{"model":2,"rows":24,"columns":80}]},{"screen-mode":{"model":2,"rows":24,"columns":80,"color":true,"oversize":false,"extended":true}},{"erase":{"logical-rows":24,"logical-cols":80}},{"connection":{"state":"not-connected"}}]}
+{"screen":{"cursor":{"enabled":true,"row":1,"column":1}}}
+"#;
+
+struct Terminal<W> {
+    out: W,
+    row: u8,
+    column: u8,
+    // Whether the input has been closed by Deafen()
+    deaf: bool,
+    // Whether Hang() has been run
+    hung: bool,
+    // Results held back by Pause()
+    paused: Vec<RunResult>,
+    // Registered passthru actions, lowercased
+    registered: HashSet<String>,
+    // The r-tag of the run waiting on each passthru action, by p-tag
+    passthru: HashMap<String, Option<String>>,
+    next_p_tag: u64,
+}
+
+impl<W: Write> Terminal<W> {
+    fn send(&mut self, indication: &Indication) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.out, indication)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn connection(&mut self, state: ConnectionState, host: Option<String>) -> anyhow::Result<()> {
+        self.send(&Indication::Connection(Connection {
+            state,
+            host,
+            cause: None,
+        }))
+    }
+
+    fn write_text(&mut self, text: &str) -> anyhow::Result<()> {
+        let row = Row {
+            row: self.row,
+            changes: vec![Change {
+                column: self.column,
+                change: CountOrText::Text(text.to_owned()),
+                fg: None,
+                bg: None,
+                gr: None,
+            }],
+        };
+        self.column = self.column.saturating_add(text.chars().count() as u8);
+        self.send(&Indication::Screen(Screen {
+            cursor: Some(Cursor {
+                enabled: true,
+                row: Some(self.row),
+                column: Some(self.column),
+            }),
+            rows: vec![row],
+        }))
+    }
+
+    /// Do one action, and say whether it succeeded (and if not, why)
+    fn action(&mut self, action: &Action) -> anyhow::Result<Result<(), Vec<String>>> {
+        let arg = |n: usize| action.args.get(n).map(String::as_str);
+        match action.action.as_str() {
+            "Connect" => {
+                let host = arg(0).ok_or_else(|| anyhow!("Connect needs a host"))?;
+                self.connection(ConnectionState::Connected3270, Some(host.to_owned()))?;
+            }
+            "Disconnect" => self.connection(ConnectionState::NotConnected, None)?,
+            "String" => {
+                for text in &action.args {
+                    self.write_text(text)?;
+                }
+            }
+            "Fail" => return Ok(Err(action.args.clone())),
+            "Flood" => {
+                let count: usize = arg(0).unwrap_or("1000").parse().context("Flood count")?;
+                for n in 0..count {
+                    self.row = 2 + (n % 20) as u8;
+                    self.column = 1;
+                    self.write_text(&format!("flood {n}"))?;
+                }
+            }
+            "Crash" => {
+                self.out.flush()?;
+                exit(arg(0).and_then(|status| status.parse().ok()).unwrap_or(1));
+            }
+            "Deafen" => {
+                // SAFETY: nothing else closes stdin, and it isn't read again
+                drop(unsafe { OwnedFd::from_raw_fd(0) });
+                self.deaf = true;
+            }
+            "Hang" => self.hung = true,
+            "Resume" => {
+                for result in std::mem::take(&mut self.paused) {
+                    self.send(&Indication::RunResult(result))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(Ok(()))
+    }
+
+    fn run(&mut self, run: Run) -> anyhow::Result<()> {
+        if run.actions.iter().any(|action| action.action == "Reject") {
+            return self.send(&Indication::UiError(UiError {
+                fatal: false,
+                text: "Rejected run".to_owned(),
+                operation: Some("run".to_owned()),
+                member: None,
+                line: None,
+                column: None,
+            }));
+        }
+        let mut result = Ok(());
+        for action in &run.actions {
+            if self.registered.contains(&action.action.to_lowercase()) {
+                // The rest of the run is left undone
+                self.next_p_tag += 1;
+                let p_tag = format!("passthru-{}", self.next_p_tag);
+                self.passthru.insert(p_tag.clone(), run.r_tag.clone());
+                return self.send(&Indication::Passthru(Passthru {
+                    p_tag,
+                    parent_r_tag: run.r_tag,
+                    action: action.action.clone(),
+                    args: action.args.clone(),
+                }));
+            }
+            result = self.action(action)?;
+            if result.is_err() {
+                break;
+            }
+        }
+        let text = result.as_ref().err().cloned().unwrap_or_default();
+        let result = RunResult {
+            r_tag: run.r_tag,
+            success: result.is_ok(),
+            text,
+            abort: None,
+            time: 0.0,
+        };
+        if run.actions.iter().any(|action| action.action == "Pause") {
+            self.paused.push(result);
+            return Ok(());
+        }
+        self.send(&Indication::RunResult(result))
+    }
+
+    /// Answer the run that a passthru action was part of
+    fn complete(&mut self, p_tag: &str, success: bool, text: Vec<String>) -> anyhow::Result<()> {
+        let Some(r_tag) = self.passthru.remove(p_tag) else {
+            return self.send(&Indication::UiError(UiError {
+                fatal: false,
+                text: format!("No such passthru action {p_tag}"),
+                operation: Some(if success { "succeed" } else { "fail" }.to_owned()),
+                member: None,
+                line: None,
+                column: None,
+            }));
+        };
+        self.send(&Indication::RunResult(RunResult {
+            r_tag,
+            success,
+            text,
+            abort: None,
+            time: 0.0,
+        }))
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut fixture = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        // -json, -utf8, and whatever else b3270 would take are ignored
+        if arg == "-fixture" {
+            fixture = Some(args.next().ok_or_else(|| anyhow!("-fixture needs a file"))?);
+        }
+    }
+    let fixture = match fixture {
+        Some(path) => std::fs::read_to_string(&path).with_context(|| format!("Failed to read {path}"))?,
+        None => DEFAULT_FIXTURE.to_owned(),
+    };
+
+    let mut terminal = Terminal {
+        out: BufWriter::new(std::io::stdout().lock()),
+        row: 1,
+        column: 1,
+        deaf: false,
+        hung: false,
+        paused: vec![],
+        registered: HashSet::new(),
+        passthru: HashMap::new(),
+        next_p_tag: 0,
+    };
+    for line in fixture.lines().filter(|line| !line.trim().is_empty()) {
+        let indication: Indication = serde_json::from_str(line).with_context(|| format!("Bad fixture line {line}"))?;
+        terminal.send(&indication)?;
+    }
+    terminal.out.flush()?;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        match serde_json::from_str::<Operation>(&line) {
+            Ok(Operation::Run(run)) => terminal.run(run)?,
+            Ok(Operation::Register(register)) => {
+                terminal.registered.insert(register.name.to_lowercase());
+            }
+            Ok(Operation::Succeed(Succeed { p_tag, text })) => terminal.complete(&p_tag, true, text)?,
+            Ok(Operation::Fail(Fail { p_tag, text })) => terminal.complete(&p_tag, false, text)?,
+            Ok(_) => {}
+            Err(error) => terminal.send(&Indication::UiError(UiError {
+                fatal: false,
+                text: error.to_string(),
+                operation: None,
+                member: None,
+                line: None,
+                column: None,
+            }))?,
+        }
+        terminal.out.flush()?;
+        // After Deafen(), whatever d3270d writes fails, but it isn't told so by
+        // stdout closing. After Hang(), what it writes goes unread.
+        if terminal.deaf || terminal.hung {
+            loop {
+                std::thread::park();
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,744 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::arbiter::RestartPolicy;
+use crate::audit::AuditLog;
+use crate::auth::Authenticator;
+use crate::policy::{ActionPolicy, ActionRule};
+use crate::recording::RecordOptions;
+use crate::replay::ReplayOptions;
+use crate::session::{self, ShutdownOptions, DEFAULT_SESSION};
+use crate::tls;
+use crate::unix_server::{self, PeerPolicy, UnixListenOptions};
+
+/// The contents of a d3270d configuration file. Every part of it is
+/// optional, and command line flags override or add to it.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Host for the default session to connect to
+    pub connect: Option<String>,
+    /// Additional sessions, as name = connect string
+    pub sessions: BTreeMap<String, String>,
+    pub b3270: B3270Config,
+    pub listen: ListenConfig,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub policy: PolicyConfig,
+    pub restart: RestartConfig,
+    pub log: LogConfig,
+    pub audit: AuditConfig,
+    pub record: RecordConfig,
+    pub replay: ReplayConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct B3270Config {
+    /// The b3270 binary; looked up in $PATH if it has no directory
+    pub path: PathBuf,
+    /// Extra arguments for b3270. `-json` and `-utf8` are always passed.
+    pub args: Vec<String>,
+}
+
+impl Default for B3270Config {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("b3270"),
+            args: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListenConfig {
+    /// host:port for the TCP listener
+    pub tcp: Option<String>,
+    /// host:port for the HTTP/websocket listener
+    pub http: Option<String>,
+    pub unix: Option<UnixConfig>,
+    /// The admin socket that d3270ctl uses. Its mode defaults to 0600.
+    pub admin: Option<UnixConfig>,
+    /// [host:]port for the s3270-compatible script port
+    pub script: Option<String>,
+    /// The socket for HLLAPI-style programs
+    pub hllapi: Option<UnixConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UnixConfig {
+    pub path: PathBuf,
+    /// Octal file mode for the socket, e.g. "0660"
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// User (name or uid) to own the socket
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Group (name or gid) to own the socket
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Users that may connect. If neither this nor allow-groups is given,
+    /// anybody who can open the socket may connect.
+    #[serde(default)]
+    pub allow_users: Vec<String>,
+    /// Groups whose members may connect
+    #[serde(default)]
+    pub allow_groups: Vec<String>,
+}
+
+impl UnixConfig {
+    fn resolve(self) -> anyhow::Result<UnixListenOptions> {
+        if self.path.as_os_str().is_empty() {
+            bail!("path is required");
+        }
+        let mode = self
+            .mode
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| anyhow!("mode: {mode:?} is not an octal file mode"))
+            })
+            .transpose()?;
+        let owner = self
+            .owner
+            .map(|owner| unix_server::lookup_user(&owner).context("owner"))
+            .transpose()?;
+        let group = self
+            .group
+            .map(|group| unix_server::lookup_gid(&group).context("group"))
+            .transpose()?;
+        let mut peers = PeerPolicy::default();
+        for user in self.allow_users {
+            peers.allow_user(&user).context("allow-users")?;
+        }
+        for group in self.allow_groups {
+            peers.allow_group(&group).context("allow-groups")?;
+        }
+        Ok(UnixListenOptions {
+            path: self.path,
+            mode,
+            owner: owner.map(|uid| uid.as_raw()),
+            group: group.map(|gid| gid.as_raw()),
+            peers,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuthConfig {
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PolicyConfig {
+    pub allow: Option<Vec<ActionRule>>,
+    pub deny: Vec<ActionRule>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RestartConfig {
+    pub limit: Option<u32>,
+    /// Seconds
+    pub backoff: Option<f64>,
+    /// Seconds
+    pub max_backoff: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    /// A tracing filter, as in RUST_LOG. RUST_LOG wins if it is set.
+    pub filter: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuditConfig {
+    /// JSON lines file that every run of actions is appended to
+    pub file: Option<PathBuf>,
+    /// Leave out what is typed into non-display fields, such as passwords
+    pub redact_hidden_input: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RecordConfig {
+    /// Record every session into files in this directory
+    pub dir: Option<PathBuf>,
+    /// Bytes
+    pub max_size: Option<u64>,
+    /// Seconds
+    pub max_age: Option<f64>,
+    /// Record the actions clients run too
+    pub actions: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReplayConfig {
+    /// Play back this recording instead of running b3270
+    pub file: Option<PathBuf>,
+    /// How many times faster than real time to play
+    pub speed: Option<f64>,
+    /// Wait to be stepped through, rather than playing
+    pub step: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ShutdownConfig {
+    /// Disconnect from the host before stopping b3270
+    pub disconnect: bool,
+    /// Seconds
+    pub timeout: Option<f64>,
+}
+
+/// Everything d3270d needs to start, checked and loaded
+pub struct Settings {
+    pub b3270_program: PathBuf,
+    pub b3270_args: Vec<OsString>,
+    /// The default session (if any) comes first
+    pub sessions: Vec<(String, String)>,
+    pub tcp_listen: Option<SocketAddr>,
+    pub http_listen: Option<SocketAddr>,
+    pub unix_listen: Option<UnixListenOptions>,
+    pub admin_listen: Option<UnixListenOptions>,
+    pub script_listen: Option<SocketAddr>,
+    pub hllapi_listen: Option<UnixListenOptions>,
+    pub auth: Authenticator,
+    pub policy: ActionPolicy,
+    pub tls: Option<ServerConfig>,
+    pub restart: RestartPolicy,
+    pub audit: Option<AuditLog>,
+    pub record: Option<RecordOptions>,
+    /// Serve this recording as the default session, rather than any real ones
+    pub replay: Option<ReplayOptions>,
+    pub shutdown: ShutdownOptions,
+}
+
+/// What the command line asked for
+pub struct Options {
+    pub config: Config,
+    /// Validate the configuration and exit
+    pub check_config: bool,
+    /// Things worth telling the user about, once logging is set up
+    pub warnings: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        // Paths in the file are relative to the file
+        let base = path.parent().unwrap_or(Path::new(""));
+        if let Some(ref mut token_file) = config.auth.token_file {
+            *token_file = base.join(&*token_file);
+        }
+        if let Some(ref mut tls) = config.tls {
+            tls.cert = base.join(&tls.cert);
+            tls.key = base.join(&tls.key);
+        }
+        if let Some(ref mut audit_file) = config.audit.file {
+            *audit_file = base.join(&*audit_file);
+        }
+        if let Some(ref mut record_dir) = config.record.dir {
+            *record_dir = base.join(&*record_dir);
+        }
+        if let Some(ref mut replay_file) = config.replay.file {
+            *replay_file = base.join(&*replay_file);
+        }
+        if let Some(ref mut unix) = config.listen.unix {
+            unix.path = base.join(&unix.path);
+        }
+        if let Some(ref mut admin) = config.listen.admin {
+            admin.path = base.join(&admin.path);
+        }
+        if let Some(ref mut hllapi) = config.listen.hllapi {
+            hllapi.path = base.join(&hllapi.path);
+        }
+        if config.b3270.path.components().count() > 1 {
+            config.b3270.path = base.join(&config.b3270.path);
+        }
+        Ok(config)
+    }
+
+    /// Check everything that can be checked without starting anything
+    pub async fn resolve(self) -> anyhow::Result<Settings> {
+        let mut sessions = vec![];
+        if let Some(connect) = self.connect {
+            sessions.push((DEFAULT_SESSION.to_owned(), connect));
+        }
+        for (name, connect) in self.sessions {
+            if name == DEFAULT_SESSION && !sessions.is_empty() {
+                bail!("sessions.{name}: conflicts with connect");
+            }
+            session::validate_name(&name).with_context(|| format!("sessions.{name}"))?;
+            sessions.push((name, connect));
+        }
+
+        let mut b3270_args = vec![OsString::from("-json"), OsString::from("-utf8")];
+        b3270_args.extend(self.b3270.args.into_iter().map(OsString::from));
+
+        let auth = match self.auth.token_file {
+            Some(path) => Authenticator::load(&path).context("auth.token-file")?,
+            None => Authenticator::open(),
+        };
+
+        let mut policy = ActionPolicy::default();
+        for rule in self.policy.allow.into_iter().flatten() {
+            policy.allow(rule);
+        }
+        for rule in self.policy.deny {
+            policy.deny(rule);
+        }
+
+        let tls = self
+            .tls
+            .map(|tls| tls::load_server_config(&tls.cert, &tls.key))
+            .transpose()
+            .context("tls")?;
+
+        let mut restart = RestartPolicy::default();
+        if let Some(limit) = self.restart.limit {
+            restart.max_restarts = limit;
+        }
+        if let Some(backoff) = self.restart.backoff {
+            restart.initial_backoff = seconds(backoff).context("restart.backoff")?;
+        }
+        if let Some(max_backoff) = self.restart.max_backoff {
+            restart.max_backoff = seconds(max_backoff).context("restart.max-backoff")?;
+        }
+
+        let audit = self
+            .audit
+            .file
+            .map(|path| AuditLog::open(&path, self.audit.redact_hidden_input))
+            .transpose()
+            .context("audit.file")?;
+
+        let record = match self.record.dir {
+            Some(dir) => Some(RecordOptions {
+                dir,
+                max_size: self.record.max_size,
+                max_age: self
+                    .record
+                    .max_age
+                    .map(seconds)
+                    .transpose()
+                    .context("record.max-age")?,
+                actions: self.record.actions,
+            }),
+            None => None,
+        };
+
+        let replay = match self.replay.file {
+            Some(_) if !sessions.is_empty() => {
+                bail!("replay.file: a replay can't be combined with connect or sessions")
+            }
+            Some(file) => {
+                let speed = self.replay.speed.unwrap_or(1.0);
+                if !(speed.is_finite() && speed > 0.0) {
+                    bail!("replay.speed: {speed} is not a positive number");
+                }
+                Some(ReplayOptions {
+                    file,
+                    speed,
+                    step: self.replay.step,
+                })
+            }
+            None => None,
+        };
+
+        let mut shutdown = ShutdownOptions {
+            disconnect: self.shutdown.disconnect,
+            ..Default::default()
+        };
+        if let Some(timeout) = self.shutdown.timeout {
+            shutdown.timeout = seconds(timeout).context("shutdown.timeout")?;
+        }
+
+        let tcp_listen = match self.listen.tcp {
+            Some(addr) => Some(resolve_addr(&addr).await.context("listen.tcp")?),
+            None => None,
+        };
+        let http_listen = match self.listen.http {
+            Some(addr) => Some(resolve_listen_addr(&addr).await.context("listen.http")?),
+            None => None,
+        };
+        let unix_listen = self
+            .listen
+            .unix
+            .map(UnixConfig::resolve)
+            .transpose()
+            .context("listen.unix")?;
+        let admin_listen = self
+            .listen
+            .admin
+            .map(UnixConfig::resolve)
+            .transpose()
+            .context("listen.admin")?;
+        let script_listen = match self.listen.script {
+            Some(addr) => Some(resolve_listen_addr(&addr).await.context("listen.script")?),
+            None => None,
+        };
+        let hllapi_listen = self
+            .listen
+            .hllapi
+            .map(UnixConfig::resolve)
+            .transpose()
+            .context("listen.hllapi")?;
+        if tcp_listen.is_none()
+            && http_listen.is_none()
+            && unix_listen.is_none()
+            && script_listen.is_none()
+            && hllapi_listen.is_none()
+        {
+            bail!("No listeners given; set listen.tcp, listen.http, listen.unix, listen.script or listen.hllapi, or use -tcp-listen, -http-listen, -unix-listen, -scriptport or -hllapi-socket");
+        }
+
+        Ok(Settings {
+            b3270_program: self.b3270.path,
+            b3270_args,
+            sessions,
+            tcp_listen,
+            http_listen,
+            unix_listen,
+            admin_listen,
+            script_listen,
+            hllapi_listen,
+            auth,
+            policy,
+            tls,
+            restart,
+            audit,
+            record,
+            replay,
+            shutdown,
+        })
+    }
+}
+
+fn seconds(value: f64) -> anyhow::Result<Duration> {
+    Duration::try_from_secs_f64(value).map_err(|_| anyhow!("{value} is not a valid number of seconds"))
+}
+
+async fn resolve_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("Failed to resolve {addr:?}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{addr:?} has no addresses"))
+}
+
+/// Like [`resolve_addr`], but a bare port is on localhost, as with x3270's
+/// `-scriptport` and `-httpd`
+async fn resolve_listen_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    match addr.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => resolve_addr(addr).await,
+    }
+}
+
+fn next_string(args: &mut impl Iterator<Item = OsString>, flag: &str) -> anyhow::Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("Arg required for {flag}"))?
+        .into_string()
+        .map_err(|_| anyhow!("Invalid argument for {flag}"))
+}
+
+fn next_parsed<T: std::str::FromStr>(
+    args: &mut impl Iterator<Item = OsString>,
+    flag: &str,
+) -> anyhow::Result<T> {
+    next_string(args, flag)?
+        .parse()
+        .map_err(|_| anyhow!("Invalid argument for {flag}"))
+}
+
+/// Parse the command line (without the program name). If a config file is
+/// given with `-config`, it is loaded first and everything else overrides it.
+pub fn parse_args(args: impl IntoIterator<Item = OsString>) -> anyhow::Result<Options> {
+    let args: Vec<OsString> = args.into_iter().collect();
+    let config_path = args
+        .iter()
+        .position(|arg| arg == "-config" || arg == "--config")
+        .map(|pos| {
+            args.get(pos + 1)
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("Arg required for -config"))
+        })
+        .transpose()?;
+    let mut config = match config_path {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+    let mut check_config = false;
+    let mut warnings = vec![];
+
+    let mut args_iter = args.into_iter().peekable();
+    while let Some(arg) = args_iter.next() {
+        // we default to one of the ignored args
+        match arg.to_str().unwrap_or("-json") {
+            "-json" | "-xml" | "-indent" | "--" | "-nowrapperdoc" | "-socket" | "-v"
+            | "--version" => {}
+            flag @ "-scriptportonce" => {
+                warnings.push(format!("Ignoring {flag}; d3270d doesn't support it"))
+            }
+            "-scriptport" => config.listen.script = Some(next_string(&mut args_iter, "-scriptport")?),
+            "-httpd" => config.listen.http = Some(next_string(&mut args_iter, "-httpd")?),
+            "-config" | "--config" => {
+                args_iter.next(); // already loaded
+            }
+            "-check-config" | "--check-config" => check_config = true,
+            "-connect" => config.connect = Some(next_string(&mut args_iter, "-connect")?),
+            "-session" => {
+                let session = next_string(&mut args_iter, "-session")?;
+                let (name, connect) = session
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("-session takes name=host[:port]"))?;
+                config.sessions.insert(name.to_owned(), connect.to_owned());
+            }
+            "-tcp-listen" => config.listen.tcp = Some(next_string(&mut args_iter, "-tcp-listen")?),
+            "-http-listen" => {
+                config.listen.http = Some(next_string(&mut args_iter, "-http-listen")?)
+            }
+            "-unix-listen" => {
+                config.listen.unix.get_or_insert_with(Default::default).path =
+                    PathBuf::from(next_string(&mut args_iter, "-unix-listen")?)
+            }
+            "-unix-mode" => {
+                config.listen.unix.get_or_insert_with(Default::default).mode =
+                    Some(next_string(&mut args_iter, "-unix-mode")?)
+            }
+            "-unix-owner" => {
+                config.listen.unix.get_or_insert_with(Default::default).owner =
+                    Some(next_string(&mut args_iter, "-unix-owner")?)
+            }
+            "-unix-group" => {
+                config.listen.unix.get_or_insert_with(Default::default).group =
+                    Some(next_string(&mut args_iter, "-unix-group")?)
+            }
+            "-unix-allow-user" => config
+                .listen
+                .unix
+                .get_or_insert_with(Default::default)
+                .allow_users
+                .push(next_string(&mut args_iter, "-unix-allow-user")?),
+            "-admin-socket" => {
+                config.listen.admin.get_or_insert_with(Default::default).path =
+                    PathBuf::from(next_string(&mut args_iter, "-admin-socket")?)
+            }
+            "-hllapi-socket" => {
+                config.listen.hllapi.get_or_insert_with(Default::default).path =
+                    PathBuf::from(next_string(&mut args_iter, "-hllapi-socket")?)
+            }
+            "-unix-allow-group" => config
+                .listen
+                .unix
+                .get_or_insert_with(Default::default)
+                .allow_groups
+                .push(next_string(&mut args_iter, "-unix-allow-group")?),
+            "-audit-log" => {
+                config.audit.file = Some(PathBuf::from(next_string(&mut args_iter, "-audit-log")?))
+            }
+            "-audit-redact" => config.audit.redact_hidden_input = true,
+            "-record-dir" => {
+                config.record.dir = Some(PathBuf::from(next_string(&mut args_iter, "-record-dir")?))
+            }
+            "-record-max-size" => {
+                config.record.max_size = Some(next_parsed(&mut args_iter, "-record-max-size")?)
+            }
+            "-record-max-age" => {
+                config.record.max_age = Some(next_parsed(&mut args_iter, "-record-max-age")?)
+            }
+            "-record-actions" => config.record.actions = true,
+            "-replay" => {
+                config.replay.file = Some(PathBuf::from(next_string(&mut args_iter, "-replay")?))
+            }
+            "-replay-speed" => {
+                config.replay.speed = Some(next_parsed(&mut args_iter, "-replay-speed")?)
+            }
+            "-replay-step" => config.replay.step = true,
+            "-shutdown-disconnect" => config.shutdown.disconnect = true,
+            "-shutdown-timeout" => {
+                config.shutdown.timeout = Some(next_parsed(&mut args_iter, "-shutdown-timeout")?)
+            }
+            "-b3270" => config.b3270.path = PathBuf::from(next_string(&mut args_iter, "-b3270")?),
+            "-auth-file" => {
+                config.auth.token_file = Some(PathBuf::from(next_string(&mut args_iter, "-auth-file")?))
+            }
+            "-tls-cert" | "-tls-key" => {
+                let flag = arg.to_str().unwrap_or_default();
+                let path = PathBuf::from(next_string(&mut args_iter, flag)?);
+                // The other half has to come from somewhere, so look for it on the command line too
+                let tls = config.tls.get_or_insert_with(|| TlsConfig {
+                    cert: PathBuf::new(),
+                    key: PathBuf::new(),
+                });
+                if flag == "-tls-cert" {
+                    tls.cert = path;
+                } else {
+                    tls.key = path;
+                }
+            }
+            "-allow-action" => config
+                .policy
+                .allow
+                .get_or_insert_with(Vec::new)
+                .push(next_parsed(&mut args_iter, "-allow-action")?),
+            "-deny-action" => config
+                .policy
+                .deny
+                .push(next_parsed(&mut args_iter, "-deny-action")?),
+            "-restart-limit" => {
+                config.restart.limit = Some(next_parsed(&mut args_iter, "-restart-limit")?)
+            }
+            "-restart-backoff" => {
+                config.restart.backoff = Some(next_parsed(&mut args_iter, "-restart-backoff")?)
+            }
+            "-restart-max-backoff" => {
+                config.restart.max_backoff =
+                    Some(next_parsed(&mut args_iter, "-restart-max-backoff")?)
+            }
+            "-log-filter" => config.log.filter = Some(next_string(&mut args_iter, "-log-filter")?),
+            "-e" => {
+                let mut command = vec![];
+                'skip: while let Some(arg) = args_iter.peek() {
+                    if arg.to_str().unwrap_or("").starts_with('-') {
+                        break 'skip;
+                    }
+                    command.extend(args_iter.next());
+                }
+                warnings.push(format!("Ignoring -e {command:?}; d3270d doesn't run commands"));
+            }
+            _ => config
+                .b3270
+                .args
+                .push(arg.into_string().map_err(|_| anyhow!("Invalid argument"))?),
+        }
+    }
+
+    if let Some(ref tls) = config.tls {
+        if tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty() {
+            bail!("-tls-cert and -tls-key must be given together");
+        }
+    }
+
+    Ok(Options {
+        config,
+        check_config,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let config: Config = toml::from_str(
+            r#"
+            connect = "mainframe:23"
+
+            [sessions]
+            cics = "cics:23"
+
+            [listen]
+            tcp = "[::1]:3270"
+
+            [policy]
+            deny = ["Connect", "PrintText(file)"]
+
+            [restart]
+            limit = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.connect.as_deref(), Some("mainframe:23"));
+        assert_eq!(config.sessions["cics"], "cics:23");
+        assert_eq!(config.policy.deny.len(), 2);
+        assert_eq!(config.restart.limit, Some(3));
+        assert_eq!(config.b3270.path, PathBuf::from("b3270"));
+    }
+
+    #[test]
+    fn config_errors_are_precise() {
+        let error = toml::from_str::<Config>("[listen]\ntcp = \"[::1]:3270\"\nudp = \"x\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 3"), "{error}");
+        assert!(error.contains("udp"), "{error}");
+
+        let error = toml::from_str::<Config>("[policy]\ndeny = [\"Connect\", \"PrintText(file\"]\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 2"), "{error}");
+        assert!(error.contains("Missing ')'"), "{error}");
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let options = parse_args(args(&[
+            "-connect",
+            "a:23",
+            "-deny-action",
+            "Connect",
+            "-model",
+            "3279-4-E",
+            "--check-config",
+        ]))
+        .unwrap();
+        assert!(options.check_config);
+        assert_eq!(options.config.connect.as_deref(), Some("a:23"));
+        assert_eq!(options.config.policy.deny.len(), 1);
+        assert_eq!(options.config.b3270.args, ["-model", "3279-4-E"]);
+
+        assert!(parse_args(args(&["-tls-cert", "cert.pem"])).is_err());
+    }
+}
@@ -16,27 +16,40 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
  *************************************************************************/
 
-use crate::arbiter::{ArbiterHandle, ArbiterHandleRequester};
-use d3270_common::b3270::indication::RunResult;
-use d3270_common::b3270::operation::Run;
+use crate::arbiter::{failed_run_result, ArbiterHandle, ArbiterHandleRequester, PassthruCompletion};
+use crate::audit::{AuditLog, AuditTrail, Origin, PendingAudit};
+use crate::auth::Identity;
+use crate::policy::ActionPolicy;
+use d3270_common::b3270::indication::{RunResult, UiError};
+use d3270_common::b3270::operation::{Action, FloorControl, Run};
 use d3270_common::b3270::{Indication, Operation};
+use d3270_common::tracker::Tracker;
+use anyhow::bail;
 use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use futures::FutureExt;
+use std::collections::VecDeque;
 use std::future::{poll_fn, Future};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 use tokio::sync::oneshot;
-use tracing::warn;
+use tracing::{info, warn};
 
 pub struct GenConnection {
     handle: ArbiterHandle,
+    policy: Arc<ActionPolicy>,
+    audit: AuditTrail,
+    read_only: bool,
     waiting_actions: FuturesUnordered<ReplaceTag>,
+    // Indications generated by d3270d itself, to be sent before anything else
+    local_indications: VecDeque<Indication>,
 }
 
 struct ReplaceTag {
     tag: Option<String>,
     rcvr: oneshot::Receiver<RunResult>,
+    audit: Option<PendingAudit>,
 }
 
 impl Future for ReplaceTag {
@@ -44,6 +57,9 @@ impl Future for ReplaceTag {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         Poll::Ready(ready!(self.rcvr.poll_unpin(cx)).ok().map(|run_res| {
+            if let Some(audit) = self.audit.take() {
+                audit.finish(&run_res);
+            }
             Indication::RunResult(RunResult {
                 r_tag: self.tag.take(),
                 ..run_res
@@ -52,28 +68,129 @@ impl Future for ReplaceTag {
     }
 }
 
+const READ_ONLY_MESSAGE: &str = "This connection is read-only";
+
+/// The indication sent to a client just before it is disconnected for failing
+/// to authenticate or naming a session that doesn't exist
+pub fn attach_failure_indication(error: &anyhow::Error) -> Indication {
+    Indication::UiError(UiError {
+        fatal: true,
+        text: error.to_string(),
+        operation: Some("attach".to_owned()),
+        member: None,
+        line: None,
+        column: None,
+    })
+}
+
 impl GenConnection {
-    pub async fn new(ahr: ArbiterHandleRequester) -> anyhow::Result<Self> {
-        let handle = ahr.connect().await?;
+    /// A `read_only` connection receives everything but may not run actions
+    /// or take part in passthru actions. Actions it runs are recorded in
+    /// `audit_log`, if there is one.
+    pub async fn new(
+        ahr: ArbiterHandleRequester,
+        identity: &Identity,
+        policy: Arc<ActionPolicy>,
+        read_only: bool,
+        audit_log: Option<Arc<AuditLog>>,
+        origin: Origin,
+    ) -> anyhow::Result<Self> {
+        let mut handle = ahr.connect(&identity.name).await?;
+        handle.join(&origin, read_only).await?;
         Ok(Self {
             handle,
+            policy,
+            audit: AuditTrail::new(audit_log, identity, origin, ahr.session()),
+            read_only,
             waiting_actions: FuturesUnordered::new(),
+            local_indications: VecDeque::new(),
         })
     }
 
+    /// Answer a run operation with a failure without ever sending it to b3270
+    fn reject(&mut self, r_tag: Option<String>, text: String, audit: Option<PendingAudit>) {
+        let result = failed_run_result(text);
+        if let Some(audit) = audit {
+            audit.finish(&result);
+        }
+        let (snd, rcvr) = oneshot::channel();
+        snd.send(result).ok();
+        self.waiting_actions.push(ReplaceTag { tag: r_tag, rcvr, audit: None });
+    }
+
+    /// Report a refused non-run operation the same way b3270 reports a bad one
+    fn reject_operation(&mut self, operation: &str, text: &str) {
+        self.local_indications.push_back(Indication::UiError(UiError {
+            fatal: false,
+            text: text.to_owned(),
+            operation: Some(operation.to_owned()),
+            member: None,
+            line: None,
+            column: None,
+        }));
+    }
+
     pub async fn handle_client_line(&mut self, line: String) -> anyhow::Result<()> {
-        let op = serde_json::from_str(&line)?;
+        self.handle_operation(serde_json::from_str(&line)?).await
+    }
+
+    pub async fn handle_operation(&mut self, op: Operation) -> anyhow::Result<()> {
         match op {
+            Operation::Run(Run { r_tag, actions, .. }) if self.read_only => {
+                let audit = self.audit.begin(&actions, self.handle.input_hidden());
+                self.reject(r_tag, READ_ONLY_MESSAGE.to_owned(), audit);
+            }
+            Operation::Register(_) if self.read_only => {
+                self.reject_operation("register", READ_ONLY_MESSAGE)
+            }
+            Operation::Succeed(_) if self.read_only => {
+                self.reject_operation("succeed", READ_ONLY_MESSAGE)
+            }
+            Operation::Fail(_) if self.read_only => self.reject_operation("fail", READ_ONLY_MESSAGE),
+            Operation::Floor(_) if self.read_only => {
+                self.reject_operation("floor", READ_ONLY_MESSAGE)
+            }
+            Operation::Register(register) => {
+                if let Err(reason) = self.handle.register(register).await? {
+                    self.reject_operation("register", &reason);
+                }
+            }
+            Operation::Succeed(succeed) => {
+                if let Err(reason) = self.handle.complete(PassthruCompletion::Succeed(succeed)).await? {
+                    self.reject_operation("succeed", &reason);
+                }
+            }
+            Operation::Fail(fail) => {
+                if let Err(reason) = self.handle.complete(PassthruCompletion::Fail(fail)).await? {
+                    self.reject_operation("fail", &reason);
+                }
+            }
+            Operation::Floor(FloorControl { action }) => {
+                if let Err(reason) = self.handle.floor(action).await? {
+                    self.reject_operation("floor", &reason);
+                }
+            }
             Operation::Run(Run { actions, r_tag, .. }) => {
+                let audit = self.audit.begin(&actions, self.handle.input_hidden());
+                if let Err(reason) = self.policy.check_all(&actions) {
+                    info!(?actions, %reason, "Rejected actions");
+                    self.reject(r_tag, reason, audit);
+                    return Ok(());
+                }
                 let rcvr = self.handle.send_actions(actions).await?;
-                self.waiting_actions.push(ReplaceTag { tag: r_tag, rcvr });
+                self.waiting_actions.push(ReplaceTag { tag: r_tag, rcvr, audit });
             }
-            _ => warn!(json = line, "Unsupported operation from client"),
+            // Waiting changes nothing, so read-only connections may
+            Operation::WaitFor(wait) => self.handle.wait_for(wait).await?,
+            op => warn!(operation = op.name(), "Unsupported operation from client"),
         }
         Ok(())
     }
 
     pub fn poll_indication(&mut self, cx: &mut Context) -> Poll<Option<Indication>> {
+        if let Some(ind) = self.local_indications.pop_front() {
+            return Poll::Ready(Some(ind));
+        }
         let mut any_can_continue = false;
 
         match self.waiting_actions.poll_next_unpin(cx) {
@@ -85,6 +202,10 @@ impl GenConnection {
         }
 
         match self.handle.poll_next_unpin(cx) {
+            Poll::Ready(Some(Indication::Floor(mut floor))) => {
+                floor.mine = floor.holder_id == Some(self.handle.id());
+                return Poll::Ready(Some(Indication::Floor(floor)));
+            }
             Poll::Ready(Some(ind)) => {
                 return Poll::Ready(Some(ind));
             }
@@ -103,3 +224,74 @@ impl GenConnection {
         poll_fn(|cx| self.poll_indication(cx)).await
     }
 }
+
+/// A connection for clients that ask about the screen rather than being sent
+/// it, such as the script port. It keeps its own copy of the screen up to
+/// date, as long as [`TrackedConnection::update`] is polled while idle.
+pub struct TrackedConnection {
+    conn: GenConnection,
+    tracker: Tracker,
+    // What the runs are tagged with, and how many there have been
+    kind: &'static str,
+    runs: u64,
+}
+
+impl TrackedConnection {
+    pub fn new(conn: GenConnection, kind: &'static str) -> Self {
+        Self {
+            conn,
+            tracker: Tracker::default(),
+            kind,
+            runs: 0,
+        }
+    }
+
+    pub fn tracker(&self) -> &Tracker {
+        &self.tracker
+    }
+
+    /// Run actions, keeping the tracker up to date until they're done
+    pub async fn run(&mut self, actions: Vec<Action>) -> anyhow::Result<RunResult> {
+        self.runs += 1;
+        let r_tag = format!("{}-{}", self.kind, self.runs);
+        let run = Run {
+            r_tag: Some(r_tag.clone()),
+            type_: Some(self.kind.to_owned()),
+            actions,
+        };
+        self.conn.handle_operation(Operation::Run(run)).await?;
+        loop {
+            match self.conn.next_indication().await {
+                Some(Indication::RunResult(result)) if result.r_tag.as_ref() == Some(&r_tag) => {
+                    // b3270 sends a run's effects before its result, so they're
+                    // already waiting if they haven't arrived yet
+                    self.catch_up();
+                    return Ok(result);
+                }
+                Some(mut ind) => {
+                    self.tracker.handle_indication(&mut ind);
+                }
+                None => bail!("Session ended"),
+            }
+        }
+    }
+
+    /// Apply whatever indications have already arrived, such as the state of
+    /// the session right after connecting
+    pub fn catch_up(&mut self) {
+        while let Some(Some(mut ind)) = self.conn.next_indication().now_or_never() {
+            self.tracker.handle_indication(&mut ind);
+        }
+    }
+
+    /// Wait for the next indication and apply it. Cancel-safe.
+    pub async fn update(&mut self) -> anyhow::Result<()> {
+        match self.conn.next_indication().await {
+            Some(mut ind) => {
+                self.tracker.handle_indication(&mut ind);
+                Ok(())
+            }
+            None => bail!("Session ended"),
+        }
+    }
+}
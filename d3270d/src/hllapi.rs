@@ -0,0 +1,565 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! The HLLAPI socket (`-hllapi-socket`), for programs written against
+//! EHLLAPI's functions.
+//!
+//! Like the client socket, it is a unix socket whose peers are identified
+//! by their user name. The requests and responses are those of
+//! [`d3270_common::hllapi`]; they are answered from a copy of the screen
+//! that is kept up to date for each connected presentation space, and keys
+//! are sent as actions, the same as any other client's.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::never::Never;
+use nix::unistd::{Uid, User};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+use d3270_common::b3270::operation::Action;
+use d3270_common::hllapi::{rc, FieldAttribute, HllapiRequest, HllapiResponse, SessionStatus};
+
+use crate::audit::{AuditLog, Origin};
+use crate::auth::Identity;
+use crate::gen_connection::{GenConnection, TrackedConnection};
+use crate::metrics::ClientGuard;
+use crate::policy::ActionPolicy;
+use crate::session::SessionRegistry;
+use crate::unix_server::{self, PeerPolicy, UnixListenOptions};
+
+/// How long `wait` waits if it isn't told
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+
+#[instrument(skip_all, fields(path = %options.path.display()))]
+pub async fn listener_proc(
+    options: UnixListenOptions,
+    sessions: SessionRegistry,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<JoinHandle<anyhow::Error>> {
+    let listener = unix_server::bind(&options).map_err(|error| {
+        error!(%error, "Failed to bind");
+        error
+    })?;
+    info!("HLLAPI listener starting");
+    Ok(tokio::spawn(
+        async move {
+            let error = listener_task(listener, options.peers, sessions, policy, audit)
+                .await
+                .unwrap_err();
+            error!(%error, "HLLAPI listener failed to accept");
+            error
+        }
+        .in_current_span(),
+    ))
+}
+
+async fn listener_task(
+    listener: UnixListener,
+    peers: PeerPolicy,
+    sessions: SessionRegistry,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<Never> {
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let cred = match conn.peer_cred() {
+            Ok(cred) => cred,
+            Err(error) => {
+                warn!(%error, "Failed to get peer credentials");
+                continue;
+            }
+        };
+        let (uid, gid) = (cred.uid(), cred.gid());
+        let name = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map_or_else(|| format!("uid:{uid}"), |user| user.name);
+        let conn_span = info_span!(target: "connection-handling", "hllapi_accept", user = name, pid = cred.pid());
+        let allowed = peers.check(uid, gid, &name);
+        let client = HllapiClient {
+            identity: Identity { name },
+            sessions: sessions.clone(),
+            policy: policy.clone(),
+            audit: audit.clone(),
+            origin: Origin {
+                listener: "hllapi",
+                address: cred.pid().map(|pid| format!("pid:{pid}")),
+            },
+            ps: None,
+        };
+        tokio::spawn(
+            async move {
+                info!("Accepted connection");
+                let result = if allowed {
+                    handle_connection(conn, client).await
+                } else {
+                    reject(conn).await
+                };
+                if let Err(error) = result {
+                    error!(%error, "Connection handler failed");
+                } else {
+                    info!("Connection closed");
+                }
+            }
+            .instrument(conn_span),
+        );
+    }
+}
+
+async fn respond<W: AsyncWrite + Unpin>(stream_wr: &mut W, response: &HllapiResponse) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    stream_wr.write_all(&line).await?;
+    Ok(())
+}
+
+async fn reject(mut conn: UnixStream) -> anyhow::Result<()> {
+    warn!("Rejecting HLLAPI connection");
+    respond(&mut conn, &HllapiResponse::error(rc::SYSTEM_ERROR, "Permission denied")).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+async fn handle_connection(conn: UnixStream, mut client: HllapiClient) -> anyhow::Result<()> {
+    let (stream_rd, mut stream_wr) = conn.into_split();
+    let mut stream_rd = BufReader::new(stream_rd).lines();
+    loop {
+        select! {
+            line = stream_rd.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                let response = match serde_json::from_str::<HllapiRequest>(&line) {
+                    Ok(request) => client.handle(request).await.unwrap_or_else(|error| {
+                        // Whatever failed, it was the session that failed
+                        warn!(%error, "HLLAPI request failed");
+                        client.ps = None;
+                        HllapiResponse::error(rc::SYSTEM_ERROR, format!("{error:#}"))
+                    }),
+                    Err(error) => HllapiResponse::error(rc::PARAMETER_ERROR, format!("Invalid request: {error}")),
+                };
+                respond(&mut stream_wr, &response).await?;
+            }
+            updated = async { client.ps.as_mut().unwrap().conn.update().await }, if client.ps.is_some() => {
+                if let Err(error) = updated {
+                    info!(%error, "Presentation space went away");
+                    client.ps = None;
+                }
+            }
+        }
+    }
+}
+
+/// A connected presentation space
+struct Ps {
+    session: String,
+    conn: TrackedConnection,
+    // Clients are counted while they have a presentation space
+    _client: ClientGuard,
+}
+
+struct HllapiClient {
+    identity: Identity,
+    sessions: SessionRegistry,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+    origin: Origin,
+    ps: Option<Ps>,
+}
+
+impl HllapiClient {
+    async fn handle(&mut self, request: HllapiRequest) -> anyhow::Result<HllapiResponse> {
+        match request {
+            HllapiRequest::ConnectPs { session } => {
+                let requester = match self.sessions.get(session.as_deref()) {
+                    Ok(requester) => requester,
+                    Err(error) => return Ok(HllapiResponse::error(rc::NOT_CONNECTED, error.to_string())),
+                };
+                // Let go of any other presentation space first, so it isn't
+                // in the roster twice
+                self.ps = None;
+                let session = requester.session().to_owned();
+                let conn = GenConnection::new(
+                    requester,
+                    &self.identity,
+                    self.policy.clone(),
+                    false,
+                    self.audit.clone(),
+                    self.origin.clone(),
+                )
+                .await?;
+                let mut conn = TrackedConnection::new(conn, "hllapi");
+                conn.catch_up();
+                info!(session, "Connected presentation space");
+                self.ps = Some(Ps {
+                    session,
+                    conn,
+                    _client: ClientGuard::new("hllapi"),
+                });
+                Ok(HllapiResponse::ok())
+            }
+            HllapiRequest::DisconnectPs if self.ps.is_some() => {
+                self.ps = None;
+                Ok(HllapiResponse::ok())
+            }
+            HllapiRequest::QuerySessions => Ok(HllapiResponse {
+                sessions: self.sessions.list().into_iter().map(|info| info.name).collect(),
+                ..HllapiResponse::ok()
+            }),
+            request => match self.ps.as_mut() {
+                Some(ps) => ps.handle(request).await,
+                None => Ok(HllapiResponse::error(
+                    rc::NOT_CONNECTED,
+                    "Not connected to a presentation space",
+                )),
+            },
+        }
+    }
+}
+
+impl Ps {
+    /// The presentation space as one line, and how wide it is
+    fn text(&self) -> (Vec<char>, usize) {
+        let screen = self.conn.tracker().get_screen();
+        let columns = screen.first().map_or(0, Vec::len);
+        (screen.iter().flatten().map(|cell| cell.ch).collect(), columns)
+    }
+
+    async fn handle(&mut self, request: HllapiRequest) -> anyhow::Result<HllapiResponse> {
+        let (ps, columns) = self.text();
+        // Positions are 1-based; this is 0-based
+        let index = |position: usize| (1..=ps.len()).contains(&position).then(|| position - 1);
+        let bad_position = |position| HllapiResponse::error(rc::INVALID_POSITION, format!("Invalid position {position}"));
+
+        Ok(match request {
+            // HllapiClient deals with these
+            HllapiRequest::ConnectPs { .. } | HllapiRequest::DisconnectPs | HllapiRequest::QuerySessions => {
+                unreachable!()
+            }
+            HllapiRequest::SendKey { keys } => {
+                if let Some(lock) = &self.conn.tracker().get_oia_state().lock {
+                    return Ok(HllapiResponse::error(rc::INHIBITED, format!("Keyboard locked ({lock})")));
+                }
+                let actions = match translate_keys(&keys) {
+                    Ok(actions) => actions,
+                    Err(error) => return Ok(HllapiResponse::error(rc::PARAMETER_ERROR, error)),
+                };
+                if actions.is_empty() {
+                    return Ok(HllapiResponse::ok());
+                }
+                let result = self.conn.run(actions).await?;
+                if result.success {
+                    HllapiResponse::ok()
+                } else {
+                    HllapiResponse::error(rc::INHIBITED, result.text.join("\n"))
+                }
+            }
+            HllapiRequest::Wait { timeout } => {
+                let timeout = match timeout.map(Duration::try_from_secs_f64) {
+                    None => DEFAULT_WAIT,
+                    Some(Ok(timeout)) => timeout,
+                    Some(Err(error)) => return Ok(HllapiResponse::error(rc::PARAMETER_ERROR, format!("Invalid timeout: {error}"))),
+                };
+                let deadline = Instant::now() + timeout;
+                loop {
+                    match &self.conn.tracker().get_oia_state().lock {
+                        None => break HllapiResponse::ok(),
+                        // Only a keystroke clears an operator error
+                        Some(lock) if lock.starts_with("oerr") => {
+                            break HllapiResponse::error(rc::INHIBITED, format!("Keyboard locked ({lock})"))
+                        }
+                        Some(_) => {}
+                    }
+                    match timeout_at(deadline, self.conn.update()).await {
+                        Ok(updated) => updated?,
+                        Err(_) => break HllapiResponse::error(rc::BUSY, "Timed out waiting for the keyboard"),
+                    }
+                }
+            }
+            HllapiRequest::CopyPs => HllapiResponse {
+                text: Some(ps.iter().collect()),
+                ..HllapiResponse::ok()
+            },
+            HllapiRequest::CopyPsToString { position, length } => {
+                let Some(start) = index(position) else {
+                    return Ok(bad_position(position));
+                };
+                if length == 0 {
+                    return Ok(HllapiResponse::error(rc::PARAMETER_ERROR, "Length must be at least 1"));
+                }
+                let Some(end) = start.checked_add(length) else {
+                    return Ok(HllapiResponse::error(rc::PARAMETER_ERROR, "Length is too large"));
+                };
+                match ps.get(start..end) {
+                    Some(text) => HllapiResponse {
+                        text: Some(text.iter().collect()),
+                        ..HllapiResponse::ok()
+                    },
+                    None => HllapiResponse::error(rc::INVALID_POSITION, "Past the end of the presentation space"),
+                }
+            }
+            HllapiRequest::SearchPs { text, position, backward } => {
+                let text: Vec<char> = text.chars().collect();
+                if text.is_empty() {
+                    return Ok(HllapiResponse::error(rc::PARAMETER_ERROR, "Nothing to search for"));
+                }
+                let from = position.unwrap_or(if backward { ps.len() } else { 1 });
+                let Some(from) = index(from) else {
+                    return Ok(bad_position(from));
+                };
+                let starts = ps.windows(text.len()).enumerate();
+                let found = if backward {
+                    starts.take(from + 1).rev().find(|(_, window)| *window == text)
+                } else {
+                    starts.skip(from).find(|(_, window)| *window == text)
+                };
+                match found {
+                    Some((start, _)) => HllapiResponse {
+                        position: Some(start + 1),
+                        ..HllapiResponse::ok()
+                    },
+                    None => HllapiResponse::error(rc::NOT_FOUND, "Not found"),
+                }
+            }
+            HllapiRequest::QueryCursor => {
+                let cursor = self.conn.tracker().get_cursor();
+                let row = usize::from(cursor.row.unwrap_or(1).max(1));
+                let column = usize::from(cursor.column.unwrap_or(1).max(1));
+                HllapiResponse {
+                    position: Some((row - 1) * columns + column),
+                    ..HllapiResponse::ok()
+                }
+            }
+            HllapiRequest::SetCursor { position } => {
+                let Some(index) = index(position) else {
+                    return Ok(bad_position(position));
+                };
+                let row = (index / columns + 1).to_string();
+                let column = (index % columns + 1).to_string();
+                let result = self.conn.run(vec![action("MoveCursor1", vec![row, column])]).await?;
+                if result.success {
+                    HllapiResponse::ok()
+                } else {
+                    HllapiResponse::error(rc::SYSTEM_ERROR, result.text.join("\n"))
+                }
+            }
+            HllapiRequest::QueryFieldAttribute { position } => {
+                let Some(index) = index(position) else {
+                    return Ok(bad_position(position));
+                };
+                // The tracker knows where the fields are, but not what their
+                // attributes are, so ask b3270
+                let result = self.conn.run(vec![action("ReadBuffer", vec!["Ascii".to_owned()])]).await?;
+                if !result.success {
+                    return Ok(HllapiResponse::error(rc::SYSTEM_ERROR, result.text.join("\n")));
+                }
+                match field_attribute(&parse_read_buffer(&result.text), index) {
+                    Some((start, value)) => HllapiResponse {
+                        position: Some(start + 1),
+                        attribute: Some(FieldAttribute::from(value)),
+                        ..HllapiResponse::ok()
+                    },
+                    None => HllapiResponse::error(rc::NOT_FOUND, "The screen is unformatted"),
+                }
+            }
+            HllapiRequest::QuerySessionStatus => {
+                let tracker = self.conn.tracker();
+                let screen_mode = tracker.get_screen_mode();
+                HllapiResponse {
+                    status: Some(SessionStatus {
+                        session: self.session.clone(),
+                        host: tracker.get_connection().host.clone(),
+                        model: screen_mode.model,
+                        rows: screen_mode.rows,
+                        columns: screen_mode.columns,
+                        keyboard_locked: tracker.get_oia_state().lock.is_some(),
+                    }),
+                    ..HllapiResponse::ok()
+                }
+            }
+        })
+    }
+}
+
+fn action(name: &str, args: Vec<String>) -> Action {
+    Action {
+        action: name.to_owned(),
+        args,
+    }
+}
+
+/// The action for a key mnemonic, the character after the `@`
+fn mnemonic(key: char) -> Option<Action> {
+    let plain = |name| action(name, vec![]);
+    let numbered = |name, n: u32| action(name, vec![n.to_string()]);
+    Some(match key {
+        'E' => plain("Enter"),
+        'C' => plain("Clear"),
+        'T' => plain("Tab"),
+        'B' => plain("BackTab"),
+        'F' => plain("EraseEOF"),
+        'D' => plain("Delete"),
+        'I' => plain("ToggleInsert"),
+        'R' => plain("Reset"),
+        'L' => plain("Left"),
+        'Z' => plain("Right"),
+        'U' => plain("Up"),
+        'V' => plain("Down"),
+        'N' => plain("Newline"),
+        '0' => plain("Home"),
+        '<' => plain("BackSpace"),
+        'q' => plain("FieldEnd"),
+        '1'..='9' => numbered("PF", key as u32 - '0' as u32),
+        'a'..='o' => numbered("PF", key as u32 - 'a' as u32 + 10),
+        'x'..='z' => numbered("PA", key as u32 - 'x' as u32 + 1),
+        _ => return None,
+    })
+}
+
+/// The action for a mnemonic that starts `@A`
+fn alt_mnemonic(key: char) -> Option<Action> {
+    Some(action(
+        match key {
+            'Q' => "Attn",
+            'H' => "SysReq",
+            'F' => "EraseInput",
+            _ => return None,
+        },
+        vec![],
+    ))
+}
+
+/// Turn `send-key`'s keys into actions: text becomes `String()`, and each
+/// `@` mnemonic the action for that key. `@@` is an `@`.
+pub fn translate_keys(keys: &str) -> Result<Vec<Action>, String> {
+    let mut actions = vec![];
+    let mut text = String::new();
+    let mut chars = keys.chars();
+    while let Some(c) = chars.next() {
+        if c != '@' {
+            // String() takes backslashes as escapes
+            if c == '\\' {
+                text.push('\\');
+            }
+            text.push(c);
+            continue;
+        }
+        let key = match chars.next() {
+            Some('@') => {
+                text.push('@');
+                continue;
+            }
+            Some('A') => match chars.next() {
+                Some('@') => chars.next().and_then(alt_mnemonic),
+                _ => None,
+            },
+            Some(key) => mnemonic(key),
+            None => return Err("Incomplete key mnemonic at the end".to_owned()),
+        };
+        let Some(key) = key else {
+            return Err(format!("Unknown key mnemonic in {keys}"));
+        };
+        if !text.is_empty() {
+            actions.push(action("String", vec![std::mem::take(&mut text)]));
+        }
+        actions.push(key);
+    }
+    if !text.is_empty() {
+        actions.push(action("String", vec![text]));
+    }
+    Ok(actions)
+}
+
+/// The field attribute of each cell of `ReadBuffer(Ascii)`'s output, which
+/// has a token per cell, `SF(c0=xx,...)` for a field attribute. `SA(...)`
+/// tokens change the character attributes, and don't take up a cell.
+fn parse_read_buffer(text: &[String]) -> Vec<Option<u8>> {
+    text.iter()
+        .flat_map(|line| line.split_whitespace())
+        .filter(|token| !token.starts_with("SA("))
+        .map(|token| {
+            let attrs = token.strip_prefix("SF(")?.trim_end_matches(')');
+            attrs
+                .split(',')
+                .find_map(|attr| attr.strip_prefix("c0="))
+                .and_then(|value| u8::from_str_radix(value, 16).ok())
+        })
+        .collect()
+}
+
+/// The field attribute that governs a cell: the nearest one at or before it,
+/// wrapping around from the top of the screen to the bottom. Also where it is.
+fn field_attribute(cells: &[Option<u8>], index: usize) -> Option<(usize, u8)> {
+    let index = index.min(cells.len().checked_sub(1)?);
+    let (before, after) = cells.split_at(index + 1);
+    before
+        .iter()
+        .enumerate()
+        .rev()
+        .chain(after.iter().enumerate().map(|(n, cell)| (n + index + 1, cell)).rev())
+        .find_map(|(n, cell)| cell.map(|value| (n, value)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_mnemonics() {
+        let names = |keys| {
+            translate_keys(keys).map(|actions| {
+                actions
+                    .into_iter()
+                    .map(|action| format!("{}({})", action.action, action.args.join(",")))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(names("logon@E"), Ok(vec!["String(logon)".to_owned(), "Enter()".to_owned()]));
+        assert_eq!(
+            names("@1@a@o@z@A@Qa\\b@@c"),
+            Ok(vec!["PF(1)", "PF(10)", "PF(24)", "PA(3)", "Attn()", "String(a\\\\b@c)"]
+                .into_iter()
+                .map(str::to_owned)
+                .collect())
+        );
+        assert_eq!(names(""), Ok(vec![]));
+        assert!(names("@").is_err());
+        assert!(names("@!").is_err());
+        assert!(names("@A@!").is_err());
+    }
+
+    #[test]
+    fn field_attributes() {
+        let text = vec!["SF(c0=e8) SA(41=f2) 41 42 SF(c0=c1,42=f4)".to_owned(), "43 00".to_owned()];
+        let cells = parse_read_buffer(&text);
+        assert_eq!(cells, vec![Some(0xe8), None, None, Some(0xc1), None, None]);
+        assert_eq!(field_attribute(&cells, 0), Some((0, 0xe8)));
+        assert_eq!(field_attribute(&cells, 2), Some((0, 0xe8)));
+        assert_eq!(field_attribute(&cells, 5), Some((3, 0xc1)));
+        let cells = vec![None, Some(0x20), None];
+        assert_eq!(field_attribute(&cells, 0), Some((1, 0x20)));
+        assert_eq!(field_attribute(&[None, None], 1), None);
+    }
+}
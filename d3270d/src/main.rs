@@ -19,23 +19,38 @@
 use std::ffi::OsString;
 use std::future::Future;
 use std::pin::Pin;
-use std::process::Stdio;
-use std::str::FromStr;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 
-use anyhow::anyhow;
+use anyhow::Context as _;
 use futures::future::select_all;
 use futures::FutureExt;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
-use tracing::{error, info};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::prelude::*;
 
-use d3270_common::b3270::operation::Action;
-
+pub mod admin;
 pub mod arbiter;
+pub mod audit;
+pub mod auth;
+pub mod config;
 pub mod gen_connection;
+pub mod hllapi;
+pub mod metrics;
+pub mod policy;
+pub mod recording;
+pub mod replay;
+pub mod screen_wait;
+pub mod script_server;
+pub mod session;
+pub mod snapshot;
 pub mod tcp_server;
+pub mod tls;
+pub mod unix_server;
 pub mod ws_server;
 
 struct TaggedJoinHandle {
@@ -67,106 +82,108 @@ impl JoinHandleTagExt for JoinHandle<anyhow::Error> {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Configure logging
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let options = config::parse_args(args.iter().cloned())?;
+
+    // Configure logging. RUST_LOG wins over the config file.
+    let filter = match (std::env::var_os(EnvFilter::DEFAULT_ENV), &options.config.log.filter) {
+        (None, Some(filter)) => EnvFilter::try_new(filter).context("Invalid log.filter")?,
+        _ => EnvFilter::from_default_env(),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::EnvFilter::from_default_env())
+        .with(filter)
         .with(
             tracing_subscriber::fmt::layer()
                 .with_span_events(FmtSpan::NONE)
         ).init();
+    for warning in &options.warnings {
+        warn!("{warning}");
+    }
 
-    info!("Test");
-
-    let mut subprocess_args = vec![
-        OsString::from_str("-json").unwrap(),
-        OsString::from_str("-utf8").unwrap(),
-    ];
-    let mut args_iter = std::env::args_os().peekable();
-    let mut connect_str = None;
-    let mut tcp_listen = None;
-    let mut http_listen = None;
-
-    args_iter.next(); // skip program name.
-
-    while let Some(arg) = args_iter.next() {
-        // we default to one of the ignored args
-        match arg.to_str().unwrap_or("-json") {
-            "-json" | "-xml" | "-indent" | "--" | "-scriptportonce" | "-nowrapperdoc"
-            | "-socket" | "-v" | "--version" => {}
-            "-scriptport" | "-httpd" => {
-                args_iter.next();
-            }
-            "-connect" => {
-                connect_str = args_iter
-                    .next()
-                    .ok_or_else(|| anyhow!("Arg required for -connect"))
-                    .and_then(|arg| {
-                        arg.into_string()
-                            .map_err(|_| anyhow!("Invalid connect string"))
-                    })
-                    .map(Some)?;
-            }
-            "-tcp-listen" => {
-                tcp_listen = args_iter
-                    .next()
-                    .ok_or_else(|| anyhow!("Arg required for -tcp-listen"))?
-                    .into_string()
-                    .map_err(|_| anyhow!("Failed to parse tcp-listen address"))?
-                    .parse()
-                    .map(Some)
-                    .map_err(|_| anyhow!("Failed to parse tcp-listen address"))?;
-            }
-            "-http-listen" => {
-                http_listen = args_iter
-                    .next()
-                    .ok_or_else(|| anyhow!("Arg required for -http-listen"))?
-                    .into_string()
-                    .map_err(|_| anyhow!("Failed to parse http-listen address"))?
-                    .parse()
-                    .map(Some)
-                    .map_err(|_| anyhow!("Failed to parse http-listen address"))?;
-            }
-            "-e" => {
-                'skip: while let Some(arg) = args_iter.peek() {
-                    if arg.to_str().unwrap_or("").starts_with("-") {
-                        break 'skip;
-                    }
-                    args_iter.next();
-                }
-            }
-            _ => subprocess_args.push(arg),
-        }
+    let settings = options.config.resolve().await?;
+    if options.check_config {
+        println!("Configuration OK");
+        return Ok(());
     }
 
-    let connect_str = connect_str.ok_or_else(|| anyhow!("No connect string given"))?;
+    let auth = Arc::new(settings.auth);
+    let policy = Arc::new(settings.policy);
+    let audit = settings.audit.map(Arc::new);
+    let tls_config = settings.tls;
 
-    info!(args=?subprocess_args, "Starting b3270");
-    let subproc = tokio::process::Command::new("b3270")
-        .args(&subprocess_args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
+    let sessions = session::SessionRegistry::new(
+        settings.b3270_program,
+        settings.b3270_args,
+        settings.restart,
+        settings.record,
+    );
+    if let Some(replay) = settings.replay {
+        sessions.replay(session::DEFAULT_SESSION, replay)?;
+    }
+    // The -connect session comes first so that it becomes the default
+    for (name, connect) in settings.sessions {
+        sessions.create(&name, &connect)?;
+    }
+    if sessions.list().is_empty() {
+        warn!("No sessions given; they will need to be created through the HTTP API");
+    }
 
     let mut handles: Vec<TaggedJoinHandle> = vec![];
 
-    let (arbiter, arbiter_req) = arbiter::B3270::spawn(
-        subproc,
-        &[Action {
-            action: "Connect".to_owned(),
-            args: vec![connect_str],
-        }],
-    );
-    handles.push(arbiter.tagged("arbiter"));
-    if let Some(addr) = tcp_listen {
-        let tcp_listener = tcp_server::listener_proc(addr, arbiter_req.clone()).await?;
+    if let Some(addr) = settings.tcp_listen {
+        let tcp_listener = tcp_server::listener_proc(
+            addr,
+            sessions.clone(),
+            auth.clone(),
+            tls_config.clone().map(|config| TlsAcceptor::from(Arc::new(config))),
+            policy.clone(),
+            audit.clone(),
+        )
+        .await?;
         handles.push(tcp_listener.tagged("tcp_listener"));
     }
-    if let Some(addr) = http_listen {
-        let ws_listener = ws_server::start_ws_server(addr, arbiter_req.clone()).await?;
+    if let Some(addr) = settings.http_listen {
+        let ws_listener = ws_server::start_ws_server(addr, sessions.clone(), auth.clone(), tls_config.clone(), policy.clone(), audit.clone()).await?;
         handles.push(ws_listener.tagged("ws_server"));
     }
-    let ((source, error), _, _) = select_all(handles).await;
-    error!(source, %error, "A core task failed");
+    if let Some(options) = settings.unix_listen {
+        let unix_listener = unix_server::listener_proc(options, sessions.clone(), auth.clone(), policy.clone(), audit.clone()).await?;
+        handles.push(unix_listener.tagged("unix_listener"));
+    }
+    if let Some(addr) = settings.script_listen {
+        let script_listener = script_server::listener_proc(addr, sessions.clone(), auth.clone(), policy.clone(), audit.clone()).await?;
+        handles.push(script_listener.tagged("script_listener"));
+    }
+    if let Some(options) = settings.hllapi_listen {
+        let hllapi_listener = hllapi::listener_proc(options, sessions.clone(), policy.clone(), audit.clone()).await?;
+        handles.push(hllapi_listener.tagged("hllapi_listener"));
+    }
+    if let Some(options) = settings.admin_listen {
+        let state = admin::AdminState {
+            sessions: sessions.clone(),
+            auth: auth.clone(),
+            policy: policy.clone(),
+            audit: audit.clone(),
+            args,
+        };
+        let admin_listener = admin::listener_proc(options, state).await?;
+        handles.push(admin_listener.tagged("admin_listener"));
+    }
+
+    let listeners: Vec<_> = handles.iter().map(|tagged| tagged.handle.abort_handle()).collect();
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+    tokio::select! {
+        ((source, error), _, _) = select_all(handles) => {
+            error!(source, %error, "A core task failed");
+        }
+        _ = sigterm.recv() => info!("Got SIGTERM; shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Got SIGINT; shutting down"),
+    }
+    // Stop taking new clients before telling the existing ones to go away
+    for listener in listeners {
+        listener.abort();
+    }
+    sessions.shutdown(&settings.shutdown, audit).await;
 
     Ok(())
 }
@@ -0,0 +1,211 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Prometheus metrics, exported by the HTTP server at `/metrics`
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use d3270_common::b3270::indication::{ConnectionState, Stats};
+use d3270_common::b3270::Indication;
+
+lazy_static! {
+    static ref CLIENTS: IntGaugeVec = register_int_gauge_vec!(
+        "d3270_clients",
+        "Clients currently connected",
+        &["listener"]
+    )
+    .unwrap();
+    static ref INDICATIONS: IntCounterVec = register_int_counter_vec!(
+        "d3270_indications_total",
+        "Indications received from b3270",
+        &["session", "type"]
+    )
+    .unwrap();
+    static ref LAGGED: IntCounterVec = register_int_counter_vec!(
+        "d3270_lagged_indications_total",
+        "Indications that clients fell too far behind to receive",
+        &["session"]
+    )
+    .unwrap();
+    static ref RESYNCS: IntCounterVec = register_int_counter_vec!(
+        "d3270_resyncs_total",
+        "Times that a client had to resync its screen",
+        &["session", "reason"]
+    )
+    .unwrap();
+    static ref PENDING_ACTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "d3270_pending_actions",
+        "Actions sent to b3270 that haven't been answered yet",
+        &["session"]
+    )
+    .unwrap();
+    static ref ACTION_SECONDS: HistogramVec = register_histogram_vec!(
+        "d3270_action_duration_seconds",
+        "Time that b3270 reported taking to run actions",
+        &["session"],
+        vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0]
+    )
+    .unwrap();
+    static ref HOST_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "d3270_host_bytes",
+        "Bytes exchanged with the host on the current connection, from b3270's stats",
+        &["session", "direction"]
+    )
+    .unwrap();
+    static ref HOST_RECORDS: IntGaugeVec = register_int_gauge_vec!(
+        "d3270_host_records",
+        "Records exchanged with the host on the current connection, from b3270's stats",
+        &["session", "direction"]
+    )
+    .unwrap();
+    static ref CONNECTION_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "d3270_connection_state",
+        "Set to 1 for the current state of the host connection",
+        &["session", "state"]
+    )
+    .unwrap();
+}
+
+/// The text exposition format, for `/metrics`
+pub fn render() -> anyhow::Result<String> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Counts a client connected to `listener` for as long as it lives
+pub struct ClientGuard(IntGauge);
+
+impl ClientGuard {
+    pub fn new(listener: &str) -> Self {
+        let gauge = CLIENTS.with_label_values(&[listener]);
+        gauge.inc();
+        ClientGuard(gauge)
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Metrics kept by an [`ArbiterHandle`](crate::arbiter::ArbiterHandle)
+#[derive(Clone)]
+pub struct HandleMetrics {
+    pub lagged: IntCounter,
+    pub lag_resyncs: IntCounter,
+    pub restart_resyncs: IntCounter,
+}
+
+fn state_name(state: ConnectionState) -> String {
+    serde_json::to_value(state)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Metrics kept by the arbiter for one session. They are removed when it goes away.
+pub struct SessionMetrics {
+    session: String,
+    pending_actions: IntGauge,
+    action_seconds: Histogram,
+    state: ConnectionState,
+}
+
+impl SessionMetrics {
+    pub fn new(session: &str) -> Self {
+        let metrics = SessionMetrics {
+            session: session.to_owned(),
+            pending_actions: PENDING_ACTIONS.with_label_values(&[session]),
+            action_seconds: ACTION_SECONDS.with_label_values(&[session]),
+            state: ConnectionState::NotConnected,
+        };
+        metrics.state_gauge().set(1);
+        metrics
+    }
+
+    pub fn handle_metrics(&self) -> HandleMetrics {
+        HandleMetrics {
+            lagged: LAGGED.with_label_values(&[&self.session]),
+            lag_resyncs: RESYNCS.with_label_values(&[&self.session, "lagged"]),
+            restart_resyncs: RESYNCS.with_label_values(&[&self.session, "restart"]),
+        }
+    }
+
+    pub fn set_pending_actions(&self, count: usize) {
+        self.pending_actions.set(count as i64);
+    }
+
+    fn state_gauge(&self) -> IntGauge {
+        CONNECTION_STATE.with_label_values(&[&self.session, &state_name(self.state)])
+    }
+
+    fn remove_state(&self) {
+        CONNECTION_STATE
+            .remove_label_values(&[&self.session, &state_name(self.state)])
+            .ok();
+    }
+
+    fn set_stats(&self, stats: &Stats) {
+        for (gauge, direction, value) in [
+            (&*HOST_BYTES, "received", stats.bytes_received),
+            (&*HOST_BYTES, "sent", stats.bytes_sent),
+            (&*HOST_RECORDS, "received", stats.records_received),
+            (&*HOST_RECORDS, "sent", stats.records_sent),
+        ] {
+            gauge
+                .with_label_values(&[&self.session, direction])
+                .set(value as i64);
+        }
+    }
+
+    /// Account for an indication from b3270
+    pub fn indication(&mut self, indication: &Indication) {
+        INDICATIONS
+            .with_label_values(&[&self.session, indication.name()])
+            .inc();
+        match indication {
+            Indication::Connection(connection) if connection.state != self.state => {
+                self.remove_state();
+                self.state = connection.state;
+                self.state_gauge().set(1);
+            }
+            Indication::RunResult(result) => self.action_seconds.observe(result.time as f64),
+            Indication::Stats(stats) => self.set_stats(stats),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for SessionMetrics {
+    fn drop(&mut self) {
+        let session = self.session.as_str();
+        PENDING_ACTIONS.remove_label_values(&[session]).ok();
+        ACTION_SECONDS.remove_label_values(&[session]).ok();
+        self.remove_state();
+        for direction in ["received", "sent"] {
+            HOST_BYTES.remove_label_values(&[session, direction]).ok();
+            HOST_RECORDS.remove_label_values(&[session, direction]).ok();
+        }
+    }
+}
@@ -0,0 +1,231 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, bail};
+use d3270_common::b3270::operation::Action;
+use serde::Deserialize;
+
+/// Other names b3270 accepts for an action, and the action they stand for
+const ALIASES: &[(&str, &str)] = &[("Open", "Connect"), ("Close", "Disconnect")];
+
+/// Actions that run other actions, from a file, a macro or a child process.
+/// Deny rules can't see those, so these are refused whenever there are any.
+const INDIRECT: &[&str] = &["Execute", "Macro", "Prompt", "Script", "Source"];
+
+fn canonical(name: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, action)| action)
+}
+
+/// Matches an action by name and, optionally, by its arguments.
+///
+/// Written as `Name` or `Name(arg,...)`. Names and arguments are compared
+/// case-insensitively, as b3270 does, and aliases such as `Open` for
+/// `Connect` match the action they stand for. A rule with arguments only matches
+/// actions that have every one of those arguments, in any position; e.g.,
+/// `PrintText(file)` matches `PrintText(html,file,/tmp/x)`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ActionRule {
+    action: String,
+    args: Vec<String>,
+}
+
+impl ActionRule {
+    pub fn matches(&self, action: &Action) -> bool {
+        canonical(&action.action).eq_ignore_ascii_case(canonical(&self.action))
+            && self.args.iter().all(|rule_arg| {
+                action
+                    .args
+                    .iter()
+                    .any(|arg| arg.eq_ignore_ascii_case(rule_arg))
+            })
+    }
+}
+
+impl FromStr for ActionRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (action, args) = match s.split_once('(') {
+            None => (s, vec![]),
+            Some((action, rest)) => {
+                let args = rest
+                    .strip_suffix(')')
+                    .ok_or_else(|| anyhow!("Missing ')' in action rule {s:?}"))?;
+                let args = args
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|arg| !arg.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                (action.trim(), args)
+            }
+        };
+        if action.is_empty() || !action.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_') {
+            bail!("Invalid action name in rule {s:?}");
+        }
+        Ok(Self {
+            action: action.to_owned(),
+            args,
+        })
+    }
+}
+
+impl TryFrom<String> for ActionRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for ActionRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.args.is_empty() {
+            f.write_str(&self.action)
+        } else {
+            write!(f, "{}({})", self.action, self.args.join(","))
+        }
+    }
+}
+
+/// Decides which actions clients may ask b3270 to run.
+///
+/// An action is denied if it matches any deny rule, or if there is an
+/// allowlist and it matches none of its rules. If there are deny rules,
+/// actions that run other actions (see [`INDIRECT`]) are denied too; only an
+/// allowlist can let them through safely. The default policy allows
+/// everything. The rules can be replaced while in use, with [`Self::reload`].
+#[derive(Debug, Default)]
+pub struct ActionPolicy {
+    rules: RwLock<Rules>,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    allow: Option<Vec<ActionRule>>,
+    deny: Vec<ActionRule>,
+}
+
+impl ActionPolicy {
+    pub fn allow(&mut self, rule: ActionRule) {
+        let rules = self.rules.get_mut().unwrap();
+        rules.allow.get_or_insert_with(Vec::new).push(rule);
+    }
+
+    pub fn deny(&mut self, rule: ActionRule) {
+        self.rules.get_mut().unwrap().deny.push(rule);
+    }
+
+    /// Switch to the rules of `other`
+    pub fn reload(&self, other: ActionPolicy) {
+        *self.rules.write().unwrap() = other.rules.into_inner().unwrap();
+    }
+
+    /// Returns the reason the action is not permitted, if it isn't.
+    pub fn check(&self, action: &Action) -> Result<(), String> {
+        let rules = self.rules.read().unwrap();
+        if let Some(rule) = rules.deny.iter().find(|rule| rule.matches(action)) {
+            return Err(format!(
+                "Action {} is denied by policy ({rule})",
+                action.action
+            ));
+        }
+        if !rules.deny.is_empty() && INDIRECT.iter().any(|name| name.eq_ignore_ascii_case(&action.action)) {
+            return Err(format!(
+                "Action {} is denied by policy (it runs actions that can't be checked)",
+                action.action
+            ));
+        }
+        match rules.allow {
+            Some(ref allow) if !allow.iter().any(|rule| rule.matches(action)) => Err(format!(
+                "Action {} is not in the list of allowed actions",
+                action.action
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a whole run operation; one forbidden action fails the lot.
+    pub fn check_all(&self, actions: &[Action]) -> Result<(), String> {
+        actions.iter().try_for_each(|action| self.check(action))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn action(name: &str, args: &[&str]) -> Action {
+        Action {
+            action: name.to_owned(),
+            args: args.iter().copied().map(str::to_owned).collect(),
+        }
+    }
+
+    #[test]
+    fn deny_with_args() {
+        let mut policy = ActionPolicy::default();
+        policy.deny("connect".parse().unwrap());
+        policy.deny("PrintText(file)".parse().unwrap());
+        assert!(policy.check(&action("Connect", &["evil:23"])).is_err());
+        assert!(policy.check(&action("PrintText", &["html", "FILE", "/tmp/x"])).is_err());
+        assert!(policy.check(&action("PrintText", &["printer"])).is_ok());
+        assert!(policy.check(&action("Enter", &[])).is_ok());
+    }
+
+    #[test]
+    fn deny_aliases_and_indirect_actions() {
+        let mut policy = ActionPolicy::default();
+        assert!(policy.check(&action("Source", &["/tmp/actions"])).is_ok());
+        policy.deny("Connect".parse().unwrap());
+        policy.deny("close".parse().unwrap());
+        assert!(policy.check(&action("open", &["evil:23"])).is_err());
+        assert!(policy.check(&action("Disconnect", &[])).is_err());
+        assert!(policy.check(&action("Source", &["/tmp/actions"])).is_err());
+        assert!(policy.check(&action("macro", &["connect"])).is_err());
+        assert!(policy.check(&action("Enter", &[])).is_ok());
+    }
+
+    #[test]
+    fn allowlist() {
+        let mut policy = ActionPolicy::default();
+        policy.allow("Key".parse().unwrap());
+        policy.allow("Enter".parse().unwrap());
+        assert!(policy.check_all(&[action("Key", &["a"]), action("enter", &[])]).is_ok());
+        assert!(policy.check_all(&[action("Key", &["a"]), action("Script", &["sh"])]).is_err());
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!("PrintText(file".parse::<ActionRule>().is_err());
+        assert!("".parse::<ActionRule>().is_err());
+        assert_eq!(
+            " PrintText( html , file ) ".parse::<ActionRule>().unwrap().to_string(),
+            "PrintText(html,file)"
+        );
+    }
+}
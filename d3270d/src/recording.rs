@@ -0,0 +1,235 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Session recordings.
+//!
+//! A recording is a JSON lines file, much like an asciicast. The first line
+//! is a header object; every line after it is an event array of
+//! `[seconds since the start, kind, data]`, where kind is `"i"` for an
+//! indication that was broadcast to clients or `"a"` for actions that a
+//! client ran (`{"user": ..., "actions": [...]}`). Each file starts with a
+//! snapshot of the screen, so it can be replayed on its own.
+
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use d3270_common::b3270::operation::Action;
+use d3270_common::b3270::Indication;
+
+pub const FORMAT_NAME: &str = "d3270-recording";
+
+/// Where and how to record sessions
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    /// Recordings are named `<session>-<start time>.jsonl` within this directory
+    pub dir: PathBuf,
+    /// Start a new file once the current one is this big
+    pub max_size: Option<u64>,
+    /// Start a new file once the current one is this old
+    pub max_age: Option<Duration>,
+    /// Record the actions clients run, as well as what b3270 displays
+    pub actions: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Header {
+    pub version: u32,
+    pub format: String,
+    pub session: String,
+    /// RFC 3339
+    pub started: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientActions {
+    pub user: String,
+    pub actions: Vec<Action>,
+}
+
+/// One line of a recording after the header
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event(pub f64, pub String, pub serde_json::Value);
+
+pub struct Recorder {
+    options: RecordOptions,
+    session: String,
+    file: BufWriter<File>,
+    started: Instant,
+    written: u64,
+    // Whether anything has been recorded since the snapshot
+    recorded: bool,
+}
+
+impl Recorder {
+    /// Start recording with `snapshot`, the state of the screen so far
+    pub fn start(
+        options: RecordOptions,
+        session: &str,
+        snapshot: Vec<Indication>,
+    ) -> anyhow::Result<Self> {
+        let (file, started) = Self::open(&options, session)?;
+        let mut recorder = Recorder {
+            options,
+            session: session.to_owned(),
+            file,
+            started,
+            written: 0,
+            recorded: false,
+        };
+        recorder.write_start(snapshot)?;
+        Ok(recorder)
+    }
+
+    fn open(options: &RecordOptions, session: &str) -> anyhow::Result<(BufWriter<File>, Instant)> {
+        std::fs::create_dir_all(&options.dir).with_context(|| {
+            format!("Failed to create recording directory {}", options.dir.display())
+        })?;
+        let stem = format!("{session}-{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+        let mut path = options.dir.join(format!("{stem}.jsonl"));
+        // Never overwrite an earlier recording, even one from the same millisecond
+        let mut suffix = 0;
+        let file = loop {
+            match File::options().create_new(true).write(true).open(&path) {
+                Err(error) if error.kind() == ErrorKind::AlreadyExists && suffix < 100 => {
+                    suffix += 1;
+                    path = options.dir.join(format!("{stem}-{suffix}.jsonl"));
+                }
+                result => {
+                    break result
+                        .with_context(|| format!("Failed to create recording {}", path.display()))?
+                }
+            }
+        };
+        info!(path = %path.display(), "Started recording");
+        Ok((BufWriter::new(file), Instant::now()))
+    }
+
+    fn write_start(&mut self, snapshot: Vec<Indication>) -> anyhow::Result<()> {
+        let header = Header {
+            version: 1,
+            format: FORMAT_NAME.to_owned(),
+            session: self.session.clone(),
+            started: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        };
+        self.write_line(&header)?;
+        for indication in snapshot {
+            self.write_event("i", serde_json::to_value(indication)?)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, value: &impl Serialize) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn write_event(&mut self, kind: &str, data: serde_json::Value) -> anyhow::Result<()> {
+        // Millisecond resolution is plenty, and keeps the file readable
+        let time = (self.started.elapsed().as_secs_f64() * 1000.0).round() / 1000.0;
+        self.write_line(&Event(time, kind.to_owned(), data))
+    }
+
+    pub fn indication(&mut self, indication: &Indication) -> anyhow::Result<()> {
+        self.recorded = true;
+        self.write_event("i", serde_json::to_value(indication)?)
+    }
+
+    pub fn actions(&mut self, user: &str, actions: &[Action]) -> anyhow::Result<()> {
+        if !self.options.actions {
+            return Ok(());
+        }
+        self.recorded = true;
+        let actions = ClientActions {
+            user: user.to_owned(),
+            actions: actions.to_vec(),
+        };
+        self.write_event("a", serde_json::to_value(actions)?)
+    }
+
+    /// Whether the current file is full or old enough to be replaced. A file
+    /// with nothing but a snapshot in it is never replaced.
+    pub fn needs_rotation(&self) -> bool {
+        self.recorded
+            && (self.options.max_size.is_some_and(|max| self.written >= max)
+                || self.options.max_age.is_some_and(|max| self.started.elapsed() >= max))
+    }
+
+    /// Finish the current file and start a new one from `snapshot`
+    pub fn rotate(&mut self, snapshot: Vec<Indication>) -> anyhow::Result<()> {
+        self.file.flush()?;
+        let (file, started) = Self::open(&self.options, &self.session)?;
+        self.file = file;
+        self.started = started;
+        self.written = 0;
+        self.recorded = false;
+        self.write_start(snapshot)
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotates_only_after_recording_something() {
+        let dir = std::env::temp_dir().join(format!("d3270d-recording-test-{}", std::process::id()));
+        let options = RecordOptions {
+            dir: dir.clone(),
+            max_size: Some(1),
+            max_age: None,
+            actions: false,
+        };
+        let mut recorder = Recorder::start(options, "test", vec![Indication::Bell {}]).unwrap();
+        assert!(!recorder.needs_rotation());
+        recorder.actions("alice", &[]).unwrap();
+        assert!(!recorder.needs_rotation(), "actions aren't being recorded");
+        recorder.indication(&Indication::Formatted { state: true }).unwrap();
+        assert!(recorder.needs_rotation());
+        recorder.rotate(vec![]).unwrap();
+        assert!(!recorder.needs_rotation());
+        recorder.flush().unwrap();
+
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        files.sort_by_key(String::len);
+        assert_eq!(files.len(), 2);
+        let lines = files[1].lines().collect::<Vec<_>>();
+        let header: Header = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(header.format, FORMAT_NAME);
+        let Event(_, kind, data) = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(kind, "i");
+        assert_eq!(data, serde_json::json!({"formatted": {"state": true}}));
+    }
+}
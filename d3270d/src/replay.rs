@@ -0,0 +1,444 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Playing back a [recording](crate::recording) in place of b3270.
+//!
+//! The [`Replayer`] answers the same requests as the [`B3270`](crate::arbiter::B3270)
+//! arbiter, so every kind of client can watch a replay just as it would a
+//! live session. Actions, floor requests and passthru registrations are refused.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context as _};
+use futures::FutureExt;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{Instant, Sleep};
+use tracing::{info, info_span, trace, warn, Instrument};
+
+use d3270_common::b3270::indication::{Popup, PopupType};
+use d3270_common::b3270::Indication;
+use d3270_common::tracker::{Disposition, Tracker};
+
+use crate::arbiter::{
+    cursor_in_hidden_field, departure, failed_run_result, ArbiterHandleRequester, B3270Request,
+};
+use crate::metrics::SessionMetrics;
+use crate::recording::{Event, Header, FORMAT_NAME};
+use crate::screen_wait::ScreenWaits;
+
+const REFUSAL: &str = "This session is a replay of a recording";
+
+/// How to play a recording back
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    pub file: PathBuf,
+    /// How many times faster than real time to play
+    pub speed: f64,
+    /// Wait for a step command before showing each change, rather than
+    /// playing as soon as the first client attaches
+    pub step: bool,
+}
+
+/// What a client can tell a replay to do
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplayCommand {
+    /// Pause, and show the next change
+    Step,
+    Pause,
+    Resume,
+}
+
+impl FromStr for ReplayCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "step" => ReplayCommand::Step,
+            "pause" => ReplayCommand::Pause,
+            "resume" => ReplayCommand::Resume,
+            _ => bail!("Unknown replay command {s:?}; expected step, pause, or resume"),
+        })
+    }
+}
+
+/// Read the indications from a recording, along with when they happened.
+/// Actions are skipped, as there's nothing to show for them.
+pub fn parse(reader: impl BufRead) -> anyhow::Result<VecDeque<(f64, Indication)>> {
+    let mut lines = reader.lines().enumerate();
+    let header = match lines.next() {
+        Some((_, line)) => line?,
+        None => bail!("The recording is empty"),
+    };
+    let header: Header = serde_json::from_str(&header).context("Invalid recording header")?;
+    if header.format != FORMAT_NAME || header.version != 1 {
+        bail!(
+            "Unsupported recording format {} version {}",
+            header.format,
+            header.version
+        );
+    }
+
+    let mut events = VecDeque::new();
+    for (number, line) in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Event(time, kind, data) = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid event on line {}", number + 1))?;
+        match kind.as_str() {
+            "i" => {
+                let indication = serde_json::from_value(data)
+                    .with_context(|| format!("Invalid indication on line {}", number + 1))?;
+                events.push_back((time, indication));
+            }
+            "a" => {}
+            _ => warn!(line = number + 1, kind, "Skipping unknown recording event"),
+        }
+    }
+    Ok(events)
+}
+
+enum Clock {
+    /// Stopped at this point in the recording
+    Paused(f64),
+    /// Was at `from` in the recording at `since`
+    Playing { from: f64, since: Instant },
+}
+
+/// A client watching the replay
+struct Watcher {
+    kick: Option<oneshot::Sender<String>>,
+    gone: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+pub struct Replayer {
+    tracker: Tracker,
+    comm: mpsc::Receiver<B3270Request>,
+    ind_chan: broadcast::Sender<Indication>,
+    events: VecDeque<(f64, Indication)>,
+    speed: f64,
+    clock: Clock,
+    // Start playing once the first client attaches
+    autoplay: bool,
+    timer: Option<Pin<Box<Sleep>>>,
+    metrics: SessionMetrics,
+    input_hidden: watch::Sender<bool>,
+    // Only so that they can be told when the replay is stopped
+    watchers: Vec<Watcher>,
+    // Sent on once every watcher has gone, after a stop request
+    stopping: Option<oneshot::Sender<()>>,
+    waits: ScreenWaits,
+}
+
+impl Replayer {
+    /// Load the recording and start serving it as `session`
+    pub fn spawn(
+        session: &str,
+        options: ReplayOptions,
+    ) -> anyhow::Result<(
+        tokio::task::JoinHandle<anyhow::Error>,
+        ArbiterHandleRequester,
+    )> {
+        let events = Self::load(&options.file)?;
+        info!(
+            session,
+            file = %options.file.display(),
+            events = events.len(),
+            duration = events.back().map_or(0.0, |(time, _)| *time),
+            "Loaded recording"
+        );
+        let (comm_snd, comm) = mpsc::channel(10);
+        let mut replayer = Replayer {
+            tracker: Tracker::default(),
+            comm,
+            ind_chan: broadcast::channel(100).0,
+            events,
+            speed: options.speed,
+            clock: Clock::Paused(0.0),
+            autoplay: !options.step,
+            timer: None,
+            metrics: SessionMetrics::new(session),
+            input_hidden: watch::channel(false).0,
+            watchers: vec![],
+            stopping: None,
+            waits: ScreenWaits::default(),
+        };
+        // The snapshot at the start is there before anybody looks
+        replayer.step();
+        let requester = ArbiterHandleRequester::new(
+            comm_snd,
+            session,
+            replayer.metrics.handle_metrics(),
+            replayer.input_hidden.subscribe(),
+        );
+        Ok((
+            tokio::task::spawn(replayer.instrument(info_span!("replay", session))),
+            requester,
+        ))
+    }
+
+    fn load(path: &Path) -> anyhow::Result<VecDeque<(f64, Indication)>> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        parse(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to read recording {}", path.display()))
+    }
+
+    /// Where playback has got to in the recording
+    fn position(&self) -> f64 {
+        match self.clock {
+            Clock::Paused(position) => position,
+            Clock::Playing { from, since } => from + since.elapsed().as_secs_f64() * self.speed,
+        }
+    }
+
+    fn show(&mut self, mut ind: Indication) {
+        self.metrics.indication(&ind);
+        // Whoever held the floor then isn't one of our clients, whatever
+        // their connection number was
+        if let Indication::Floor(floor) = &mut ind {
+            floor.holder_id = None;
+            floor.mine = false;
+        }
+        if let Disposition::Broadcast = self.tracker.handle_indication(&mut ind) {
+            // It's OK to drop these, as anybody who cares will resync
+            self.ind_chan.send(ind).ok();
+        }
+    }
+
+    /// Show every event up to `position`
+    fn play_until(&mut self, position: f64) {
+        while self.events.front().is_some_and(|(time, _)| *time <= position) {
+            let (_, ind) = self.events.pop_front().unwrap();
+            self.show(ind);
+        }
+        if self.events.is_empty() && matches!(self.clock, Clock::Playing { .. }) {
+            info!("Replay finished");
+            self.clock = Clock::Paused(position);
+        }
+    }
+
+    /// Show the next event, along with everything recorded at the same moment.
+    /// Returns false if there's nothing left.
+    fn step(&mut self) -> bool {
+        let Some(&(time, _)) = self.events.front() else {
+            return false;
+        };
+        self.clock = Clock::Paused(time);
+        self.play_until(time);
+        true
+    }
+
+    fn control(&mut self, command: ReplayCommand) -> Result<(), String> {
+        info!(?command, "Replay command");
+        self.autoplay = false;
+        self.timer = None;
+        match command {
+            ReplayCommand::Step => {
+                if !self.step() {
+                    return Err("The replay is finished".to_owned());
+                }
+            }
+            ReplayCommand::Pause => self.clock = Clock::Paused(self.position()),
+            ReplayCommand::Resume if self.events.is_empty() => {
+                return Err("The replay is finished".to_owned())
+            }
+            ReplayCommand::Resume => {
+                self.clock = Clock::Playing {
+                    from: self.position(),
+                    since: Instant::now(),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Future for Replayer {
+    type Output = anyhow::Error;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        while let Poll::Ready(cmd) = self.comm.poll_recv(cx) {
+            match cmd {
+                None => return Poll::Ready(anyhow!("Nothing can reach the replay any more")),
+                Some(B3270Request::Resync(sender)) => {
+                    if std::mem::take(&mut self.autoplay) {
+                        info!("Starting replay");
+                        let from = self.position();
+                        self.clock = Clock::Playing { from, since: Instant::now() };
+                    }
+                    // it's OK for this to fail; we just don't get a new client
+                    sender
+                        .send((self.tracker.get_init_indication(), self.ind_chan.subscribe()))
+                        .ok();
+                }
+                Some(
+                    B3270Request::Floor(_, reply)
+                    | B3270Request::Register(_, reply)
+                    | B3270Request::Complete(_, _, reply),
+                ) => {
+                    reply.send(Err(REFUSAL.to_owned())).ok();
+                }
+                Some(
+                    B3270Request::Action(_, _, actions, response_chan)
+                    | B3270Request::AdminAction(actions, response_chan),
+                ) => {
+                    trace!(?actions, "Refusing actions");
+                    response_chan
+                        .send(failed_run_result(format!("{REFUSAL}; actions can't be run")))
+                        .ok();
+                }
+                // A replay's roster is whatever was recorded
+                Some(B3270Request::Join(_, presence, kick)) => self.watchers.push(Watcher {
+                    kick: Some(kick),
+                    gone: departure(presence),
+                }),
+                Some(B3270Request::Stop(reason, stopped)) => {
+                    info!(reason, "Stopping replay");
+                    for watcher in &mut self.watchers {
+                        if let Some(kick) = watcher.kick.take() {
+                            kick.send(reason.clone()).ok();
+                        }
+                    }
+                    self.stopping = Some(stopped);
+                }
+                Some(B3270Request::Replay(command, reply)) => {
+                    let result = self.control(command);
+                    reply.send(result).ok();
+                }
+                Some(B3270Request::Kick(_, reply) | B3270Request::Rotate(reply)) => {
+                    reply.send(Err(REFUSAL.to_owned())).ok();
+                }
+                Some(B3270Request::WaitFor(wait, client)) => {
+                    let this = &mut *self;
+                    this.waits.add(wait, client, &this.tracker);
+                }
+                Some(B3270Request::Popup(text)) => {
+                    // Not part of the recording, so the tracker needn't see it
+                    let popup = Popup { type_: PopupType::Info, text, error: None };
+                    self.ind_chan.send(Indication::Popup(popup)).ok();
+                }
+            }
+        }
+
+        // Catch up with the clock, then sleep until the next event is due
+        while let Clock::Playing { from, since } = self.clock {
+            let position = self.position();
+            self.play_until(position);
+            let Some(&(next, _)) = self.events.front() else {
+                break;
+            };
+            let delay = match Duration::try_from_secs_f64(((next - from) / self.speed).max(0.0)) {
+                Ok(delay) => delay,
+                Err(error) => {
+                    return Poll::Ready(anyhow!("Can't wait for the event at {next}s in the recording: {error}"))
+                }
+            };
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(since)));
+            timer.as_mut().reset(since + delay);
+            if timer.poll_unpin(cx).is_pending() {
+                break;
+            }
+        }
+
+        let this = &mut *self;
+        this.waits.poll(&this.tracker, cx);
+
+        self.watchers
+            .retain_mut(|watcher| watcher.gone.poll_unpin(cx).is_pending());
+        if self.watchers.is_empty() {
+            if let Some(stopped) = self.stopping.take() {
+                stopped.send(()).ok();
+            }
+        }
+
+        let hidden = cursor_in_hidden_field(&self.tracker);
+        self.input_hidden
+            .send_if_modified(|old| std::mem::replace(old, hidden) != hidden);
+
+        // A replay carries on until it is destroyed
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_recording() {
+        let recording = concat!(
+            r#"{"version":1,"format":"d3270-recording","session":"default","started":"2023-01-01T00:00:00.000Z"}"#,
+            "\n",
+            r#"[0.0,"i",{"formatted":{"state":true}}]"#,
+            "\n",
+            r#"[1.5,"a",{"user":"alice","actions":[{"action":"Enter"}]}]"#,
+            "\n",
+            r#"[1.75,"i",{"bell":{}}]"#,
+            "\n",
+        );
+        let events = parse(recording.as_bytes()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].0, 1.75);
+        assert!(matches!(events[1].1, Indication::Bell {}));
+
+        let error = parse(r#"{"version":1,"format":"asciicast","session":"","started":""}"#.as_bytes())
+            .unwrap_err();
+        assert!(error.to_string().contains("asciicast"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn recorded_floor_and_far_off_events() {
+        let file = std::env::temp_dir().join(format!("d3270d-replay-{}.rec", std::process::id()));
+        let recording = concat!(
+            r#"{"version":1,"format":"d3270-recording","session":"default","started":"2023-01-01T00:00:00.000Z"}"#,
+            "\n",
+            r#"[0.0,"i",{"floor":{"holder":"bob","holder-id":1,"mine":true}}]"#,
+            "\n",
+            r#"[1e300,"i",{"bell":{}}]"#,
+            "\n",
+        );
+        std::fs::write(&file, recording).unwrap();
+        let options = ReplayOptions {
+            file: file.clone(),
+            speed: 1.0,
+            step: false,
+        };
+        let (replay, requester) = Replayer::spawn("replay", options).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        // Connection numbers from the recording mean nothing now
+        let floor = requester.snapshot().await.unwrap().get_floor().cloned().unwrap();
+        assert_eq!(floor.holder.as_deref(), Some("bob"));
+        assert_eq!((floor.holder_id, floor.mine), (None, false));
+
+        // Looking started it playing, and it can't wait that long
+        let error = tokio::time::timeout(Duration::from_secs(10), replay).await.unwrap().unwrap();
+        assert!(error.to_string().contains("Can't wait"), "{error}");
+    }
+}
@@ -0,0 +1,259 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Clients' wait-for operations, which the arbiter checks against its
+//! tracker whenever the screen changes.
+
+use std::pin::Pin;
+use std::task::Context;
+use std::time::Duration;
+
+use futures::FutureExt;
+use regex::Regex;
+use tokio::sync::mpsc;
+use tokio::time::Sleep;
+use tracing::debug;
+
+use d3270_common::b3270::indication::{ConnectionState, WaitResult};
+use d3270_common::b3270::operation::{WaitCondition, WaitFor};
+use d3270_common::b3270::types::{GraphicRendition, PackedAttr};
+use d3270_common::b3270::Indication;
+use d3270_common::tracker::Tracker;
+
+/// A [`WaitCondition`], ready to check
+enum Condition {
+    // 0-based
+    Text { row: usize, column: usize, text: Vec<char> },
+    Regex(Regex),
+    Unlocked,
+    InputField,
+    Connection(ConnectionState),
+}
+
+impl TryFrom<WaitCondition> for Condition {
+    type Error = String;
+
+    fn try_from(condition: WaitCondition) -> Result<Self, Self::Error> {
+        Ok(match condition {
+            WaitCondition::Text { row: 0, .. } | WaitCondition::Text { column: 0, .. } => {
+                return Err("Rows and columns start at 1".to_owned())
+            }
+            WaitCondition::Text { text, .. } if text.is_empty() => return Err("No text to wait for".to_owned()),
+            WaitCondition::Text { row, column, text } => Condition::Text {
+                row: usize::from(row) - 1,
+                column: usize::from(column) - 1,
+                text: text.chars().collect(),
+            },
+            WaitCondition::Regex(pattern) => {
+                Condition::Regex(Regex::new(&pattern).map_err(|error| format!("Invalid regex: {error}"))?)
+            }
+            WaitCondition::Unlocked => Condition::Unlocked,
+            WaitCondition::InputField => Condition::InputField,
+            WaitCondition::Connection(state) => Condition::Connection(state),
+        })
+    }
+}
+
+/// Where a condition matched, if it is about text
+type Match = Option<(usize, usize)>;
+
+impl Condition {
+    fn check(&self, tracker: &Tracker) -> Option<Match> {
+        let unlocked = tracker.get_oia_state().lock.is_none();
+        match self {
+            Condition::Text { row, column, text } => {
+                let cells = tracker.get_screen().get(*row)?.get(*column..*column + text.len())?;
+                cells
+                    .iter()
+                    .map(|cell| cell.ch)
+                    .eq(text.iter().copied())
+                    .then_some(Some((*row, *column)))
+            }
+            Condition::Regex(regex) => {
+                let text = screen_rows(tracker).join("\n");
+                let found = regex.find(&text)?;
+                let before = &text[..found.start()];
+                let row = before.matches('\n').count();
+                let column = before.rsplit('\n').next().unwrap_or_default().chars().count();
+                Some(Some((row, column)))
+            }
+            Condition::Unlocked => unlocked.then_some(None),
+            // b3270 doesn't say which fields are protected, so this is as close
+            // as we can get
+            Condition::InputField => (unlocked
+                && tracker.get_formatted()
+                && tracker
+                    .get_cursor_cell()
+                    .is_some_and(|cell| !cell.attr.c_gr().contains(GraphicRendition::ORDER)))
+            .then_some(None),
+            Condition::Connection(state) => (tracker.get_connection().state == *state).then_some(None),
+        }
+    }
+}
+
+fn screen_rows(tracker: &Tracker) -> Vec<String> {
+    tracker
+        .get_screen()
+        .iter()
+        .map(|row| row.iter().map(|cell| cell.ch).collect())
+        .collect()
+}
+
+fn result(w_tag: Option<String>, outcome: Result<Match, String>, tracker: &Tracker) -> Indication {
+    let (success, text, found) = match outcome {
+        Ok(found) => (true, None, found),
+        Err(text) => (false, Some(text), None),
+    };
+    Indication::WaitResult(WaitResult {
+        w_tag,
+        success,
+        text,
+        row: found.map(|(row, _)| row as u8 + 1),
+        column: found.map(|(_, column)| column as u8 + 1),
+        screen: screen_rows(tracker),
+        cursor: *tracker.get_cursor(),
+    })
+}
+
+struct ScreenWait {
+    w_tag: Option<String>,
+    condition: Condition,
+    deadline: Option<Pin<Box<Sleep>>>,
+    // The client's direct channel, which the result goes to
+    client: mpsc::UnboundedSender<Indication>,
+}
+
+/// Every wait that hasn't been answered yet
+#[derive(Default)]
+pub(crate) struct ScreenWaits {
+    waits: Vec<ScreenWait>,
+}
+
+impl ScreenWaits {
+    /// Start waiting. Bad conditions are answered at once; the rest are
+    /// answered by [`Self::poll`], which may be straight away.
+    pub fn add(&mut self, wait: WaitFor, client: mpsc::UnboundedSender<Indication>, tracker: &Tracker) {
+        let deadline = match wait.timeout.map(Duration::try_from_secs_f64).transpose() {
+            Ok(timeout) => timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            Err(error) => {
+                client
+                    .send(result(wait.w_tag, Err(format!("Invalid timeout: {error}")), tracker))
+                    .ok();
+                return;
+            }
+        };
+        match Condition::try_from(wait.condition) {
+            Ok(condition) => self.waits.push(ScreenWait {
+                w_tag: wait.w_tag,
+                condition,
+                deadline,
+                client,
+            }),
+            Err(error) => {
+                client.send(result(wait.w_tag, Err(error), tracker)).ok();
+            }
+        }
+    }
+
+    /// Answer the waits whose conditions now hold or whose time is up, and
+    /// forget those whose clients have gone
+    pub fn poll(&mut self, tracker: &Tracker, cx: &mut Context<'_>) {
+        self.waits.retain_mut(|wait| {
+            if wait.client.is_closed() {
+                return false;
+            }
+            let outcome = match wait.condition.check(tracker) {
+                Some(found) => Ok(found),
+                None if wait.deadline.as_mut().is_some_and(|deadline| deadline.poll_unpin(cx).is_ready()) => {
+                    Err("Timed out".to_owned())
+                }
+                None => return true,
+            };
+            debug!(w_tag = wait.w_tag, success = outcome.is_ok(), "Wait finished");
+            wait.client.send(result(wait.w_tag.take(), outcome, tracker)).ok();
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use d3270_common::b3270::indication::{Change, CountOrText, Cursor, Erase, Row, Screen};
+
+    fn check(condition: WaitCondition, tracker: &Tracker) -> Option<Match> {
+        Condition::try_from(condition).unwrap().check(tracker)
+    }
+
+    #[test]
+    fn conditions() {
+        let mut tracker = Tracker::default();
+        for mut ind in [
+            Indication::Erase(Erase {
+                logical_rows: Some(3),
+                logical_cols: Some(10),
+                fg: None,
+                bg: None,
+            }),
+            Indication::Screen(Screen {
+                cursor: Some(Cursor {
+                    enabled: true,
+                    row: Some(2),
+                    column: Some(1),
+                }),
+                rows: vec![Row {
+                    row: 2,
+                    changes: vec![Change {
+                        column: 3,
+                        change: CountOrText::Text("READY".to_owned()),
+                        fg: None,
+                        bg: None,
+                        gr: None,
+                    }],
+                }],
+            }),
+        ] {
+            tracker.handle_indication(&mut ind);
+        }
+
+        let text = |row, column, text: &str| WaitCondition::Text {
+            row,
+            column,
+            text: text.to_owned(),
+        };
+        assert_eq!(check(text(2, 3, "READY"), &tracker), Some(Some((1, 2))));
+        assert_eq!(check(text(2, 4, "READY"), &tracker), None);
+        assert_eq!(check(text(2, 8, "READY"), &tracker), None);
+        assert_eq!(check(text(9, 1, "READY"), &tracker), None);
+        assert_eq!(check(WaitCondition::Regex("RE+A".to_owned()), &tracker), Some(Some((1, 2))));
+        assert_eq!(check(WaitCondition::Regex("(?m)^  R".to_owned()), &tracker), Some(Some((1, 0))));
+        assert_eq!(check(WaitCondition::Regex("READY\\s+X".to_owned()), &tracker), None);
+        assert_eq!(check(WaitCondition::Unlocked, &tracker), Some(None));
+        // Default trackers are unformatted
+        assert_eq!(check(WaitCondition::InputField, &tracker), None);
+        assert_eq!(
+            check(WaitCondition::Connection(ConnectionState::NotConnected), &tracker),
+            Some(None)
+        );
+
+        assert!(Condition::try_from(text(0, 1, "x")).is_err());
+        assert!(Condition::try_from(text(1, 1, "")).is_err());
+        assert!(Condition::try_from(WaitCondition::Regex("(".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! The s3270-compatible script port (`-scriptport`).
+//!
+//! Each line from the client is one or more actions in x3270's own syntax,
+//! e.g. `String("hello") Enter()`. They are run like any other client's
+//! actions, and answered the way s3270 answers them: the result text as
+//! `data:` lines, a status line, and then `ok` or `error`.
+
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::never::Never;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, instrument, Instrument};
+
+use d3270_common::b3270::indication::ConnectionState;
+use d3270_common::b3270::operation::Action;
+use d3270_common::b3270::types::{GraphicRendition, PackedAttr};
+use d3270_common::tracker::Tracker;
+
+use crate::audit::{AuditLog, Origin};
+use crate::auth::Authenticator;
+use crate::gen_connection::{GenConnection, TrackedConnection};
+use crate::metrics::ClientGuard;
+use crate::policy::ActionPolicy;
+use crate::session::SessionRegistry;
+
+#[instrument(skip(sessions, auth, policy, audit))]
+pub async fn listener_proc(
+    socket: SocketAddr,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<JoinHandle<anyhow::Error>> {
+    let listener = match TcpListener::bind(socket).await {
+        Err(error) => {
+            error!(?socket, ?error, "Failed to bind");
+            return Err(error.into());
+        }
+        Ok(listener) => listener,
+    };
+    let span = info_span!(target: "connection-handling", "script_listener", addr=%socket);
+    info!("Script port starting");
+    Ok(tokio::spawn(
+        async move {
+            let error = listener_task(listener, sessions, auth, policy, audit).await.unwrap_err();
+            error!(%error, "Script port failed to accept");
+            error
+        }
+        .instrument(span),
+    ))
+}
+
+async fn listener_task(
+    listener: TcpListener,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<Never> {
+    loop {
+        let (conn, client_addr) = listener.accept().await?;
+        let sessions = sessions.clone();
+        let auth = auth.clone();
+        let policy = policy.clone();
+        let audit = audit.clone();
+        let origin = Origin {
+            listener: "script",
+            address: Some(client_addr.to_string()),
+        };
+        let conn_span =
+            info_span!(target: "connection-handling", "script_accept", client=%client_addr);
+        tokio::spawn(
+            async move {
+                info!("Accepted connection");
+                if let Err(error) = handle_script_connection(conn, sessions, &auth, policy, audit, origin).await {
+                    error!(%error, "Connection handler failed");
+                } else {
+                    info!("Connection closed");
+                }
+            }
+            .instrument(conn_span),
+        );
+    }
+}
+
+/// Split a line into actions. Arguments may be separated by commas or
+/// spaces, and quoted; inside quotes, `\"` is a quote and every other
+/// backslash is left for the action to interpret.
+pub fn parse_actions(line: &str) -> Result<Vec<Action>, String> {
+    let mut actions = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(actions);
+        };
+        let mut action = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-') {
+            action.push(c);
+        }
+        if action.is_empty() {
+            return Err(format!("Syntax error: expected an action name, found '{first}'"));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut args = vec![];
+        if chars.next_if_eq(&'(').is_some() {
+            loop {
+                while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+                match chars.next() {
+                    None => return Err(format!("Syntax error: missing ')' after {action}")),
+                    Some(')') => break,
+                    Some('"') => {
+                        let mut arg = String::new();
+                        loop {
+                            match chars.next() {
+                                None => return Err(format!("Syntax error: missing '\"' in {action}")),
+                                Some('"') => break,
+                                Some('\\') => match chars.next() {
+                                    Some('"') => arg.push('"'),
+                                    Some(c) => {
+                                        arg.push('\\');
+                                        arg.push(c);
+                                    }
+                                    None => arg.push('\\'),
+                                },
+                                Some(c) => arg.push(c),
+                            }
+                        }
+                        args.push(arg);
+                    }
+                    Some(c) => {
+                        let mut arg = String::from(c);
+                        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ',' && *c != ')') {
+                            arg.push(c);
+                        }
+                        args.push(arg);
+                    }
+                }
+            }
+        }
+        actions.push(Action { action, args });
+    }
+}
+
+/// The twelve fields that s3270 prints after every command
+pub fn status_line(tracker: &Tracker, time: Option<f32>) -> String {
+    use ConnectionState::*;
+
+    let keyboard = match &tracker.get_oia_state().lock {
+        None => 'U',
+        Some(lock) if lock.starts_with("oerr") => 'E',
+        Some(_) => 'L',
+    };
+    let formatted = tracker.get_formatted();
+    // b3270 doesn't say which fields are protected; the attribute bytes are
+    // the only cells we know to be
+    let protected = formatted
+        && tracker
+            .get_cursor_cell()
+            .is_some_and(|cell| cell.attr.c_gr().contains(GraphicRendition::ORDER));
+    let connection = tracker.get_connection();
+    let connected = !matches!(
+        connection.state,
+        NotConnected | Reconnecting | Resolving | TcpPending | TlsPending
+    );
+    let host = match (&connection.host, connected) {
+        (Some(host), true) => format!("C({host})"),
+        (None, true) => "C()".to_owned(),
+        (_, false) => "N".to_owned(),
+    };
+    let mode = match connection.state {
+        ConnectedNvt | ConnectedENvt => 'L',
+        ConnectedNvtCharmode => 'C',
+        Connected3270 | ConnectedTn3270e | ConnectedSscp => 'I',
+        _ if connected => 'P',
+        _ => 'N',
+    };
+    let screen_mode = tracker.get_screen_mode();
+    let cursor = tracker.get_cursor();
+    let mut line = format!(
+        "{keyboard} {} {} {host} {mode} {} {} {} {} {} 0x0 ",
+        if formatted { 'F' } else { 'U' },
+        if protected { 'P' } else { 'U' },
+        screen_mode.model,
+        screen_mode.rows,
+        screen_mode.columns,
+        cursor.row.unwrap_or(1).saturating_sub(1),
+        cursor.column.unwrap_or(1).saturating_sub(1),
+    );
+    match time {
+        Some(time) => write!(line, "{time:.3}").unwrap(),
+        None => line.push('-'),
+    }
+    line
+}
+
+async fn respond<W: AsyncWrite + Unpin>(
+    stream_wr: &mut W,
+    text: &[String],
+    tracker: &Tracker,
+    time: Option<f32>,
+    success: bool,
+) -> anyhow::Result<()> {
+    let mut response = String::new();
+    for line in text.iter().flat_map(|text| text.lines()) {
+        writeln!(response, "data: {line}").unwrap();
+    }
+    writeln!(response, "{}", status_line(tracker, time)).unwrap();
+    response.push_str(if success { "ok\n" } else { "error\n" });
+    stream_wr.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// An attached script, and what it knows of the screen
+struct ScriptConnection {
+    conn: TrackedConnection,
+}
+
+impl ScriptConnection {
+    /// Carry out one line from the script. Returns false if the script is done.
+    async fn command<W: AsyncWrite + Unpin>(&mut self, line: &str, stream_wr: &mut W) -> anyhow::Result<bool> {
+        let actions = match parse_actions(line) {
+            Ok(actions) => actions,
+            Err(error) => {
+                respond(stream_wr, &[error], self.conn.tracker(), None, false).await?;
+                return Ok(true);
+            }
+        };
+        // These would end b3270, and with it everybody's session
+        if actions.iter().any(|action| {
+            action.action.eq_ignore_ascii_case("Quit") || action.action.eq_ignore_ascii_case("Exit")
+        }) {
+            return Ok(false);
+        }
+        if actions.is_empty() {
+            respond(stream_wr, &[], self.conn.tracker(), None, true).await?;
+            return Ok(true);
+        }
+        let result = self.conn.run(actions).await?;
+        respond(stream_wr, &result.text, self.conn.tracker(), Some(result.time), result.success).await?;
+        Ok(true)
+    }
+}
+
+async fn handle_script_connection(
+    conn: TcpStream,
+    sessions: SessionRegistry,
+    auth: &Authenticator,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+    origin: Origin,
+) -> anyhow::Result<()> {
+    let (stream_rd, mut stream_wr) = conn.into_split();
+    let mut stream_rd = BufReader::new(stream_rd).lines();
+
+    // s3270 has no notion of logging in, so a script that needs a token (or
+    // wants another session) starts with Attach(token[, session]). Otherwise
+    // its first line is an ordinary command.
+    let Some(first) = stream_rd.next_line().await? else {
+        return Ok(());
+    };
+    let (token, session, pending_line) = match parse_actions(&first).as_deref() {
+        Ok([Action { action, args }]) if action.eq_ignore_ascii_case("Attach") => {
+            (args.first().cloned(), args.get(1).cloned(), None)
+        }
+        _ => (None, None, Some(first)),
+    };
+    let attached = auth
+        .authenticate(token.as_deref())
+        .and_then(|identity| Ok((sessions.get(session.as_deref())?, identity)));
+    let (requester, identity) = match attached {
+        Ok(attached) => attached,
+        Err(error) => {
+            respond(&mut stream_wr, &[error.to_string()], &Tracker::default(), None, false).await?;
+            stream_wr.shutdown().await?;
+            return Err(error);
+        }
+    };
+    info!(user = %identity, session = requester.session(), "Script authenticated");
+
+    let conn = GenConnection::new(requester, &identity, policy, false, audit, origin).await?;
+    let _client = ClientGuard::new("script");
+    let mut script = ScriptConnection {
+        conn: TrackedConnection::new(conn, "script"),
+    };
+    match pending_line {
+        Some(line) => {
+            if !script.command(&line, &mut stream_wr).await? {
+                return Ok(());
+            }
+        }
+        None => respond(&mut stream_wr, &[], script.conn.tracker(), None, true).await?,
+    }
+
+    loop {
+        select! {
+            line = stream_rd.next_line() => match line? {
+                Some(line) => {
+                    if !script.command(&line, &mut stream_wr).await? {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+            updated = script.conn.update() => updated?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn action(name: &str, args: &[&str]) -> Action {
+        Action {
+            action: name.to_owned(),
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_script_lines() {
+        assert_eq!(parse_actions("Ascii()"), Ok(vec![action("Ascii", &[])]));
+        assert_eq!(
+            parse_actions(r#"  String("say \"hi\"\n") Enter Wait(30, InputField)"#),
+            Ok(vec![
+                action("String", &[r#"say "hi"\n"#]),
+                action("Enter", &[]),
+                action("Wait", &["30", "InputField"]),
+            ])
+        );
+        assert_eq!(parse_actions("Ascii(0 0 5)"), Ok(vec![action("Ascii", &["0", "0", "5"])]));
+        assert_eq!(parse_actions(""), Ok(vec![]));
+        assert!(parse_actions("String(\"oops)").is_err());
+        assert!(parse_actions("Enter(").is_err());
+        assert!(parse_actions("(1)").is_err());
+    }
+}
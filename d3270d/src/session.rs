@@ -0,0 +1,331 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use futures::future::join_all;
+use tokio::runtime::Handle;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use d3270_common::admin::SessionInfo;
+use d3270_common::b3270::operation::Action;
+
+use crate::arbiter::{ArbiterHandleRequester, RestartPolicy, B3270};
+use crate::audit::{AuditLog, AuditTrail, Origin};
+use crate::auth::Identity;
+use crate::recording::RecordOptions;
+use crate::replay::{ReplayOptions, Replayer};
+
+/// Name of the session created by `-connect`
+pub const DEFAULT_SESSION: &str = "default";
+
+/// What clients are told when d3270d shuts down
+const SHUTDOWN_NOTICE: &str = "d3270d is shutting down";
+
+/// How d3270d winds sessions down when it is asked to stop
+#[derive(Clone, Debug)]
+pub struct ShutdownOptions {
+    /// Run `Disconnect` and wait for it, so that the host sees a clean logoff
+    pub disconnect: bool,
+    /// The longest to wait for each step before moving on
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        Self {
+            disconnect: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Session {
+    connect: String,
+    requester: ArbiterHandleRequester,
+    arbiter: AbortHandle,
+    // Distinguishes this session from a later one with the same name
+    generation: u64,
+}
+
+struct RegistryInner {
+    sessions: HashMap<String, Session>,
+    default: Option<String>,
+    next_generation: u64,
+}
+
+/// Every b3270 session that this d3270d is running, by name.
+///
+/// Each session has its own b3270 child and arbiter (and therefore its own
+/// tracker). Sessions go away when they are destroyed or when their b3270
+/// exits more often than the restart policy allows.
+#[derive(Clone)]
+pub struct SessionRegistry {
+    b3270_program: Arc<PathBuf>,
+    b3270_args: Arc<Vec<OsString>>,
+    restart_policy: RestartPolicy,
+    recording: Option<RecordOptions>,
+    // Sessions may be created from the HTTP server, which runs outside of tokio
+    runtime: Handle,
+    inner: Arc<Mutex<RegistryInner>>,
+}
+
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "-_.".contains(ch))
+    {
+        bail!("Invalid session name {name:?}");
+    }
+    Ok(())
+}
+
+impl SessionRegistry {
+    /// `b3270_args` are passed to every b3270 that is started. Every session
+    /// is recorded if `recording` is given. Must be called from within the
+    /// tokio runtime.
+    pub fn new(
+        b3270_program: PathBuf,
+        b3270_args: Vec<OsString>,
+        restart_policy: RestartPolicy,
+        recording: Option<RecordOptions>,
+    ) -> Self {
+        Self {
+            b3270_program: Arc::new(b3270_program),
+            b3270_args: Arc::new(b3270_args),
+            restart_policy,
+            recording,
+            runtime: Handle::current(),
+            inner: Arc::new(Mutex::new(RegistryInner {
+                sessions: HashMap::new(),
+                default: None,
+                next_generation: 0,
+            })),
+        }
+    }
+
+    /// The tokio runtime that the sessions run on. The HTTP server runs
+    /// on async-std, so it needs this to set timers.
+    pub fn runtime(&self) -> &Handle {
+        &self.runtime
+    }
+
+    /// Start a new b3270 and connect it to `connect`. The first session
+    /// created becomes the default one.
+    pub fn create(&self, name: &str, connect: &str) -> anyhow::Result<ArbiterHandleRequester> {
+        validate_name(name)?;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sessions.contains_key(name) {
+            bail!("Session {name} already exists");
+        }
+
+        let _runtime = self.runtime.enter();
+        info!(session = name, program = ?self.b3270_program, args = ?self.b3270_args, "Starting b3270");
+        let b3270_program = self.b3270_program.clone();
+        let b3270_args = self.b3270_args.clone();
+        let spawn_child = move || {
+            tokio::process::Command::new(&*b3270_program)
+                .args(b3270_args.iter())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+        };
+        let (arbiter, requester) = B3270::spawn(
+            name,
+            Box::new(spawn_child),
+            &[Action {
+                action: "Connect".to_owned(),
+                args: vec![connect.to_owned()],
+            }],
+            self.restart_policy.clone(),
+            self.recording.clone(),
+        )?;
+        self.register(&mut inner, name, connect, arbiter, requester.clone());
+        Ok(requester)
+    }
+
+    /// Play back a recording as a session, in place of b3270
+    pub fn replay(&self, name: &str, options: ReplayOptions) -> anyhow::Result<ArbiterHandleRequester> {
+        validate_name(name)?;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sessions.contains_key(name) {
+            bail!("Session {name} already exists");
+        }
+
+        let _runtime = self.runtime.enter();
+        let connect = format!("replay:{}", options.file.display());
+        let (arbiter, requester) = Replayer::spawn(name, options)?;
+        self.register(&mut inner, name, &connect, arbiter, requester.clone());
+        Ok(requester)
+    }
+
+    fn register(
+        &self,
+        inner: &mut RegistryInner,
+        name: &str,
+        connect: &str,
+        arbiter: JoinHandle<anyhow::Error>,
+        requester: ArbiterHandleRequester,
+    ) {
+        let generation = inner.next_generation;
+        inner.next_generation += 1;
+        inner.sessions.insert(
+            name.to_owned(),
+            Session {
+                connect: connect.to_owned(),
+                requester,
+                arbiter: arbiter.abort_handle(),
+                generation,
+            },
+        );
+        if inner.default.is_none() {
+            inner.default = Some(name.to_owned());
+        }
+
+        // Forget about the session once its arbiter goes away
+        let registry = self.clone();
+        tokio::spawn(
+            async move {
+                match arbiter.await {
+                    Ok(error) => error!(%error, "Session ended"),
+                    Err(error) if error.is_cancelled() => info!("Session destroyed"),
+                    Err(error) => error!(%error, "Session arbiter panicked"),
+                }
+                registry.forget(generation);
+            }
+            .instrument(info_span!("session", name)),
+        );
+    }
+
+    fn forget(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .sessions
+            .retain(|_, session| session.generation != generation);
+    }
+
+    /// Stop a session. Its b3270 is killed and all of its clients are disconnected.
+    pub fn destroy(&self, name: &str) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let session = inner
+            .sessions
+            .remove(name)
+            .ok_or_else(|| anyhow!("No such session {name}"))?;
+        // Clients that don't name a session are told there's no default,
+        // rather than that a session they never asked for doesn't exist
+        if inner.default.as_deref() == Some(name) {
+            inner.default = None;
+        }
+        session.arbiter.abort();
+        Ok(())
+    }
+
+    /// Find a session by name, or the default session if no name is given
+    pub fn get(&self, name: Option<&str>) -> anyhow::Result<ArbiterHandleRequester> {
+        let inner = self.inner.lock().unwrap();
+        let name = name
+            .or(inner.default.as_deref())
+            .ok_or_else(|| anyhow!("No session given and there is no default session"))?;
+        inner
+            .sessions
+            .get(name)
+            .map(|session| session.requester.clone())
+            .ok_or_else(|| anyhow!("No such session {name}"))
+    }
+
+    pub fn set_default(&self, name: &str) {
+        self.inner.lock().unwrap().default = Some(name.to_owned());
+    }
+
+    /// Stop every session, all at once. Each session's clients are told and
+    /// disconnected, and its b3270 is given the chance to exit by itself.
+    /// The `Disconnect` of `-shutdown-disconnect` goes into the audit log.
+    pub async fn shutdown(&self, options: &ShutdownOptions, audit: Option<Arc<AuditLog>>) {
+        let sessions = self.list().into_iter().map(|info| {
+            self.shutdown_session(info.name.clone(), options, audit.clone())
+                .instrument(info_span!("session", name = info.name))
+        });
+        join_all(sessions).await;
+    }
+
+    async fn shutdown_session(&self, name: String, options: &ShutdownOptions, audit: Option<Arc<AuditLog>>) {
+        let Ok(requester) = self.get(Some(&name)) else {
+            return;
+        };
+        if options.disconnect {
+            let disconnect = Action {
+                action: "Disconnect".to_owned(),
+                args: vec![],
+            };
+            let identity = Identity {
+                name: "d3270d".to_owned(),
+            };
+            let origin = Origin {
+                listener: "shutdown",
+                address: None,
+            };
+            // Left unfinished, and so logged as abandoned, if it times out
+            let audit = AuditTrail::new(audit, &identity, origin, &name).begin(std::slice::from_ref(&disconnect), false);
+            match tokio::time::timeout(options.timeout, requester.admin_actions(vec![disconnect])).await {
+                Ok(Ok(result)) => {
+                    if let Some(audit) = audit {
+                        audit.finish(&result);
+                    }
+                    if result.success {
+                        info!("Disconnected from the host");
+                    } else {
+                        warn!(text = ?result.text, "Failed to disconnect from the host");
+                    }
+                }
+                Ok(Err(error)) => warn!(%error, "Failed to disconnect from the host"),
+                Err(_) => warn!("Timed out disconnecting from the host"),
+            }
+        }
+        match tokio::time::timeout(options.timeout, requester.stop(SHUTDOWN_NOTICE)).await {
+            Ok(Ok(())) => info!("Session stopped"),
+            Ok(Err(error)) => warn!(%error, "Session went away while stopping"),
+            Err(_) => warn!("Timed out stopping the session; killing b3270"),
+        }
+        // This kills b3270 if it's still around
+        self.destroy(&name).ok();
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut sessions = inner
+            .sessions
+            .iter()
+            .map(|(name, session)| SessionInfo {
+                name: name.clone(),
+                connect: session.connect.clone(),
+                default: inner.default.as_deref() == Some(name.as_str()),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        sessions
+    }
+}
@@ -0,0 +1,172 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Renderings of a [`Tracker`]'s screen for clients that don't speak the b3270 protocol
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::Serialize;
+
+use d3270_common::b3270::indication::{Connection, Cursor, OiaField};
+use d3270_common::b3270::types::{Color, GraphicRendition, PackedAttr};
+use d3270_common::tracker::{CharCell, Tracker};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Ansi,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "ansi" => Format::Ansi,
+            _ => bail!("Unknown screen format {s:?}; expected text, json, or ansi"),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Cell {
+    ch: char,
+    fg: Color,
+    bg: Color,
+    gr: GraphicRendition,
+}
+
+impl From<&CharCell> for Cell {
+    fn from(cell: &CharCell) -> Self {
+        Cell {
+            ch: cell.ch,
+            fg: cell.attr.c_fg(),
+            bg: cell.attr.c_bg(),
+            gr: cell.attr.c_gr(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ScreenJson<'a> {
+    rows: Vec<Vec<Cell>>,
+    cursor: &'a Cursor,
+    oia: Vec<&'a OiaField>,
+    connection: &'a Connection,
+    formatted: bool,
+}
+
+/// Rows of the screen, one per line
+pub fn render_text(tracker: &Tracker) -> String {
+    let mut result = String::new();
+    for row in tracker.get_screen() {
+        result.extend(row.iter().map(|cell| cell.ch));
+        result.push('\n');
+    }
+    result
+}
+
+pub fn render_json(tracker: &Tracker) -> ScreenJson<'_> {
+    let mut oia = tracker.get_oia().iter().collect::<Vec<_>>();
+    oia.sort_by_key(|(name, _)| **name);
+    ScreenJson {
+        rows: tracker
+            .get_screen()
+            .iter()
+            .map(|row| row.iter().map(Cell::from).collect())
+            .collect(),
+        cursor: tracker.get_cursor(),
+        oia: oia.into_iter().map(|(_, field)| field).collect(),
+        connection: tracker.get_connection(),
+        formatted: tracker.get_formatted(),
+    }
+}
+
+/// Index into the 16-color ANSI palette; this matches d3270console's choices
+fn ansi_color(color: Color) -> u8 {
+    match color {
+        Color::NeutralBlack | Color::Black => 0,
+        Color::Blue => 12,
+        Color::Red => 9,
+        Color::Pink => 13,
+        Color::Green => 2,
+        Color::Turquoise | Color::PaleTurquoise => 14,
+        Color::Yellow => 11,
+        Color::NeutralWhite | Color::White => 15,
+        Color::DeepBlue => 4,
+        Color::Orange => 3,
+        Color::Purple => 5,
+        Color::PaleGreen => 10,
+        Color::Gray => 7,
+    }
+}
+
+fn sgr(attr: u32) -> String {
+    let gr = attr.c_gr();
+    let mut result = "\x1b[0".to_owned();
+    for (flag, code) in [
+        (GraphicRendition::HIGHLIGHT, 1),
+        (GraphicRendition::UNDERLINE, 4),
+        (GraphicRendition::BLINK, 5),
+        (GraphicRendition::REVERSE, 7),
+    ] {
+        if gr.contains(flag) {
+            write!(result, ";{code}").unwrap();
+        }
+    }
+    let (fg, bg) = (ansi_color(attr.c_fg()), ansi_color(attr.c_bg()));
+    let fg = if fg < 8 { 30 + fg } else { 82 + fg };
+    let bg = if bg < 8 { 40 + bg } else { 92 + bg };
+    write!(result, ";{fg};{bg}m").unwrap();
+    result
+}
+
+/// Rows of the screen with SGR escapes for colors and renditions
+pub fn render_ansi(tracker: &Tracker) -> String {
+    let mut result = String::new();
+    for row in tracker.get_screen() {
+        let mut last_attr = None;
+        for cell in row {
+            if last_attr != Some(cell.attr) {
+                result.push_str(&sgr(cell.attr));
+                last_attr = Some(cell.attr);
+            }
+            result.push(cell.ch);
+        }
+        result.push_str("\x1b[0m\n");
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sgr_codes() {
+        let attr = u32::c_pack(Color::Red, Color::NeutralBlack, GraphicRendition::UNDERLINE);
+        assert_eq!(sgr(attr), "\x1b[0;4;91;40m");
+        let attr = u32::c_pack(Color::DeepBlue, Color::White, GraphicRendition::empty());
+        assert_eq!(sgr(attr), "\x1b[0;34;107m");
+    }
+}
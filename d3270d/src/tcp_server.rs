@@ -17,35 +17,53 @@
  *************************************************************************/
 
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use futures::never::Never;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 use tokio::select;
 use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 use tracing::{error, info, info_span, Instrument, instrument};
 
-use crate::arbiter::ArbiterHandleRequester;
-use crate::gen_connection::GenConnection;
+use d3270_common::b3270::operation::Attach;
+use d3270_common::b3270::{Indication, Operation};
 
-#[instrument(skip(handle_requester))]
+use crate::audit::{AuditLog, Origin};
+use crate::auth::{Authenticator, Identity};
+use crate::gen_connection::{attach_failure_indication, GenConnection};
+use crate::metrics::ClientGuard;
+use crate::policy::ActionPolicy;
+use crate::session::SessionRegistry;
+
+/// How long a client has to finish the TLS handshake, and then to send its
+/// attach line
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[instrument(skip(sessions, auth, tls, policy, audit))]
 pub async fn listener_proc(
     socket: SocketAddr,
-    handle_requester: ArbiterHandleRequester,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    tls: Option<TlsAcceptor>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
 ) -> anyhow::Result<JoinHandle<anyhow::Error>> {
-    let listener = match tokio::net::TcpListener::bind(socket.clone()).await {
+    let listener = match tokio::net::TcpListener::bind(socket).await {
         Err(error) => {
             error!(?socket, ?error, "Failed to bind");
             return Err(error.into());
         }
         Ok(listener) => listener
     };
-    let span = info_span!(target: "connection-handling", "tcp_listener", addr=%socket);
+    let span = info_span!(target: "connection-handling", "tcp_listener", addr=%socket, tls=tls.is_some());
     info!("TCP listener starting");
     Ok(tokio::spawn(
         async move {
-            let error = listener_task(listener, handle_requester).await.unwrap_err();
+            let error = listener_task(listener, sessions, auth, tls, policy, audit).await.unwrap_err();
             error!(%error, "TCP listener failed to accept");
             error
         }
@@ -55,17 +73,39 @@ pub async fn listener_proc(
 
 async fn listener_task(
     listener: TcpListener,
-    handle_requester: ArbiterHandleRequester,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    tls: Option<TlsAcceptor>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
 ) -> anyhow::Result<Never> {
     loop {
         let (conn, client_addr) = listener.accept().await?;
-        let handle_requester = handle_requester.clone();
+        let sessions = sessions.clone();
+        let auth = auth.clone();
+        let tls = tls.clone();
+        let policy = policy.clone();
+        let audit = audit.clone();
+        let origin = Origin {
+            listener: "tcp",
+            address: Some(client_addr.to_string()),
+        };
         let conn_span =
             info_span!(target: "connection-handling", "tcp_accept", client=%client_addr);
         tokio::spawn(
             async move {
                 info!("Accepted connection");
-                if let Err(error) = handle_tcp_connection(conn, handle_requester).await {
+                // The TLS handshake happens here rather than in the accept loop so that
+                // a slow client can't hold up everybody else.
+                let result = match tls {
+                    Some(acceptor) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                        Ok(Ok(conn)) => handle_tcp_connection(conn, sessions, &auth, None, policy, audit, origin).await,
+                        Ok(Err(error)) => Err(anyhow::Error::from(error).context("TLS handshake failed")),
+                        Err(_) => Err(anyhow!("Timed out waiting for the TLS handshake")),
+                    },
+                    None => handle_tcp_connection(conn, sessions, &auth, None, policy, audit, origin).await,
+                };
+                if let Err(error) = result {
                     error!(%error, "Connection handler failed");
                 } else {
                     info!("Connection closed");
@@ -76,30 +116,104 @@ async fn listener_task(
     }
 }
 
-async fn handle_tcp_connection(
-    mut conn: TcpStream,
-    handle_requester: ArbiterHandleRequester,
+async fn send_indication<W: AsyncWrite + Unpin>(
+    stream_wr: &mut W,
+    ind: &Indication,
+) -> anyhow::Result<()> {
+    let mut ind = serde_json::to_vec(ind)?;
+    ind.push(b'\n');
+    stream_wr.write_all(ind.as_slice()).await?;
+    Ok(())
+}
+
+/// Speak the newline-delimited JSON protocol over `conn`. If the transport
+/// already knows who the client is (`peer`), the client doesn't need a token.
+pub async fn handle_tcp_connection<S: AsyncRead + AsyncWrite>(
+    conn: S,
+    sessions: SessionRegistry,
+    auth: &Authenticator,
+    peer: Option<Identity>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+    origin: Origin,
 ) -> anyhow::Result<()> {
     info!("Handling TCP connection");
-    let (stream_rd, mut stream_wr) = conn.split();
+    let (stream_rd, mut stream_wr) = tokio::io::split(conn);
     let mut stream_rd = BufReader::new(stream_rd).lines();
 
-    let mut conn = GenConnection::new(handle_requester).await?;
+    // Clients that need a token send it in an attach operation as their first
+    // line, and are sent nothing until they do. Everybody else is attached to
+    // the default session straight away; an attach as their first line then
+    // only chooses another session, or asks to be read-only. Its initialize
+    // replaces whatever the client already had from the default session.
+    let needs_token = peer.is_none() && auth.required();
+    let attach = if needs_token {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream_rd.next_line()).await {
+            Err(_) => Err(anyhow!("Timed out waiting for attach")),
+            Ok(line) => {
+                let line = line?.ok_or_else(|| anyhow!("Connection closed before attach"))?;
+                match serde_json::from_str(&line) {
+                    Ok(Operation::Attach(attach)) => Ok(attach),
+                    _ => Err(anyhow!("Authentication required")),
+                }
+            }
+        }
+    } else {
+        Ok(Attach::default())
+    };
+    let handle_requester = attach.and_then(|Attach { token, session, read_only }| {
+        let identity = match peer {
+            Some(identity) => identity,
+            None => auth.authenticate(token.as_deref())?,
+        };
+        info!(user = %identity, ?session, read_only, "Client authenticated");
+        Ok((sessions.get(session.as_deref())?, identity, read_only))
+    });
+    let (handle_requester, identity, read_only) = match handle_requester {
+        Ok(attached) => attached,
+        Err(error) => return refuse(&mut stream_wr, error).await,
+    };
+
+    let mut conn =
+        GenConnection::new(handle_requester, &identity, policy.clone(), read_only, audit.clone(), origin.clone()).await?;
+    // Only counted once it's in, so that refused clients don't show up
+    let _client = ClientGuard::new(origin.listener);
+    let mut may_choose_session = !needs_token;
 
     loop {
         select! {
             line = stream_rd.next_line() => match line? {
-                Some(line) => conn.handle_client_line(line).await?,
+                Some(line) => match serde_json::from_str(&line) {
+                    Ok(Operation::Attach(Attach { session, read_only, .. })) if std::mem::take(&mut may_choose_session) => {
+                        // Already attached to the default session, which is
+                        // all that an empty attach asks for
+                        if session.is_some() || read_only {
+                            info!(?session, read_only, "Client chose a session");
+                            let handle_requester = match sessions.get(session.as_deref()) {
+                                Ok(handle_requester) => handle_requester,
+                                Err(error) => return refuse(&mut stream_wr, error).await,
+                            };
+                            conn = GenConnection::new(handle_requester, &identity, policy.clone(), read_only, audit.clone(), origin.clone()).await?;
+                        }
+                    }
+                    _ => {
+                        may_choose_session = false;
+                        conn.handle_client_line(line).await?;
+                    }
+                },
                 None => bail!("Connection closed"),
             },
             ind = conn.next_indication() => match ind {
                 None => bail!("Arbiter lost"),
-                Some(ind) => {
-                    let mut ind = serde_json::to_vec(&ind)?;
-                    ind.push(b'\n');
-                    stream_wr.write_all(ind.as_slice()).await?;
-                }
+                Some(ind) => send_indication(&mut stream_wr, &ind).await?,
             },
         }
     }
 }
+
+/// Tell the client why it can't attach, and hang up
+async fn refuse<W: AsyncWrite + Unpin>(stream_wr: &mut W, error: anyhow::Error) -> anyhow::Result<()> {
+    send_indication(stream_wr, &attach_failure_indication(&error)).await?;
+    stream_wr.shutdown().await?;
+    Err(error)
+}
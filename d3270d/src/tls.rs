@@ -0,0 +1,126 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use async_std::net::TcpStream;
+use rustls_pemfile::Item;
+use tide_rustls::async_rustls::server::TlsStream;
+use tide_rustls::async_rustls::TlsAcceptor;
+use tide_rustls::CustomTlsAcceptor;
+use tokio::runtime::Handle;
+use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+use crate::tcp_server::HANDSHAKE_TIMEOUT;
+
+/// Build the TLS configuration shared by all of the listeners.
+///
+/// `cert` is a PEM file containing the server certificate followed by any
+/// intermediates; `key` is a PEM file containing its (RSA or PKCS#8) private key.
+pub fn load_server_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = read_pem(cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert.display());
+    }
+
+    let key_der = read_pem(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {}", key.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key_der)
+        .context("Certificate and key don't match")?;
+    Ok(config)
+}
+
+/// Accepts the HTTP listener's TLS connections, as tide-rustls would, but
+/// gives up on clients that take longer than [`HANDSHAKE_TIMEOUT`]
+pub struct TimedTlsAcceptor {
+    acceptor: TlsAcceptor,
+    // tide runs on async-std, so the timer needs to be told where tokio is
+    runtime: Handle,
+}
+
+impl TimedTlsAcceptor {
+    pub fn new(config: ServerConfig, runtime: Handle) -> Self {
+        TimedTlsAcceptor {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            runtime,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl CustomTlsAcceptor for TimedTlsAcceptor {
+    async fn accept(&self, stream: TcpStream) -> std::io::Result<Option<TlsStream<TcpStream>>> {
+        let handshake = {
+            let _runtime = self.runtime.enter();
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+        };
+        match handshake.await {
+            Ok(stream) => stream.map(Some),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out waiting for the TLS handshake",
+            )),
+        }
+    }
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn data(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+    }
+
+    #[test]
+    fn server_config() {
+        let (cert, key) = (data("tls-cert.pem"), data("tls-key.pem"));
+        assert!(load_server_config(&cert, &key).is_ok());
+
+        let error = |cert: &Path, key: &Path| load_server_config(cert, key).err().unwrap().to_string();
+        assert!(error(&key, &key).starts_with("No certificates found"));
+        assert!(error(&cert, &cert).starts_with("No private key found"));
+        assert!(error(&data("missing.pem"), &key).starts_with("Failed to open"));
+    }
+}
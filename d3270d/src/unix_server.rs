@@ -0,0 +1,276 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use futures::never::Never;
+use nix::unistd::{Gid, Group, Uid, User};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+use crate::audit::{AuditLog, Origin};
+use crate::auth::{Authenticator, Identity};
+use crate::gen_connection::attach_failure_indication;
+use crate::policy::ActionPolicy;
+use crate::session::SessionRegistry;
+use crate::tcp_server::handle_tcp_connection;
+
+/// Who may connect to the unix socket, by their `SO_PEERCRED` credentials.
+///
+/// With no users or groups, anybody that the socket's permissions let in
+/// may connect. Clients are identified by their user name, and don't need
+/// a token.
+#[derive(Clone, Debug, Default)]
+pub struct PeerPolicy {
+    uids: Vec<u32>,
+    // (gid, member names)
+    groups: Vec<(u32, Vec<String>)>,
+}
+
+impl PeerPolicy {
+    pub fn allow_user(&mut self, user: &str) -> anyhow::Result<()> {
+        self.uids.push(lookup_user(user)?.as_raw());
+        Ok(())
+    }
+
+    pub fn allow_group(&mut self, group: &str) -> anyhow::Result<()> {
+        let group = lookup_group(group)?;
+        self.groups.push((group.gid.as_raw(), group.mem));
+        Ok(())
+    }
+
+    pub(crate) fn check(&self, uid: u32, gid: u32, name: &str) -> bool {
+        (self.uids.is_empty() && self.groups.is_empty())
+            || self.uids.contains(&uid)
+            || self.groups.iter().any(|(group_gid, members)| {
+                *group_gid == gid || members.iter().any(|member| member == name)
+            })
+    }
+}
+
+/// How to set up the socket file
+#[derive(Clone, Debug)]
+pub struct UnixListenOptions {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    pub peers: PeerPolicy,
+}
+
+/// Accepts a user name or a numeric uid
+pub fn lookup_user(user: &str) -> anyhow::Result<Uid> {
+    if let Ok(uid) = user.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(user)
+        .with_context(|| format!("Failed to look up user {user}"))?
+        .map(|user| user.uid)
+        .ok_or_else(|| anyhow!("No such user {user}"))
+}
+
+/// Accepts a group name or a numeric gid
+fn lookup_group(group: &str) -> anyhow::Result<Group> {
+    let found = match group.parse() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(group),
+    };
+    found
+        .with_context(|| format!("Failed to look up group {group}"))?
+        .ok_or_else(|| anyhow!("No such group {group}"))
+}
+
+pub fn lookup_gid(group: &str) -> anyhow::Result<Gid> {
+    Ok(lookup_group(group)?.gid)
+}
+
+pub(crate) fn bind(options: &UnixListenOptions) -> anyhow::Result<UnixListener> {
+    let path = &options.path;
+    // Clear away the socket from a previous run, but nothing else
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove old socket {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    // Bind in a directory only we can enter, so that the socket is never
+    // reachable before it has its mode and owner, and only then move it
+    // into place
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file name", path.display()))?;
+    let private = parent.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("Failed to create {}", private.display()))?;
+    let listener = bind_privately(options, &private.join(name));
+    std::fs::remove_dir_all(&private).ok();
+    listener
+}
+
+fn bind_privately(options: &UnixListenOptions, private_path: &Path) -> anyhow::Result<UnixListener> {
+    let path = &options.path;
+    let listener = UnixListener::bind(private_path)
+        .with_context(|| format!("Failed to bind {}", path.display()))?;
+    if let Some(mode) = options.mode {
+        std::fs::set_permissions(private_path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode of {}", path.display()))?;
+    }
+    if options.owner.is_some() || options.group.is_some() {
+        nix::unistd::chown(
+            private_path,
+            options.owner.map(Uid::from_raw),
+            options.group.map(Gid::from_raw),
+        )
+        .with_context(|| format!("Failed to change owner of {}", path.display()))?;
+    }
+    std::fs::rename(private_path, path).with_context(|| format!("Failed to move socket to {}", path.display()))?;
+    Ok(listener)
+}
+
+#[instrument(skip_all, fields(path = %options.path.display()))]
+pub async fn listener_proc(
+    options: UnixListenOptions,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<JoinHandle<anyhow::Error>> {
+    let listener = bind(&options).map_err(|error| {
+        error!(%error, "Failed to bind");
+        error
+    })?;
+    info!("Unix listener starting");
+    Ok(tokio::spawn(
+        async move {
+            let error = listener_task(listener, options.peers, sessions, auth, policy, audit)
+                .await
+                .unwrap_err();
+            error!(%error, "Unix listener failed to accept");
+            error
+        }
+        .in_current_span(),
+    ))
+}
+
+async fn listener_task(
+    listener: UnixListener,
+    peers: PeerPolicy,
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+) -> anyhow::Result<Never> {
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let cred = match conn.peer_cred() {
+            Ok(cred) => cred,
+            Err(error) => {
+                warn!(%error, "Failed to get peer credentials");
+                continue;
+            }
+        };
+        let (uid, gid) = (cred.uid(), cred.gid());
+        let name = User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map_or_else(|| format!("uid:{uid}"), |user| user.name);
+        let conn_span = info_span!(target: "connection-handling", "unix_accept", user = name, pid = cred.pid());
+        let allowed = peers.check(uid, gid, &name);
+        let sessions = sessions.clone();
+        let auth = auth.clone();
+        let policy = policy.clone();
+        let audit = audit.clone();
+        let origin = Origin {
+            listener: "unix",
+            address: cred.pid().map(|pid| format!("pid:{pid}")),
+        };
+        tokio::spawn(
+            async move {
+                info!("Accepted connection");
+                let result = if allowed {
+                    let peer = Identity { name };
+                    handle_tcp_connection(conn, sessions, &auth, Some(peer), policy, audit, origin).await
+                } else {
+                    reject(conn).await
+                };
+                if let Err(error) = result {
+                    error!(%error, "Connection handler failed");
+                } else {
+                    info!("Connection closed");
+                }
+            }
+            .instrument(conn_span),
+        );
+    }
+}
+
+async fn reject(mut conn: UnixStream) -> anyhow::Result<()> {
+    let error = anyhow!("Permission denied");
+    let mut ind = serde_json::to_vec(&attach_failure_indication(&error))?;
+    ind.push(b'\n');
+    conn.write_all(&ind).await?;
+    conn.shutdown().await?;
+    Err(error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peer_policy() {
+        assert!(PeerPolicy::default().check(1000, 1000, "alice"));
+
+        let policy = PeerPolicy {
+            uids: vec![1000],
+            groups: vec![(50, vec!["carol".to_owned()])],
+        };
+        assert!(policy.check(1000, 1000, "alice"));
+        assert!(!policy.check(1001, 1001, "bob"));
+        assert!(policy.check(1001, 50, "bob"));
+        assert!(policy.check(1002, 1002, "carol"));
+    }
+
+    #[tokio::test]
+    async fn bind_sets_mode_before_the_socket_appears() {
+        let dir = std::env::temp_dir().join(format!("d3270d-bind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = UnixListenOptions {
+            path: dir.join("admin.sock"),
+            mode: Some(0o600),
+            owner: None,
+            group: None,
+            peers: PeerPolicy::default(),
+        };
+        let _listener = bind(&options).unwrap();
+        let meta = std::fs::symlink_metadata(&options.path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Nothing is left behind but the socket
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
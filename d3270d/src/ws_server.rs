@@ -17,28 +17,432 @@
  *************************************************************************/
 
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::anyhow;
 use tide::prelude::*;
 use tide::{Request, Response};
 use tide_websockets::{WebSocketConnection, self as ws};
+use tide_websockets::tungstenite::protocol::CloseFrame;
+use tide_websockets::tungstenite::protocol::frame::coding::CloseCode;
 use tokio::select;
 use tokio::task::JoinHandle;
-use crate::arbiter::ArbiterHandleRequester;
-use crate::gen_connection::GenConnection;
+use crate::arbiter::{failed_run_result, ArbiterHandleRequester};
+use crate::audit::{AuditLog, AuditTrail, Origin, PendingAudit};
+use crate::auth::{Authenticator, Identity};
+use crate::gen_connection::{attach_failure_indication, GenConnection};
+use crate::metrics::{self, ClientGuard};
+use crate::policy::ActionPolicy;
+use crate::replay::ReplayCommand;
+use crate::script_server;
+use crate::session::{self, SessionRegistry};
+use crate::snapshot::{self, Format};
+use crate::tls::TimedTlsAcceptor;
 use futures::stream::StreamExt;
+use percent_encoding::percent_decode_str;
 use tracing::{info, warn};
 use d3270_common::b3270::Indication;
+use d3270_common::b3270::indication::RunResult;
+use d3270_common::b3270::operation::{Action, Run};
 use rust_embed::{EmbeddedFile, RustEmbed};
 use tide::http::{mime, StatusCode};
+use tide::http::headers::AUTHORIZATION;
+use tide::listener::Listener;
+use tide_rustls::TlsListener;
+use tokio_rustls::rustls::ServerConfig;
 
+#[derive(Clone)]
+pub struct ServerState {
+    sessions: SessionRegistry,
+    auth: Arc<Authenticator>,
+    policy: Arc<ActionPolicy>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct WsQuery {
+    token: Option<String>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+/// Find the client's token, either in the `token` query parameter or as a bearer token.
+/// Browsers can't set headers on a websocket request, so js3270 uses the former.
+fn request_token(req: &Request<ServerState>) -> Option<String> {
+    if let Some(token) = req.query::<WsQuery>().ok().and_then(|query| query.token) {
+        return Some(token);
+    }
+    req.header(AUTHORIZATION)
+        .and_then(|values| values.last().as_str().strip_prefix("Bearer "))
+        .map(str::to_owned)
+}
+
+fn error_response(status: StatusCode, error: impl ToString) -> Response {
+    Response::builder(status)
+        .content_type(mime::PLAIN)
+        .body(error.to_string())
+        .build()
+}
+
+/// Authenticate a plain HTTP request
+fn authenticate(req: &Request<ServerState>) -> anyhow::Result<Identity> {
+    req.state().auth.authenticate(request_token(req).as_deref())
+}
+
+async fn list_sessions(req: Request<ServerState>) -> tide::Result {
+    if let Err(error) = authenticate(&req) {
+        return Ok(error_response(StatusCode::Unauthorized, error));
+    }
+    Ok(json!(req.state().sessions.list()).into())
+}
+
+#[derive(Deserialize)]
+struct NewSession {
+    connect: String,
+}
+
+async fn create_session(mut req: Request<ServerState>) -> tide::Result {
+    let identity = match authenticate(&req) {
+        Ok(identity) => identity,
+        Err(error) => return Ok(error_response(StatusCode::Unauthorized, error)),
+    };
+    let NewSession { connect } = req.body_json().await?;
+    let name = req.param("session")?;
+    if let Err(error) = session::validate_name(name) {
+        return Ok(error_response(StatusCode::BadRequest, error));
+    }
+    // Starting a session is as good as running Connect, so the same policy applies
+    let connect_action = Action {
+        action: "Connect".to_owned(),
+        args: vec![connect.clone()],
+    };
+    if let Err(reason) = req.state().policy.check(&connect_action) {
+        return Ok(error_response(StatusCode::Forbidden, reason));
+    }
+    let sessions = &req.state().sessions;
+    if sessions.get(Some(name)).is_ok() {
+        return Ok(error_response(StatusCode::Conflict, format!("Session {name} already exists")));
+    }
+    if let Err(error) = sessions.create(name, &connect) {
+        return Ok(error_response(StatusCode::InternalServerError, error));
+    }
+    info!(user = %identity, session = name, connect, "Created session");
+    Ok(Response::new(StatusCode::Created))
+}
+
+async fn destroy_session(req: Request<ServerState>) -> tide::Result {
+    let identity = match authenticate(&req) {
+        Ok(identity) => identity,
+        Err(error) => return Ok(error_response(StatusCode::Unauthorized, error)),
+    };
+    let name = req.param("session")?;
+    // Ending a session disconnects it from its host, so Disconnect's policy applies
+    let disconnect_action = Action {
+        action: "Disconnect".to_owned(),
+        args: vec![],
+    };
+    if let Err(reason) = req.state().policy.check(&disconnect_action) {
+        return Ok(error_response(StatusCode::Forbidden, reason));
+    }
+    if let Err(error) = req.state().sessions.destroy(name) {
+        return Ok(error_response(StatusCode::NotFound, error));
+    }
+    info!(user = %identity, session = name, "Destroyed session");
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// Render the session's current screen; no websocket needed
+async fn screen(req: Request<ServerState>) -> tide::Result {
+    if let Err(error) = authenticate(&req) {
+        return Ok(error_response(StatusCode::Unauthorized, error));
+    }
+    let format: Format = match req.param("format")?.parse() {
+        Ok(format) => format,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let requester = match req.state().sessions.get(req.param("session").ok()) {
+        Ok(requester) => requester,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let tracker = match requester.snapshot().await {
+        Ok(tracker) => tracker,
+        Err(error) => return Ok(error_response(StatusCode::ServiceUnavailable, error)),
+    };
+    let body = match format {
+        Format::Text => snapshot::render_text(&tracker),
+        Format::Json => return Ok(json!(snapshot::render_json(&tracker)).into()),
+        Format::Ansi => snapshot::render_ansi(&tracker),
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::PLAIN)
+        .body(body)
+        .build())
+}
+
+/// Step, pause, or resume a replay
+async fn control_replay(req: Request<ServerState>) -> tide::Result {
+    let identity = match authenticate(&req) {
+        Ok(identity) => identity,
+        Err(error) => return Ok(error_response(StatusCode::Unauthorized, error)),
+    };
+    let command: ReplayCommand = match req.param("command")?.parse() {
+        Ok(command) => command,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let requester = match req.state().sessions.get(req.param("session").ok()) {
+        Ok(requester) => requester,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    match requester.replay(command).await {
+        Ok(Ok(())) => {
+            info!(user = %identity, session = requester.session(), ?command, "Controlled replay");
+            Ok(Response::new(StatusCode::NoContent))
+        }
+        Ok(Err(reason)) => Ok(error_response(StatusCode::Conflict, reason)),
+        Err(error) => Ok(error_response(StatusCode::ServiceUnavailable, error)),
+    }
+}
+
+async fn get_metrics(req: Request<ServerState>) -> tide::Result {
+    if let Err(error) = authenticate(&req) {
+        return Ok(error_response(StatusCode::Unauthorized, error));
+    }
+    match metrics::render() {
+        Ok(body) => Ok(Response::builder(StatusCode::Ok)
+            .content_type("text/plain; version=0.0.4")
+            .body(body)
+            .build()),
+        Err(error) => Ok(error_response(StatusCode::InternalServerError, error)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RunBody {
+    Run(Run),
+    Actions(Vec<Action>),
+}
+
+#[derive(Deserialize)]
+struct RunQuery {
+    /// Seconds to wait for the result
+    timeout: Option<f64>,
+}
+
+/// Run actions and respond with their `run-result`
+async fn run_actions(mut req: Request<ServerState>) -> tide::Result {
+    let identity = match authenticate(&req) {
+        Ok(identity) => identity,
+        Err(error) => return Ok(error_response(StatusCode::Unauthorized, error)),
+    };
+    let timeout = match run_timeout(&req) {
+        Ok(timeout) => timeout,
+        Err((status, error)) => return Ok(error_response(status, error)),
+    };
+    let (r_tag, actions) = match req.body_json().await {
+        Ok(RunBody::Run(Run { r_tag, actions, .. })) => (r_tag, actions),
+        Ok(RunBody::Actions(actions)) => (None, actions),
+        Err(error) => return Ok(error_response(StatusCode::BadRequest, error)),
+    };
+    let requester = match req.state().sessions.get(req.param("session").ok()) {
+        Ok(requester) => requester,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let mut result = match run_for(&req, &identity, &requester, actions, timeout).await {
+        Ok(result) => result,
+        Err((status, error)) => return Ok(error_response(status, error)),
+    };
+    result.r_tag = r_tag;
+    let status = if result.success { StatusCode::Ok } else { StatusCode::UnprocessableEntity };
+    Ok(Response::builder(status).body(json!(result)).build())
+}
+
+fn run_timeout(req: &Request<ServerState>) -> Result<Option<Duration>, (StatusCode, String)> {
+    match req.query::<RunQuery>() {
+        Ok(RunQuery { timeout: None }) => Ok(None),
+        Ok(RunQuery { timeout: Some(secs) }) => match Duration::try_from_secs_f64(secs) {
+            Ok(timeout) => Ok(Some(timeout)),
+            Err(error) => Err((StatusCode::BadRequest, format!("Invalid timeout: {error}"))),
+        },
+        Err(error) => Err((StatusCode::BadRequest, error.to_string())),
+    }
+}
+
+/// Run actions for an HTTP client, as the action policy and audit log see
+/// fit. Anything that keeps them from running is an error, with the status
+/// code to respond with.
+async fn run_for(
+    req: &Request<ServerState>,
+    identity: &Identity,
+    requester: &ArbiterHandleRequester,
+    actions: Vec<Action>,
+    timeout: Option<Duration>,
+) -> Result<RunResult, (StatusCode, String)> {
+    let handle = match requester.connect(&identity.name).await {
+        Ok(handle) => handle,
+        Err(error) => return Err((StatusCode::ServiceUnavailable, error.to_string())),
+    };
+    let origin = Origin {
+        listener: "http",
+        address: req.remote().map(str::to_owned),
+    };
+    let audit = AuditTrail::new(req.state().audit.clone(), identity, origin, requester.session())
+        .begin(&actions, handle.input_hidden());
+    let finish = |audit: Option<PendingAudit>, result: &RunResult| {
+        if let Some(audit) = audit {
+            audit.finish(result);
+        }
+    };
+
+    if let Err(reason) = req.state().policy.check_all(&actions) {
+        info!(user = %identity, ?actions, %reason, "Rejected actions");
+        finish(audit, &failed_run_result(reason.clone()));
+        return Err((StatusCode::Forbidden, reason));
+    }
+
+    info!(user = %identity, ?actions, "Running actions");
+    let rcvr = match handle.send_actions(actions).await {
+        Ok(rcvr) => rcvr,
+        Err(error) => {
+            finish(audit, &failed_run_result(error.to_string()));
+            return Err((StatusCode::ServiceUnavailable, error.to_string()));
+        }
+    };
+    let result = match timeout {
+        Some(timeout) => {
+            let wait = {
+                let _runtime = req.state().sessions.runtime().enter();
+                tokio::time::timeout(timeout, rcvr)
+            };
+            match wait.await {
+                Ok(result) => result,
+                Err(_) => {
+                    let message = "Timed out waiting for the actions to finish";
+                    finish(audit, &failed_run_result(format!("{message}; they may still run")));
+                    return Err((StatusCode::GatewayTimeout, message.to_owned()));
+                }
+            }
+        }
+        None => rcvr.await,
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(_) => {
+            let message = "b3270 went away before the actions finished";
+            finish(audit, &failed_run_result(message.to_owned()));
+            return Err((StatusCode::ServiceUnavailable, message.to_owned()));
+        }
+    };
+    finish(audit, &result);
+    Ok(result)
+}
+
+/// How x3270's REST interface answers
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RestFormat {
+    /// The action's output
+    Text,
+    /// The action's output, and then the s3270 status line
+    StatusText,
+    /// `{"result": [output lines], "status": status line}`
+    Json,
+}
+
+impl FromStr for RestFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(RestFormat::Text),
+            "stext" => Ok(RestFormat::StatusText),
+            "json" => Ok(RestFormat::Json),
+            _ => Err(format!("Unknown REST format {s:?}; use text, stext or json")),
+        }
+    }
+}
+
+// POST /3270/rest/post/json takes the same forms b3270 does
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RestBody {
+    Text(String),
+    Action(Action),
+    Actions(Vec<Action>),
+}
+
+/// x3270's REST interface: `GET /3270/rest/<format>/<actions>`, or `POST
+/// /3270/rest/post/<format>` with the actions as the body
+async fn rest(mut req: Request<ServerState>) -> tide::Result {
+    let identity = match authenticate(&req) {
+        Ok(identity) => identity,
+        Err(error) => return Ok(error_response(StatusCode::Unauthorized, error)),
+    };
+    let format: RestFormat = match req.param("format")?.parse() {
+        Ok(format) => format,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let timeout = match run_timeout(&req) {
+        Ok(timeout) => timeout,
+        Err((status, error)) => return Ok(error_response(status, error)),
+    };
+    let text = match req.param("actions") {
+        Ok(actions) => percent_decode_str(actions).decode_utf8_lossy().into_owned(),
+        Err(_) => req.body_string().await?,
+    };
+    let actions = match format {
+        RestFormat::Json if text.trim_start().starts_with(['{', '[', '"']) => {
+            match serde_json::from_str(&text) {
+                Ok(RestBody::Text(text)) => script_server::parse_actions(&text),
+                Ok(RestBody::Action(action)) => Ok(vec![action]),
+                Ok(RestBody::Actions(actions)) => Ok(actions),
+                Err(error) => Err(error.to_string()),
+            }
+        }
+        _ => script_server::parse_actions(&text),
+    };
+    let actions = match actions {
+        Ok(actions) => actions,
+        Err(error) => return Ok(error_response(StatusCode::BadRequest, error)),
+    };
+    let requester = match req.state().sessions.get(req.param("session").ok()) {
+        Ok(requester) => requester,
+        Err(error) => return Ok(error_response(StatusCode::NotFound, error)),
+    };
+    let result = match run_for(&req, &identity, &requester, actions, timeout).await {
+        Ok(result) => result,
+        Err((status, error)) => return Ok(error_response(status, error)),
+    };
+    let status = if result.success { StatusCode::Ok } else { StatusCode::BadRequest };
+    let lines = result.text.iter().flat_map(|text| text.lines());
+    let status_line = || async {
+        requester.snapshot().await.map(|tracker| script_server::status_line(&tracker, Some(result.time)))
+    };
+    let body: String = match format {
+        RestFormat::Text => lines.map(|line| format!("{line}\n")).collect(),
+        RestFormat::StatusText => match status_line().await {
+            Ok(status_line) => lines.chain([status_line.as_str()]).map(|line| format!("{line}\n")).collect(),
+            Err(error) => return Ok(error_response(StatusCode::ServiceUnavailable, error)),
+        },
+        RestFormat::Json => match status_line().await {
+            Ok(status_line) => {
+                let body = json!({ "result": lines.collect::<Vec<_>>(), "status": status_line });
+                return Ok(Response::builder(status).body(body).build());
+            }
+            Err(error) => return Ok(error_response(StatusCode::ServiceUnavailable, error)),
+        },
+    };
+    Ok(Response::builder(status).content_type(mime::PLAIN).body(body).build())
+}
 
 #[derive(RustEmbed)]
 #[folder = "$CARGO_MANIFEST_DIR/../js3270/dist/"]
 struct Asset;
 
-async fn static_file(req: Request<ArbiterHandleRequester>) -> tide::Result {
+async fn static_file(req: Request<ServerState>) -> tide::Result {
     let mut path = req.param("path").unwrap_or("index.html");
-    if path == "" {
+    if path.is_empty() {
         path = "index.html"
     }
     let content_type = if let Some((_, ext)) = path.rsplit_once(".") {
@@ -68,27 +472,75 @@ async fn static_file(req: Request<ArbiterHandleRequester>) -> tide::Result {
     }
 }
 
-pub async fn start_ws_server(socket: SocketAddr, handle_requester: ArbiterHandleRequester, ) -> anyhow::Result<JoinHandle<anyhow::Error>> {
-    let mut app = tide::Server::with_state(handle_requester);
+pub async fn start_ws_server(socket: SocketAddr, sessions: SessionRegistry, auth: Arc<Authenticator>, tls: Option<ServerConfig>, policy: Arc<ActionPolicy>, audit: Option<Arc<AuditLog>>) -> anyhow::Result<JoinHandle<anyhow::Error>> {
+    let runtime = sessions.runtime().clone();
+    let mut app = tide::Server::with_state(ServerState { sessions, auth, policy, audit });
     app.with(tide_tracing::TraceMiddleware::new());
     app.at("/api/ws").get(tide_websockets::WebSocket::new(handle_websocket));
+    app.at("/api/ws/:session").get(tide_websockets::WebSocket::new(handle_websocket));
+    app.at("/api/sessions").get(list_sessions);
+    app.at("/api/sessions/:session").put(create_session).delete(destroy_session);
+    app.at("/metrics").get(get_metrics);
+    app.at("/api/run").post(run_actions);
+    app.at("/api/sessions/:session/run").post(run_actions);
+    app.at("/3270/rest/post/:format").post(rest);
+    app.at("/3270/rest/:format/*actions").get(rest);
+    app.at("/api/sessions/:session/3270/rest/post/:format").post(rest);
+    app.at("/api/sessions/:session/3270/rest/:format/*actions").get(rest);
+    app.at("/api/screen/:format").get(screen);
+    app.at("/api/sessions/:session/screen/:format").get(screen);
+    app.at("/api/replay/:command").post(control_replay);
+    app.at("/api/sessions/:session/replay/:command").post(control_replay);
     app.at("/*path").get(static_file);
     app.at("/").get(static_file);
 
-    let mut listener = app.bind(socket.clone()).await?;
+    match tls {
+        Some(config) => {
+            let acceptor = TimedTlsAcceptor::new(config, runtime);
+            let listener = TlsListener::build().addrs(socket).tls_acceptor(Arc::new(acceptor));
+            spawn_listener(app.bind(listener).await?, socket)
+        }
+        None => spawn_listener(app.bind(socket).await?, socket),
+    }
+}
+
+fn spawn_listener<L: Listener<ServerState>>(mut listener: L, socket: SocketAddr) -> anyhow::Result<JoinHandle<anyhow::Error>> {
     Ok(tokio::task::spawn(async move {
-        info!(address=%socket, "Starting HTTP server");
+        info!(address=%socket, %listener, "Starting HTTP server");
         listener.accept().await
             .map(|()| anyhow!("HTTP server returned early"))
             .unwrap_or_else(Into::into)
     }))
 }
 
-async fn handle_websocket(req: Request<ArbiterHandleRequester>, mut ws: WebSocketConnection) -> tide::Result<()> {
+async fn handle_websocket(req: Request<ServerState>, mut ws: WebSocketConnection) -> tide::Result<()> {
     info!("Handling websocket");
-    let mut arbiter = GenConnection::new(req.state().clone()).await?;
+    let session = req.param("session").ok();
+    let handle_requester = req.state().auth.authenticate(request_token(&req).as_deref())
+        .and_then(|identity| {
+            // Don't quietly give a writable connection to someone who asked for a read-only one
+            let read_only = req.query::<WsQuery>()
+                .map_err(|error| anyhow!("Invalid query: {error}"))?
+                .read_only;
+            info!(user = %identity, ?session, read_only, "Client authenticated");
+            Ok((req.state().sessions.get(session)?, identity, read_only))
+        });
+    let (handle_requester, identity, read_only) = match handle_requester {
+        Ok(attached) => attached,
+        Err(error) => {
+            warn!(%error, "Rejecting websocket client");
+            ws.send_json(&attach_failure_indication(&error)).await?;
+            ws.send(ws::Message::Close(None)).await?;
+            return Ok(());
+        }
+    };
 
-    // TODO: do authenticatey things
+    let origin = Origin {
+        listener: "ws",
+        address: req.remote().map(str::to_owned),
+    };
+    let mut arbiter = GenConnection::new(handle_requester, &identity, req.state().policy.clone(), read_only, req.state().audit.clone(), origin).await?;
+    let _client = ClientGuard::new("ws");
 
     'main: loop {
         select! {
@@ -103,7 +555,14 @@ async fn handle_websocket(req: Request<ArbiterHandleRequester>, mut ws: WebSocke
                 }
             },
             msg = arbiter.next_indication() => {
-                let msg: Indication = if let Some(msg) = msg { msg } else { break 'main; };
+                let msg: Indication = if let Some(msg) = msg { msg } else {
+                    // The session let go of us (kicked, or d3270d is stopping)
+                    ws.send(ws::Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Session closed".into(),
+                    }))).await?;
+                    break 'main;
+                };
                 ws.send_json(&msg).await?;
             }
         }
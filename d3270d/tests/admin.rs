@@ -0,0 +1,83 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d's admin socket end to end
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
+
+use d3270_common::admin::{AdminRequest, AdminResponse};
+use serde_json::Value;
+
+use common::{Client, Daemon, TcpClient};
+
+struct Admin(BufReader<UnixStream>);
+
+impl Admin {
+    fn connect(daemon: &Daemon) -> Self {
+        // The admin listener may come up after the TCP ones
+        let start = Instant::now();
+        loop {
+            match UnixStream::connect(daemon.path("admin.sock")) {
+                Ok(conn) => return Admin(BufReader::new(conn)),
+                Err(error) => assert!(start.elapsed() < common::TIMEOUT, "no admin socket: {error}"),
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+    }
+
+    fn request(&mut self, request: &AdminRequest) -> AdminResponse {
+        let mut line = serde_json::to_string(request).unwrap();
+        line.push('\n');
+        self.0.get_mut().write_all(line.as_bytes()).unwrap();
+        line.clear();
+        self.0.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+}
+
+#[test]
+fn admin_requests_are_audited() {
+    let daemon = Daemon::start("[listen.admin]\npath = \"admin.sock\"\n[audit]\nfile = \"audit.log\"");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    let mut admin = Admin::connect(&daemon);
+
+    let AdminResponse::Clients(clients) = admin.request(&AdminRequest::Clients { session: None }) else {
+        panic!("no client list");
+    };
+    let id = clients[0].client.id;
+    assert_eq!(admin.request(&AdminRequest::Kick { id, session: None }), AdminResponse::Ok);
+    assert_eq!(admin.request(&AdminRequest::Disconnect { session: None }), AdminResponse::Ok);
+
+    let log = std::fs::read_to_string(daemon.path("audit.log")).unwrap();
+    let records: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let uid = format!("uid:{}", nix::unistd::getuid());
+    assert_eq!(records.len(), 2, "{log}");
+    for (record, action) in records.iter().zip(["Kick", "Disconnect"]) {
+        assert_eq!(record["listener"], "admin");
+        assert_eq!(record["peer"], uid.as_str());
+        assert_eq!(record["session"], "default");
+        assert_eq!(record["actions"][0]["action"], action);
+        assert_eq!(record["success"], true);
+    }
+    assert_eq!(records[0]["actions"][0]["args"][0], id.to_string());
+}
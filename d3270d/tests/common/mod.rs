@@ -0,0 +1,405 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! Runs d3270d, with fake-b3270 behind it, for the end-to-end tests.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use d3270_common::b3270::indication::{ConnectionState, RunResult};
+use d3270_common::b3270::operation::{Action, Attach, Run};
+use d3270_common::b3270::{Indication, InitializeIndication, Operation};
+use tokio_rustls::rustls::{ClientConfig, ClientSession, StreamOwned};
+use tungstenite::protocol::CloseFrame;
+use tokio_rustls::webpki::DNSNameRef;
+
+/// How long to wait for anything before giving up on the test
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const TOKEN: &str = "secret";
+pub const HOST: &str = "fake-host";
+
+pub struct Daemon {
+    child: Child,
+    dir: PathBuf,
+    pub tcp: SocketAddr,
+    pub http: SocketAddr,
+    pub script: SocketAddr,
+}
+
+fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+impl Daemon {
+    /// Start d3270d, with `extra` added to its configuration file
+    pub fn start(extra: &str) -> Self {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "d3270d-test-{}-{}",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tokens"), format!("alice:{TOKEN}\n")).unwrap();
+
+        let (tcp, http, script) = (free_port(), free_port(), free_port());
+        let config = format!(
+            r#"connect = "{HOST}"
+[listen]
+tcp = "{tcp}"
+http = "{http}"
+script = "{script}"
+[b3270]
+path = "{b3270}"
+[auth]
+token-file = "tokens"
+{extra}
+"#,
+            b3270 = env!("CARGO_BIN_EXE_fake-b3270"),
+        );
+        std::fs::write(dir.join("d3270d.toml"), config).unwrap();
+
+        let log = std::fs::File::create(dir.join("d3270d.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_d3270d"))
+            .arg("-config")
+            .arg(dir.join("d3270d.toml"))
+            .env("RUST_LOG", "debug")
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        let daemon = Daemon { child, dir, tcp, http, script };
+
+        let start = Instant::now();
+        while [tcp, http, script].into_iter().any(|addr| TcpStream::connect(addr).is_err()) {
+            assert!(start.elapsed() < TIMEOUT, "d3270d didn't start listening");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        daemon
+    }
+
+    /// A file in d3270d's directory, which relative paths in its
+    /// configuration are relative to
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// What d3270d has logged so far
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.path("d3270d.log")).unwrap_or_default()
+    }
+
+    /// The processes d3270d has started, i.e. its b3270s
+    pub fn children(&self) -> Vec<u32> {
+        let parent = self.child.id().to_string();
+        let mut children = vec![];
+        for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+            // The parent pid is the fourth field, after the parenthesized name
+            let stat = std::fs::read_to_string(entry.path().join("stat")).unwrap_or_default();
+            let Some((_, fields)) = stat.rsplit_once(')') else {
+                continue;
+            };
+            if fields.split_whitespace().nth(1) == Some(parent.as_str()) {
+                children.extend(entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()));
+            }
+        }
+        children
+    }
+
+    /// Send d3270d SIGTERM
+    pub fn terminate(&self) {
+        let status = Command::new("kill").arg("-TERM").arg(self.child.id().to_string()).status().unwrap();
+        assert!(status.success(), "Failed to send SIGTERM");
+    }
+
+    /// Wait for d3270d to exit, and say how it went
+    pub fn wait(&mut self) -> ExitStatus {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(start.elapsed() < TIMEOUT, "d3270d didn't exit");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Whether a process has gone, or is only waiting to be reaped
+pub fn exited(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        Ok(stat) => stat.rsplit_once(')').and_then(|(_, fields)| fields.split_whitespace().next()) == Some("Z"),
+        Err(_) => true,
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        if std::thread::panicking() {
+            eprintln!("d3270d's log:\n{}", self.log());
+        }
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Anything that carries indications from d3270d
+pub trait Client {
+    /// The next indication, or None once d3270d has closed the connection
+    fn recv(&mut self) -> Option<Indication>;
+
+    fn send(&mut self, op: &Operation);
+
+    /// Skip indications until one matches
+    fn wait_for<T>(&mut self, what: &str, mut check: impl FnMut(Indication) -> Option<T>) -> T {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            match self.recv() {
+                Some(ind) => {
+                    if let Some(found) = check(ind) {
+                        return found;
+                    }
+                }
+                None => panic!("Connection closed while waiting for {what}"),
+            }
+        }
+        panic!("Timed out waiting for {what}")
+    }
+
+    fn run(&mut self, r_tag: &str, actions: Vec<Action>) -> RunResult {
+        self.start_run(r_tag, actions);
+        self.run_result(r_tag)
+    }
+
+    /// Send a run without waiting for its result
+    fn start_run(&mut self, r_tag: &str, actions: Vec<Action>) {
+        self.send(&Operation::Run(Run {
+            r_tag: Some(r_tag.to_owned()),
+            type_: None,
+            actions,
+        }));
+    }
+
+    fn run_result(&mut self, r_tag: &str) -> RunResult {
+        self.wait_for("run-result", |ind| match ind {
+            Indication::RunResult(result) if result.r_tag.as_deref() == Some(r_tag) => Some(result),
+            _ => None,
+        })
+    }
+
+    /// Wait until the session is connected to the fake host, whether that
+    /// arrives with the initial state or afterwards
+    fn wait_connected(&mut self) {
+        self.wait_for("connection", |ind| {
+            let connection = match ind {
+                Indication::Connection(connection) => Some(connection),
+                Indication::Initialize(init) => init.into_iter().find_map(|ind| match ind {
+                    InitializeIndication::Connection(connection) => Some(connection),
+                    _ => None,
+                }),
+                _ => None,
+            };
+            connection
+                .filter(|connection| {
+                    connection.state == ConnectionState::Connected3270 && connection.host.as_deref() == Some(HOST)
+                })
+                .map(drop)
+        })
+    }
+}
+
+pub fn action(name: &str, args: &[&str]) -> Action {
+    Action {
+        action: name.to_owned(),
+        args: args.iter().map(|&arg| arg.to_owned()).collect(),
+    }
+}
+
+/// A client of the TCP protocol, which the unix socket speaks too
+pub struct TcpClient {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl TcpClient {
+    pub fn attach(daemon: &Daemon, token: &str) -> Self {
+        let writer = TcpStream::connect(daemon.tcp).unwrap();
+        writer.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut client = TcpClient {
+            reader: BufReader::new(Box::new(writer.try_clone().unwrap())),
+            writer: Box::new(writer),
+        };
+        client.send(&Operation::Attach(Attach {
+            token: Some(token.to_owned()),
+            session: None,
+            read_only: false,
+        }));
+        client
+    }
+
+    /// Connect to the unix socket `name` in d3270d's directory, without
+    /// sending anything
+    pub fn unix(daemon: &Daemon, name: &str) -> Self {
+        // The socket may come a little after the TCP listener
+        let start = Instant::now();
+        let writer = loop {
+            match UnixStream::connect(daemon.path(name)) {
+                Ok(stream) => break stream,
+                Err(error) => assert!(start.elapsed() < TIMEOUT, "Failed to connect: {error}"),
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        writer.set_read_timeout(Some(TIMEOUT)).unwrap();
+        TcpClient {
+            reader: BufReader::new(Box::new(writer.try_clone().unwrap())),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Attach over TLS, trusting the test CA, to a daemon started with
+    /// [`tls_config`]
+    pub fn tls(daemon: &Daemon, token: &str) -> Self {
+        let stream = TlsStream::connect(daemon.tcp);
+        let mut client = TcpClient {
+            reader: BufReader::new(Box::new(stream.clone())),
+            writer: Box::new(stream),
+        };
+        client.send(&Operation::Attach(Attach {
+            token: Some(token.to_owned()),
+            session: None,
+            read_only: false,
+        }));
+        client
+    }
+}
+
+/// Configuration for a daemon that uses the test certificate
+pub fn tls_config() -> String {
+    let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    format!("[tls]\ncert = \"{data}/tls-cert.pem\"\nkey = \"{data}/tls-key.pem\"")
+}
+
+/// A TLS client connection to localhost, which can be shared between the
+/// reading and writing halves of a [`TcpClient`]
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ClientSession, TcpStream>>>);
+
+impl TlsStream {
+    pub fn connect(addr: SocketAddr) -> Self {
+        let mut config = ClientConfig::new();
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/tls-ca.pem");
+        let mut ca = BufReader::new(std::fs::File::open(ca).unwrap());
+        config.root_store.add_pem_file(&mut ca).unwrap();
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let session = ClientSession::new(&Arc::new(config), name);
+        let socket = TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        TlsStream(Arc::new(Mutex::new(StreamOwned::new(session, socket))))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl Client for TcpClient {
+    fn recv(&mut self) -> Option<Indication> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(serde_json::from_str(&line).unwrap_or_else(|error| panic!("Bad indication {line}: {error}"))),
+            Err(error) if error.kind() == ErrorKind::ConnectionReset => None,
+            Err(error) => panic!("Failed to read from d3270d: {error}"),
+        }
+    }
+
+    fn send(&mut self, op: &Operation) {
+        let mut line = serde_json::to_vec(op).unwrap();
+        line.push(b'\n');
+        self.writer.write_all(&line).unwrap();
+    }
+}
+
+pub struct WsClient {
+    socket: tungstenite::WebSocket<TcpStream>,
+}
+
+impl WsClient {
+    pub fn attach(daemon: &Daemon, token: &str) -> Self {
+        let stream = TcpStream::connect(daemon.http).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let url = format!("ws://{}/api/ws?token={token}", daemon.http);
+        let (socket, _) = tungstenite::client(url.as_str(), stream).unwrap();
+        WsClient { socket }
+    }
+
+    /// Read until d3270d closes the websocket, and return its close frame
+    pub fn wait_closed(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            match self.socket.read_message() {
+                Ok(tungstenite::Message::Close(frame)) => return frame.map(CloseFrame::into_owned),
+                Ok(_) => {}
+                Err(error) => panic!("The websocket went away without being closed: {error}"),
+            }
+        }
+    }
+}
+
+impl Client for WsClient {
+    fn recv(&mut self) -> Option<Indication> {
+        loop {
+            match self.socket.read_message() {
+                Ok(tungstenite::Message::Text(text)) => {
+                    return Some(serde_json::from_str(&text).unwrap_or_else(|error| panic!("Bad indication {text}: {error}")))
+                }
+                Ok(tungstenite::Message::Close(_)) => return None,
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => return None,
+                Err(error) => panic!("Failed to read from d3270d: {error}"),
+            }
+        }
+    }
+
+    fn send(&mut self, op: &Operation) {
+        let text = serde_json::to_string(op).unwrap();
+        self.socket.write_message(tungstenite::Message::Text(text)).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, through the HLLAPI socket
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use common::Daemon;
+use d3270_common::hllapi::{rc, HllapiRequest, HllapiResponse};
+
+struct Hllapi {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Hllapi {
+    fn connect(daemon: &Daemon) -> Self {
+        // The socket may come a little after the TCP listener
+        let start = Instant::now();
+        let writer = loop {
+            match UnixStream::connect(daemon.path("hllapi.sock")) {
+                Ok(stream) => break stream,
+                Err(error) => assert!(start.elapsed() < common::TIMEOUT, "Failed to connect: {error}"),
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        writer.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        Hllapi {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+
+    fn call(&mut self, request: HllapiRequest) -> HllapiResponse {
+        let mut line = serde_json::to_vec(&request).unwrap();
+        line.push(b'\n');
+        self.writer.write_all(&line).unwrap();
+        let mut line = String::new();
+        assert_ne!(self.reader.read_line(&mut line).unwrap(), 0, "Connection closed");
+        serde_json::from_str(&line).unwrap()
+    }
+}
+
+#[test]
+fn presentation_space_functions() {
+    let daemon = Daemon::start("[listen.hllapi]\npath = \"hllapi.sock\"");
+    let mut hllapi = Hllapi::connect(&daemon);
+
+    assert_eq!(hllapi.call(HllapiRequest::CopyPs).rc, rc::NOT_CONNECTED);
+    assert!(!hllapi.call(HllapiRequest::QuerySessions).sessions.is_empty());
+    assert_eq!(hllapi.call(HllapiRequest::ConnectPs { session: None }).rc, rc::OK);
+
+    // The daemon may still be bringing the session up
+    let start = Instant::now();
+    let status = loop {
+        let status = hllapi.call(HllapiRequest::QuerySessionStatus).status.unwrap();
+        if status.host.as_deref() == Some(common::HOST) {
+            break status;
+        }
+        assert!(start.elapsed() < common::TIMEOUT, "Session never connected");
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!((status.rows, status.columns), (24, 80));
+
+    let keys = HllapiRequest::SendKey {
+        keys: "hello@E".to_owned(),
+    };
+    assert_eq!(hllapi.call(keys).rc, rc::OK);
+    assert_eq!(hllapi.call(HllapiRequest::Wait { timeout: Some(1.0) }).rc, rc::OK);
+    let copied = hllapi.call(HllapiRequest::CopyPsToString { position: 1, length: 5 });
+    assert_eq!(copied.text.as_deref(), Some("hello"));
+    assert_eq!(hllapi.call(HllapiRequest::CopyPs).text.unwrap().len(), 24 * 80);
+    assert_eq!(hllapi.call(HllapiRequest::QueryCursor).position, Some(6));
+
+    let search = |text: &str, position, backward| HllapiRequest::SearchPs {
+        text: text.to_owned(),
+        position,
+        backward,
+    };
+    assert_eq!(hllapi.call(search("llo", None, false)).position, Some(3));
+    assert_eq!(hllapi.call(search("llo", Some(4), false)).rc, rc::NOT_FOUND);
+    assert_eq!(hllapi.call(search("he", Some(100), true)).position, Some(1));
+
+    let bad = HllapiRequest::SendKey { keys: "@!".to_owned() };
+    assert_eq!(hllapi.call(bad).rc, rc::PARAMETER_ERROR);
+    let bad = HllapiRequest::CopyPsToString {
+        position: 24 * 80,
+        length: 2,
+    };
+    assert_eq!(hllapi.call(bad).rc, rc::INVALID_POSITION);
+    let bad = HllapiRequest::CopyPsToString {
+        position: 2,
+        length: usize::MAX,
+    };
+    assert_eq!(hllapi.call(bad).rc, rc::PARAMETER_ERROR);
+    assert_eq!(hllapi.call(HllapiRequest::SetCursor { position: 0 }).rc, rc::INVALID_POSITION);
+    assert_eq!(hllapi.call(HllapiRequest::SetCursor { position: 81 }).rc, rc::OK);
+    // fake-b3270's ReadBuffer() is empty, as if the screen were unformatted
+    let attribute = hllapi.call(HllapiRequest::QueryFieldAttribute { position: 1 });
+    assert_eq!(attribute.rc, rc::NOT_FOUND);
+
+    assert_eq!(hllapi.call(HllapiRequest::DisconnectPs).rc, rc::OK);
+    assert_eq!(hllapi.call(HllapiRequest::CopyPs).rc, rc::NOT_CONNECTED);
+}
@@ -0,0 +1,151 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, through x3270's REST interface
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use common::{Client, Daemon, TcpClient};
+
+/// Make a request, and return the status code and body
+fn http(daemon: &Daemon, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, String) {
+    let mut conn = TcpStream::connect(daemon.http).unwrap();
+    conn.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let auth = token.map(|token| format!("Authorization: Bearer {token}\r\n")).unwrap_or_default();
+    write!(
+        conn,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\n{auth}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_owned())
+}
+
+#[test]
+fn text_and_stext() {
+    let daemon = Daemon::start("");
+    let token = Some(common::TOKEN);
+    let (status, body) = http(&daemon, "GET", "/3270/rest/text/String(%22hi%22)", token, "");
+    assert_eq!((status, body.as_str()), (200, ""));
+
+    let (status, body) = http(&daemon, "GET", "/3270/rest/stext/Fail(%22no%20good%22)", token, "");
+    assert_eq!(status, 400);
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines[0], "no good");
+    assert!(lines[1].contains(&format!("C({})", common::HOST)), "{body}");
+
+    let (status, _) = http(&daemon, "GET", "/3270/rest/text/Enter", None, "");
+    assert_eq!(status, 401);
+}
+
+#[test]
+fn json() {
+    let daemon = Daemon::start("");
+    let token = Some(common::TOKEN);
+    let (status, body) = http(&daemon, "GET", "/3270/rest/json/Fail(nope)", token, "");
+    assert_eq!(status, 400);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["result"], serde_json::json!(["nope"]));
+    assert_eq!(body["status"].as_str().unwrap().split(' ').count(), 12);
+
+    let action = r#"{"action":"String","args":["hello"]}"#;
+    let (status, body) = http(&daemon, "POST", "/3270/rest/post/json", token, action);
+    assert_eq!(status, 200, "{body}");
+    let (status, body) = http(&daemon, "POST", "/3270/rest/post/text", token, "Tab() Enter()");
+    assert_eq!(status, 200, "{body}");
+}
+
+#[test]
+fn destroying_sessions() {
+    let daemon = Daemon::start("[policy]\ndeny = [\"Disconnect\"]");
+    let (status, _) = http(&daemon, "DELETE", "/api/sessions/default", Some(common::TOKEN), "");
+    assert_eq!(status, 403);
+
+    let daemon = Daemon::start("");
+    let (status, _) = http(&daemon, "DELETE", "/api/sessions/default", Some(common::TOKEN), "");
+    assert_eq!(status, 204);
+    let (status, body) = http(&daemon, "GET", "/api/screen/text", Some(common::TOKEN), "");
+    assert_eq!(status, 404);
+    assert!(body.contains("no default session"), "{body}");
+}
+
+#[test]
+fn run_status_codes() {
+    let daemon = Daemon::start("[policy]\ndeny = [\"Disconnect\"]");
+    let token = Some(common::TOKEN);
+    let run = |path: &str, body: &str| http(&daemon, "POST", path, token, body);
+
+    let (status, body) = run("/api/run", r#"[{"action":"String","args":["hi"]}]"#);
+    assert_eq!(status, 200, "{body}");
+    let (status, body) = run("/api/run", r#"[{"action":"Fail","args":["no good"]}]"#);
+    assert_eq!(status, 422, "{body}");
+    let result: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(result["text"], serde_json::json!(["no good"]));
+    let (status, body) = run("/api/run", r#"[{"action":"Disconnect"}]"#);
+    assert_eq!(status, 403, "{body}");
+
+    let (status, body) = run("/api/run?timeout=0.2", r#"[{"action":"Pause"}]"#);
+    assert_eq!(status, 504, "{body}");
+}
+
+#[test]
+fn run_in_a_destroyed_session() {
+    let daemon = Daemon::start("");
+    let token = Some(common::TOKEN);
+    std::thread::scope(|scope| {
+        let run = scope.spawn(|| http(&daemon, "POST", "/api/run", token, r#"[{"action":"Pause"}]"#));
+        // Once b3270 has the run, take the session away from under it
+        let start = Instant::now();
+        while !http(&daemon, "GET", "/metrics", token, "").1.contains("d3270_pending_actions{session=\"default\"} 1") {
+            assert!(start.elapsed() < common::TIMEOUT, "The run never started");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let (status, _) = http(&daemon, "DELETE", "/api/sessions/default", token, "");
+        assert_eq!(status, 204);
+        let (status, body) = run.join().unwrap();
+        assert_eq!(status, 503, "{body}");
+    });
+}
+
+#[test]
+fn only_attached_clients_are_counted() {
+    let daemon = Daemon::start("");
+    let clients = || {
+        let (_, body) = http(&daemon, "GET", "/metrics", Some(common::TOKEN), "");
+        body.lines()
+            .find_map(|line| line.strip_prefix("d3270_clients{listener=\"tcp\"} "))
+            .map_or(0, |count| count.parse().unwrap())
+    };
+    // One that hasn't sent its token yet, and one that sent the wrong one
+    let _waiting = TcpStream::connect(daemon.tcp).unwrap();
+    let mut refused = TcpClient::attach(&daemon, "wrong");
+    assert!(matches!(refused.recv(), Some(d3270_common::b3270::Indication::UiError(_))));
+    assert_eq!(clients(), 0);
+
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    assert_eq!(clients(), 1);
+}
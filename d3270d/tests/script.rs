@@ -0,0 +1,118 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, through the s3270-compatible script port
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use common::Daemon;
+
+struct Script {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// What s3270 says to a command
+#[derive(Debug)]
+struct Reply {
+    data: Vec<String>,
+    status: Vec<String>,
+    ok: bool,
+}
+
+impl Script {
+    fn connect(daemon: &Daemon) -> Self {
+        let writer = TcpStream::connect(daemon.script).unwrap();
+        writer.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        Script {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Reply {
+        writeln!(self.writer, "{line}").unwrap();
+        let mut data = vec![];
+        loop {
+            let mut line = String::new();
+            assert_ne!(self.reader.read_line(&mut line).unwrap(), 0, "Connection closed");
+            let line = line.trim_end();
+            match line.strip_prefix("data: ") {
+                Some(text) => data.push(text.to_owned()),
+                None => {
+                    let status = line.split(' ').map(str::to_owned).collect();
+                    let mut result = String::new();
+                    self.reader.read_line(&mut result).unwrap();
+                    return Reply {
+                        data,
+                        status,
+                        ok: result.trim_end() == "ok",
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn commands_get_s3270_replies() {
+    let daemon = Daemon::start("");
+    let mut script = Script::connect(&daemon);
+
+    let reply = script.command(&format!("Attach({})", common::TOKEN));
+    assert!(reply.ok);
+    let reply = script.command(r#"String("hi") Tab"#);
+    assert!(reply.ok, "{reply:?}");
+    assert_eq!(reply.status.len(), 12);
+    assert_eq!(reply.status[3], format!("C({})", common::HOST));
+    assert_eq!(reply.status[4], "I");
+    assert_eq!(&reply.status[5..10], ["2", "24", "80", "0", "2"]);
+
+    let reply = script.command("Fail(\"no such field\")");
+    assert!(!reply.ok);
+    assert_eq!(reply.data, vec!["no such field".to_owned()]);
+
+    let reply = script.command("String(\"unterminated");
+    assert!(!reply.ok);
+    assert!(reply.data[0].starts_with("Syntax error"), "{reply:?}");
+}
+
+#[test]
+fn token_is_required() {
+    let daemon = Daemon::start("");
+    let mut script = Script::connect(&daemon);
+    let reply = script.command("Enter");
+    assert!(!reply.ok);
+}
+
+#[test]
+fn quit_only_ends_the_script() {
+    let daemon = Daemon::start("");
+    let mut script = Script::connect(&daemon);
+    assert!(script.command(&format!("Attach({})", common::TOKEN)).ok);
+    writeln!(script.writer, "Quit()").unwrap();
+    let mut line = String::new();
+    assert_eq!(script.reader.read_line(&mut line).unwrap(), 0);
+
+    let mut script = Script::connect(&daemon);
+    assert!(script.command(&format!("Attach({})", common::TOKEN)).ok);
+    assert!(script.command("Enter").ok);
+}
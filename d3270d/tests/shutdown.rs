@@ -0,0 +1,107 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d winding down on SIGTERM
+
+mod common;
+
+use d3270_common::b3270::indication::ConnectionState;
+use d3270_common::b3270::Indication;
+use serde_json::Value;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use common::{action, Client, Daemon, TcpClient, WsClient};
+
+fn wait_notice(client: &mut impl Client) {
+    client.wait_for("shutdown notice", |ind| match ind {
+        Indication::UiError(error) if error.fatal => {
+            assert_eq!(error.text, "d3270d is shutting down");
+            Some(())
+        }
+        _ => None,
+    });
+}
+
+/// The audit records of what d3270d itself ran while shutting down
+fn shutdown_audit(daemon: &Daemon) -> Vec<Value> {
+    let log = std::fs::read_to_string(daemon.path("audit.log")).unwrap();
+    log.lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|record| record["listener"] == "shutdown")
+        .collect()
+}
+
+#[test]
+fn sigterm_tells_clients_and_stops_b3270() {
+    let mut daemon = Daemon::start("[shutdown]\ndisconnect = true\n[audit]\nfile = \"audit.log\"");
+    let mut tcp = TcpClient::attach(&daemon, common::TOKEN);
+    tcp.wait_connected();
+    let mut ws = WsClient::attach(&daemon, common::TOKEN);
+    ws.wait_connected();
+    let b3270 = daemon.children();
+    assert_eq!(b3270.len(), 1);
+
+    daemon.terminate();
+    tcp.wait_for("disconnection", |ind| match ind {
+        Indication::Connection(connection) if connection.state == ConnectionState::NotConnected => Some(()),
+        _ => None,
+    });
+    wait_notice(&mut tcp);
+    assert!(tcp.recv().is_none(), "still connected after the notice");
+    wait_notice(&mut ws);
+    let frame = ws.wait_closed().expect("no close frame");
+    assert_eq!(frame.code, CloseCode::Away);
+
+    assert!(daemon.wait().success());
+    assert!(common::exited(b3270[0]));
+    // It went when its input was closed, rather than being killed
+    let log = daemon.log();
+    assert!(log.contains("b3270 has stopped"), "{log}");
+    assert!(!log.contains("Timed out"), "{log}");
+
+    let audit = shutdown_audit(&daemon);
+    assert_eq!(audit.len(), 1, "{audit:?}");
+    assert_eq!(audit[0]["user"], "d3270d");
+    assert_eq!(audit[0]["actions"][0]["action"], "Disconnect");
+    assert_eq!(audit[0]["success"], true);
+}
+
+#[test]
+fn sigterm_gives_up_on_a_hung_b3270() {
+    let mut daemon = Daemon::start("[shutdown]\ndisconnect = true\ntimeout = 0.5\n[audit]\nfile = \"audit.log\"");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    let b3270 = daemon.children();
+    assert_eq!(b3270.len(), 1);
+    assert!(client.run("hang", vec![action("Hang", &[])]).success);
+
+    daemon.terminate();
+    wait_notice(&mut client);
+    assert!(client.recv().is_none(), "still connected after the notice");
+
+    assert!(daemon.wait().success());
+    assert!(common::exited(b3270[0]));
+    let log = daemon.log();
+    assert!(log.contains("Timed out disconnecting from the host"), "{log}");
+    assert!(log.contains("Timed out stopping the session"), "{log}");
+
+    let audit = shutdown_audit(&daemon);
+    assert_eq!(audit.len(), 1, "{audit:?}");
+    assert_eq!(audit[0]["actions"][0]["action"], "Disconnect");
+    assert_eq!(audit[0]["abandoned"], true);
+}
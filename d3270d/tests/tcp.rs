@@ -0,0 +1,413 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, through its TCP listener
+
+mod common;
+
+use d3270_common::b3270::indication::{ConnectionState, CountOrText, Floor, Passthru, Screen, UiError, WaitResult};
+use d3270_common::b3270::operation::{
+    Attach, Fail, FloorAction, FloorControl, Register, Succeed, WaitCondition, WaitFor,
+};
+use d3270_common::b3270::{Indication, InitializeIndication, Operation};
+use d3270_common::tracker::Tracker;
+
+use common::{action, Client, Daemon, TcpClient};
+
+#[test]
+fn attach_gets_initial_state() {
+    let daemon = Daemon::start("");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    let mode = client.wait_for("initialize", |ind| match ind {
+        Indication::Initialize(init) => init.into_iter().find_map(|ind| match ind {
+            InitializeIndication::ScreenMode(mode) => Some(mode),
+            _ => None,
+        }),
+        _ => None,
+    });
+    assert_eq!((mode.model, mode.rows, mode.columns), (2, 24, 80));
+    client.wait_connected();
+}
+
+#[test]
+fn unix_clients_are_attached_at_once() {
+    let daemon = Daemon::start("[listen.unix]\npath = \"d3270d.sock\"");
+    // Nothing is sent, but the state arrives anyway
+    let mut watcher = TcpClient::unix(&daemon, "d3270d.sock");
+    watcher.wait_connected();
+
+    // An attach as the first line only chooses the session and access
+    let mut reader = TcpClient::unix(&daemon, "d3270d.sock");
+    reader.send(&Operation::Attach(Attach {
+        token: None,
+        session: None,
+        read_only: true,
+    }));
+    reader.wait_connected();
+    assert!(!reader.run("read-only", vec![action("String", &["x"])]).success);
+    assert!(watcher.run("writable", vec![action("String", &["x"])]).success);
+}
+
+#[test]
+fn choosing_a_session_replaces_the_default_sessions_state() {
+    let daemon = Daemon::start("[sessions]\nother = \"other-host\"\n[listen.unix]\npath = \"d3270d.sock\"");
+    let mut alice = TcpClient::attach(&daemon, common::TOKEN);
+    alice.wait_connected();
+    alice.send(&Operation::Floor(FloorControl { action: FloorAction::Request }));
+    wait_floor(&mut alice, |floor| floor.mine);
+    assert!(alice.run("type", vec![action("String", &["hello"])]).success);
+
+    // Take in the default session's state before choosing the other one
+    let mut tracker = Tracker::default();
+    let mut client = TcpClient::unix(&daemon, "d3270d.sock");
+    client.wait_for("the default session's floor", |mut ind| {
+        tracker.handle_indication(&mut ind);
+        tracker.get_floor().is_some().then_some(())
+    });
+    client.send(&Operation::Attach(Attach {
+        token: None,
+        session: Some("other".to_owned()),
+        read_only: false,
+    }));
+    client.wait_for("the other session", |mut ind| {
+        tracker.handle_indication(&mut ind);
+        let connection = tracker.get_connection();
+        (connection.state == ConnectionState::Connected3270 && connection.host.as_deref() == Some("other-host"))
+            .then_some(())
+    });
+    assert!(tracker.get_floor().is_none());
+    let screen: String = tracker.get_screen().iter().flatten().map(|cell| cell.ch).collect();
+    assert!(!screen.contains("hello"));
+}
+
+#[test]
+fn read_only_clients_only_watch() {
+    let daemon = Daemon::start("[listen.unix]\npath = \"d3270d.sock\"");
+    let mut typist = TcpClient::attach(&daemon, common::TOKEN);
+    let mut reader = TcpClient::unix(&daemon, "d3270d.sock");
+    reader.send(&Operation::Attach(Attach {
+        token: None,
+        session: None,
+        read_only: true,
+    }));
+    typist.wait_connected();
+    reader.wait_connected();
+
+    let refused = reader.run("refused", vec![action("String", &["x"])]);
+    assert!(!refused.success);
+    assert_eq!(refused.text, vec!["This connection is read-only".to_owned()]);
+    reader.send(&Operation::Floor(FloorControl { action: FloorAction::Take }));
+    let error = reader.wait_for("ui-error", |ind| match ind {
+        Indication::UiError(error) => Some(error),
+        _ => None,
+    });
+    assert_eq!(error.operation.as_deref(), Some("floor"));
+
+    // The floor is still free, and the reader sees what the typist types
+    assert!(typist.run("type", vec![action("String", &["hello"])]).success);
+    reader.wait_for("screen", |ind| match ind {
+        Indication::Screen(Screen { rows, .. }) => rows
+            .iter()
+            .flat_map(|row| &row.changes)
+            .any(|change| change.change == CountOrText::Text("hello".to_owned()))
+            .then_some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn bad_token_is_refused() {
+    let daemon = Daemon::start("");
+    let mut client = TcpClient::attach(&daemon, "wrong");
+    match client.recv() {
+        Some(Indication::UiError(error)) => assert!(error.fatal),
+        other => panic!("Expected a fatal ui-error, got {other:?}"),
+    }
+    assert_eq!(client.recv(), None);
+}
+
+#[test]
+fn run_results_keep_their_tags() {
+    let daemon = Daemon::start("");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+
+    let result = client.run("first", vec![action("String", &["hello"])]);
+    assert!(result.success);
+    let result = client.run("second", vec![action("Fail", &["no such field"])]);
+    assert!(!result.success);
+    assert_eq!(result.text, vec!["no such field".to_owned()]);
+}
+
+#[test]
+fn other_clients_see_screen_changes() {
+    let daemon = Daemon::start("");
+    let mut typist = TcpClient::attach(&daemon, common::TOKEN);
+    let mut watcher = TcpClient::attach(&daemon, common::TOKEN);
+    typist.wait_connected();
+    watcher.wait_connected();
+
+    assert!(typist.run("type", vec![action("String", &["hello"])]).success);
+    watcher.wait_for("screen", |ind| match ind {
+        Indication::Screen(Screen { rows, .. }) => rows
+            .iter()
+            .flat_map(|row| &row.changes)
+            .any(|change| change.change == CountOrText::Text("hello".to_owned()))
+            .then_some(()),
+        _ => None,
+    });
+}
+
+fn wait_floor(client: &mut TcpClient, check: impl Fn(&Floor) -> bool) {
+    client.wait_for("floor", |ind| match ind {
+        Indication::Floor(floor) => check(&floor).then_some(()),
+        _ => None,
+    })
+}
+
+#[test]
+fn only_the_floor_holder_may_type() {
+    let daemon = Daemon::start("");
+    let mut alice = TcpClient::attach(&daemon, common::TOKEN);
+    let mut bob = TcpClient::attach(&daemon, common::TOKEN);
+    alice.wait_connected();
+    bob.wait_connected();
+    let floor = |action| Operation::Floor(FloorControl { action });
+    let typing = || vec![action("String", &["x"])];
+
+    alice.send(&floor(FloorAction::Request));
+    wait_floor(&mut alice, |floor| floor.mine);
+    wait_floor(&mut bob, |floor| floor.holder.is_some());
+    let refused = bob.run("refused", typing());
+    assert!(!refused.success);
+    assert!(refused.text[0].starts_with("The keyboard is held by"), "{:?}", refused.text);
+    assert!(alice.run("holder", typing()).success);
+
+    alice.send(&floor(FloorAction::Release));
+    wait_floor(&mut bob, |floor| floor.holder.is_none());
+    assert!(bob.run("released", typing()).success);
+
+    alice.send(&floor(FloorAction::Request));
+    wait_floor(&mut bob, |floor| floor.holder.is_some());
+    bob.send(&floor(FloorAction::Take));
+    wait_floor(&mut bob, |floor| floor.mine);
+    assert!(bob.run("taken", typing()).success);
+    assert!(!alice.run("lost", typing()).success);
+}
+
+fn wait_run_error(client: &mut TcpClient) -> UiError {
+    client.wait_for("ui-error", |ind| match ind {
+        Indication::UiError(error) if error.operation.as_deref() == Some("run") => Some(error),
+        _ => None,
+    })
+}
+
+#[test]
+fn bad_run_fails() {
+    let daemon = Daemon::start("");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    client.start_run("bad", vec![action("Reject", &[])]);
+    // The error and the result come in either order
+    let (mut error, mut result) = (None, None);
+    client.wait_for("ui-error and run-result", |ind| {
+        match ind {
+            Indication::UiError(ui_error) => error = Some(ui_error),
+            Indication::RunResult(run_result) if run_result.r_tag.as_deref() == Some("bad") => result = Some(run_result),
+            _ => {}
+        }
+        (error.is_some() && result.is_some()).then_some(())
+    });
+    assert_eq!(error.unwrap().operation.as_deref(), Some("run"));
+    let result = result.unwrap();
+    assert!(!result.success);
+    assert_eq!(result.text, vec!["Rejected run".to_owned()]);
+}
+
+#[test]
+fn bad_run_behind_a_slow_one() {
+    let daemon = Daemon::start("");
+    let mut slow = TcpClient::attach(&daemon, common::TOKEN);
+    let mut bad = TcpClient::attach(&daemon, common::TOKEN);
+    slow.wait_connected();
+    bad.wait_connected();
+    slow.start_run("slow", vec![action("Pause", &[])]);
+    // Anything after it is written after it
+    assert!(slow.run("sync", vec![action("Nothing", &[])]).success);
+
+    // Either run may be the bad one until the slow one is answered
+    bad.start_run("bad", vec![action("Reject", &[])]);
+    wait_run_error(&mut bad);
+    slow.start_run("resume", vec![action("Resume", &[])]);
+    assert!(slow.run_result("slow").success);
+    assert!(slow.run_result("resume").success);
+    let result = bad.run_result("bad");
+    assert!(!result.success);
+    assert_eq!(result.text, vec!["Rejected run".to_owned()]);
+}
+
+fn register(client: &mut TcpClient, name: &str) {
+    client.send(&Operation::Register(Register {
+        name: name.to_owned(),
+        help_text: None,
+        help_params: None,
+    }));
+    // Anything after it is handled after it
+    assert!(client.run("registered", vec![action("Nothing", &[])]).success);
+}
+
+fn wait_passthru(client: &mut TcpClient, name: &str) -> Passthru {
+    client.wait_for("passthru", |ind| match ind {
+        Indication::Passthru(passthru) if passthru.action == name => Some(passthru),
+        _ => None,
+    })
+}
+
+#[test]
+fn passthru_goes_to_the_registering_client() {
+    let daemon = Daemon::start("");
+    let mut handler = TcpClient::attach(&daemon, common::TOKEN);
+    let mut user = TcpClient::attach(&daemon, common::TOKEN);
+    handler.wait_connected();
+    user.wait_connected();
+    register(&mut handler, "Lookup");
+
+    user.start_run("found", vec![action("Lookup", &["x"])]);
+    let passthru = wait_passthru(&mut handler, "Lookup");
+    assert_eq!(passthru.args, vec!["x".to_owned()]);
+    handler.send(&Operation::Succeed(Succeed {
+        p_tag: passthru.p_tag,
+        text: vec!["here".to_owned()],
+    }));
+    let result = user.run_result("found");
+    assert!(result.success);
+    assert_eq!(result.text, vec!["here".to_owned()]);
+
+    user.start_run("missing", vec![action("Lookup", &["y"])]);
+    let passthru = wait_passthru(&mut handler, "Lookup");
+    handler.send(&Operation::Fail(Fail {
+        p_tag: passthru.p_tag,
+        text: vec!["not here".to_owned()],
+    }));
+    let result = user.run_result("missing");
+    assert!(!result.success);
+    assert_eq!(result.text, vec!["not here".to_owned()]);
+
+    // Leaving fails what the handler was in the middle of, and lets go of
+    // the action
+    user.start_run("orphaned", vec![action("Lookup", &["z"])]);
+    wait_passthru(&mut handler, "Lookup");
+    drop(handler);
+    let result = user.run_result("orphaned");
+    assert!(!result.success);
+    assert_eq!(result.text, vec!["The client handling this action disconnected".to_owned()]);
+
+    let mut successor = TcpClient::attach(&daemon, common::TOKEN);
+    successor.wait_connected();
+    register(&mut successor, "Lookup");
+    user.start_run("again", vec![action("Lookup", &[])]);
+    let passthru = wait_passthru(&mut successor, "Lookup");
+    successor.send(&Operation::Succeed(Succeed {
+        p_tag: passthru.p_tag,
+        text: vec![],
+    }));
+    assert!(user.run_result("again").success);
+}
+
+fn wait_result(client: &mut TcpClient, w_tag: &str) -> WaitResult {
+    client.wait_for("wait-result", |ind| match ind {
+        Indication::WaitResult(result) if result.w_tag.as_deref() == Some(w_tag) => Some(result),
+        _ => None,
+    })
+}
+
+#[test]
+fn waits_end_when_the_screen_matches() {
+    let daemon = Daemon::start("");
+    let mut waiter = TcpClient::attach(&daemon, common::TOKEN);
+    let mut typist = TcpClient::attach(&daemon, common::TOKEN);
+    waiter.wait_connected();
+    typist.wait_connected();
+
+    let wait = |w_tag: &str, condition, timeout| {
+        Operation::WaitFor(WaitFor {
+            w_tag: Some(w_tag.to_owned()),
+            condition,
+            timeout,
+        })
+    };
+    let hello = WaitCondition::Text {
+        row: 1,
+        column: 1,
+        text: "hello".to_owned(),
+    };
+    waiter.send(&wait("hello", hello, Some(common::TIMEOUT.as_secs_f64())));
+    waiter.send(&wait("never", WaitCondition::Regex("nope".to_owned()), Some(0.1)));
+    waiter.send(&wait("bad", WaitCondition::Regex("(".to_owned()), None));
+
+    let bad = wait_result(&mut waiter, "bad");
+    assert!(!bad.success);
+    assert!(bad.text.unwrap().starts_with("Invalid regex"));
+    let never = wait_result(&mut waiter, "never");
+    assert_eq!(never.text.as_deref(), Some("Timed out"));
+
+    assert!(typist.run("type", vec![action("String", &["hello"])]).success);
+    let hello = wait_result(&mut waiter, "hello");
+    assert!(hello.success);
+    assert_eq!((hello.row, hello.column), (Some(1), Some(1)));
+    assert!(hello.screen[0].starts_with("hello"));
+    assert_eq!(hello.cursor.column, Some(6));
+}
+
+#[test]
+fn flood_is_delivered() {
+    let daemon = Daemon::start("");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    assert!(client.run("flood", vec![action("Flood", &["5000"])]).success);
+    // Still attached and answering afterwards
+    assert!(client.run("after", vec![action("Enter", &[])]).success);
+}
+
+#[test]
+fn crash_restarts_b3270() {
+    let daemon = Daemon::start("[restart]\nbackoff = 0.1");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+
+    let result = client.run("crash", vec![action("Crash", &[])]);
+    assert!(!result.success);
+    assert!(result.text[0].contains("b3270 exited"), "{:?}", result.text);
+    // The new b3270 is connected to the host again, and takes actions
+    client.wait_connected();
+    assert!(client.run("after", vec![action("String", &["again"])]).success);
+}
+
+#[test]
+fn failing_writes_restart_b3270() {
+    let daemon = Daemon::start("[restart]\nbackoff = 0.1");
+    let mut client = TcpClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+
+    assert!(client.run("deafen", vec![action("Deafen", &[])]).success);
+    let result = client.run("unheard", vec![action("String", &["lost"])]);
+    assert!(!result.success);
+    assert!(result.text[0].contains("Failed to write to b3270"), "{:?}", result.text);
+    client.wait_connected();
+    assert!(client.run("after", vec![action("String", &["again"])]).success);
+}
@@ -0,0 +1,63 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, with both listeners behind TLS
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{action, Client, Daemon, TcpClient, TlsStream};
+
+#[test]
+fn tcp_over_tls() {
+    let daemon = Daemon::start(&common::tls_config());
+    let mut client = TcpClient::tls(&daemon, common::TOKEN);
+    client.wait_connected();
+    assert!(client.run("typed", vec![action("String", &["hi"])]).success);
+}
+
+#[test]
+fn https() {
+    let daemon = Daemon::start(&common::tls_config());
+    let mut conn = TlsStream::connect(daemon.http);
+    write!(
+        conn,
+        "GET /api/screen/text HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        common::TOKEN
+    )
+    .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[test]
+fn plain_clients_are_refused() {
+    let daemon = Daemon::start(&common::tls_config());
+    for addr in [daemon.tcp, daemon.http] {
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        conn.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        // Whatever comes back is a TLS alert, not 3270 state or HTTP
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).ok();
+        assert!(!response.starts_with(b"HTTP") && !response.starts_with(b"{"));
+    }
+}
@@ -0,0 +1,101 @@
+/*************************************************************************
+ * D3270 - Detachable 3270 interface                                      *
+ * Copyright (C) 2023  Daniel Hirsch                                      *
+ *                                                                        *
+ * This program is free software: you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the          *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. *
+ *************************************************************************/
+
+//! d3270d end to end, through its websocket
+
+mod common;
+
+use d3270_common::b3270::indication::RosterEntry;
+use d3270_common::b3270::operation::Run;
+use d3270_common::b3270::{Indication, Operation};
+
+use common::{action, Client, Daemon, TcpClient, WsClient};
+
+#[test]
+fn run_over_websocket() {
+    let daemon = Daemon::start("");
+    let mut client = WsClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    assert!(client.run("ws", vec![action("String", &["hello"])]).success);
+}
+
+#[test]
+fn bad_token_is_refused() {
+    let daemon = Daemon::start("");
+    let mut client = WsClient::attach(&daemon, "wrong");
+    match client.recv() {
+        Some(Indication::UiError(error)) => assert!(error.fatal),
+        other => panic!("Expected a fatal ui-error, got {other:?}"),
+    }
+    assert_eq!(client.recv(), None);
+}
+
+#[test]
+fn websocket_and_tcp_share_a_session() {
+    let daemon = Daemon::start("");
+    let mut web = WsClient::attach(&daemon, common::TOKEN);
+    let mut tcp = TcpClient::attach(&daemon, common::TOKEN);
+    web.wait_connected();
+    tcp.wait_connected();
+
+    assert!(tcp.run("disconnect", vec![action("Disconnect", &[])]).success);
+    web.wait_for("disconnection", |ind| match ind {
+        Indication::Connection(connection) => connection.host.is_none().then_some(()),
+        _ => None,
+    });
+}
+
+fn wait_roster(client: &mut impl Client, check: impl Fn(&[RosterEntry]) -> bool) -> Vec<RosterEntry> {
+    client.wait_for("roster", |ind| match ind {
+        Indication::Roster(roster) if check(&roster.clients) => Some(roster.clients),
+        _ => None,
+    })
+}
+
+#[test]
+fn roster_follows_clients() {
+    let daemon = Daemon::start("");
+    let mut tcp = TcpClient::attach(&daemon, common::TOKEN);
+    tcp.wait_connected();
+    let first = wait_roster(&mut tcp, |clients| clients.len() == 1);
+    assert_eq!((first[0].user.as_str(), first[0].listener.as_str()), ("alice", "tcp"));
+
+    let web = WsClient::attach(&daemon, common::TOKEN);
+    let both = wait_roster(&mut tcp, |clients| clients.len() == 2);
+    assert_eq!(both[0].id, first[0].id);
+    assert_eq!(both[1].listener, "ws");
+    assert!(both[1].peer.is_some());
+
+    drop(web);
+    let left = wait_roster(&mut tcp, |clients| clients.len() == 1);
+    assert_eq!(left[0].id, first[0].id);
+}
+
+#[test]
+fn session_end_closes_websocket() {
+    let daemon = Daemon::start("[restart]\nlimit = 0");
+    let mut client = WsClient::attach(&daemon, common::TOKEN);
+    client.wait_connected();
+    // With restarts off, b3270 crashing ends the session, and with it the websocket
+    client.send(&Operation::Run(Run {
+        r_tag: None,
+        type_: None,
+        actions: vec![action("Crash", &[])],
+    }));
+    while client.recv().is_some() {}
+}
@@ -103,7 +103,20 @@ class Js3270 {
 
     private reconnect_ws() {
         this.backoff = Math.max(1, Math.min(this.backoff * 1.5, 30));
-        let ws = this.ws = new WebSocket(`ws://${document.location.host}:${document.location.port}/api/ws`);
+        // Pass through the auth token, session name and read-only flag, if we were given them
+        let params = new URLSearchParams(document.location.search);
+        let ws_params = new URLSearchParams();
+        for (let name of ["token", "read-only"]) {
+            let value = params.get(name);
+            if (value !== null) {
+                ws_params.set(name, value);
+            }
+        }
+        let query = ws_params.toString() === "" ? "" : `?${ws_params}`;
+        let session = params.get("session");
+        let path = session === null ? "/api/ws" : `/api/ws/${encodeURIComponent(session)}`;
+        let scheme = document.location.protocol == "https:" ? "wss" : "ws";
+        let ws = this.ws = new WebSocket(`${scheme}://${document.location.host}:${document.location.port}${path}${query}`);
         this.ws.addEventListener("message", this.on_message.bind(this))
         this.ws.addEventListener("open", () => {
             this.backoff = 1;
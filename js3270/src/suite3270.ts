@@ -195,6 +195,27 @@ export type IndUiError = {
     column?: number
 }
 
+// d3270 extensions
+export type IndFloor = {
+    holder?: string
+    "holder-id"?: number
+    mine: boolean
+}
+
+export type RosterEntry = {
+    id: number
+    user: string
+    listener: string
+    peer?: string
+    connected: string
+    "read-only": boolean
+    "last-input"?: string
+}
+
+export type IndRoster = {
+    clients: RosterEntry[]
+}
+
 // operations
 export type OpRun = {
     "r-tag"?: string,
@@ -219,11 +240,16 @@ export type OpResult = {
     text?: string[]
 }
 
+export type OpFloor = {
+    action: "request" | "release" | "take"
+}
+
 export type Operation =
     {run: OpRun} |
     {register: OpRegister} |
     {fail: OpResult} |
-    {succeed: OpResult}
+    {succeed: OpResult} |
+    {floor: OpFloor}
 
 export type Indication =
     {bell: {}} |
@@ -234,11 +260,13 @@ export type Indication =
     {font: {text: string}} |
     {formatted: {state: boolean}} |
     {ft: IndFileTransfer} |
+    {floor: IndFloor} |
     {icon: {text: string}} |
     {initialize: InitializeIndication[]} |
     {oia: IndOia} |
     {passthru: IndPassthru} |
     {popup: IndPopup} |
+    {roster: IndRoster} |
     {"run-result": IndRunResult} |
     {screen: IndScreen} |
     {"screen-mode": IndScreenMode} |